@@ -0,0 +1,89 @@
+//! Echoes lines received on USART1, buffered by the USART interrupt
+//!
+//! The interrupt keeps receiving into the RX buffer while the main loop is busy with a line.
+//!
+//! Note: This example is for the STM32F746
+
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+extern crate panic_halt;
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use cortex_m::interrupt::{free, Mutex};
+use cortex_m::peripheral::NVIC;
+use cortex_m_rt::entry;
+use stm32f7xx_hal::{
+    device::{self, USART1},
+    gpio::{gpioa::PA9, gpiob::PB7, Alternate, AF7},
+    interrupt,
+    prelude::*,
+    serial::{self, BufferedSerial, Serial},
+};
+
+type Console = BufferedSerial<USART1, (PA9<Alternate<AF7>>, PB7<Alternate<AF7>>), 64, 64>;
+
+static CONSOLE: Mutex<RefCell<Option<Console>>> = Mutex::new(RefCell::new(None));
+
+#[entry]
+fn main() -> ! {
+    let p = device::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+    let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
+
+    let gpioa = p.GPIOA.split();
+    let gpiob = p.GPIOB.split();
+
+    let tx = gpioa.pa9.into_alternate_af7();
+    let rx = gpiob.pb7.into_alternate_af7();
+
+    let serial = Serial::usart1(
+        p.USART1,
+        (tx, rx),
+        serial::Config::default(),
+        clocks,
+        &mut rcc.apb2,
+    );
+    free(|cs| CONSOLE.borrow(cs).replace(Some(serial.buffered())));
+
+    // NOTE(unsafe) the handler only accesses the console through the mutex
+    unsafe { NVIC::unmask(device::Interrupt::USART1) };
+
+    let mut line = [0u8; 32];
+    let mut len = 0;
+    loop {
+        let byte = free(|cs| {
+            let mut console = CONSOLE.borrow(cs).borrow_mut();
+            console.as_mut().unwrap().try_read()
+        });
+
+        match byte {
+            Ok(b'\r') | Ok(b'\n') => {
+                let line = core::str::from_utf8(&line[..len]).unwrap_or("<not UTF-8>");
+                free(|cs| {
+                    let mut console = CONSOLE.borrow(cs).borrow_mut();
+                    writeln!(console.as_mut().unwrap(), "\r\n> {}\r", line).ok();
+                });
+                len = 0;
+            }
+            Ok(byte) if len < line.len() => {
+                line[len] = byte;
+                len += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[interrupt]
+fn USART1() {
+    free(|cs| {
+        if let Some(console) = CONSOLE.borrow(cs).borrow_mut().as_mut() {
+            console.handle_interrupt();
+        }
+    });
+}
@@ -24,7 +24,7 @@ fn main() -> ! {
     let p = device::Peripherals::take().unwrap();
     let cp = cortex_m::Peripherals::take().unwrap();
 
-    let rcc = p.RCC.constrain();
+    let mut rcc = p.RCC.constrain();
     let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
 
     let mut delay = Delay::new(cp.SYST, clocks);
@@ -35,14 +35,15 @@ fn main() -> ! {
     let tx = gpioa.pa9.into_alternate_af7();
     let rx = gpiob.pb7.into_alternate_af7();
 
-    let serial = Serial::new(
+    let serial = Serial::usart1(
         p.USART1,
         (tx, rx),
-        clocks,
         serial::Config {
-            baud_rate: 115_200.bps(),
-            oversampling: serial::Oversampling::By16,
+            baudrate: 115_200.bps(),
+            ..serial::Config::default()
         },
+        clocks,
+        &mut rcc.apb2,
     );
     let (mut tx, _) = serial.split();
 
@@ -11,13 +11,9 @@
 
 extern crate panic_halt;
 
-use core::pin::Pin;
-
-use cortex_m::{asm, interrupt};
 use cortex_m_rt::entry;
 use stm32f7xx_hal::{
     device,
-    dma::{self, DMA},
     prelude::*,
     serial::{self, Serial},
 };
@@ -27,82 +23,47 @@ fn main() -> ! {
     let p = device::Peripherals::take().unwrap();
 
     let mut rcc = p.RCC.constrain();
-
-    let dma = DMA::new(p.DMA1);
-
-    let mut rx_stream = dma.streams.stream1;
-    let mut tx_stream = dma.streams.stream3;
-
-    let dma = dma.handle.enable(&mut rcc);
-
     let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
 
+    let streams = p.DMA1.split(&mut rcc.ahb1);
+
     let gpiod = p.GPIOD.split();
 
     let tx = gpiod.pd8.into_alternate_af7();
     let rx = gpiod.pd9.into_alternate_af7();
 
-    let serial = Serial::new(
+    let serial = Serial::usart3(
         p.USART3,
         (tx, rx),
-        clocks,
         serial::Config {
-            baud_rate: 115_200.bps(),
-            oversampling: serial::Oversampling::By16,
+            baudrate: 115_200.bps(),
+            ..serial::Config::default()
         },
+        clocks,
+        &mut rcc.apb1,
     );
-    let (mut tx, mut rx) = serial.split();
+    let (tx, rx) = serial.split();
+
+    let mut rx = rx.with_dma(streams.1);
+    let mut tx = tx.with_dma(streams.3);
 
     // Create the buffer we're going to use for DMA. This is safe, as this
     // function won't return as long as the program runs, so there's no chance
     // of anyone else using the same static.
     static mut BUFFER: [u8; 4] = [0; 4];
-    let mut buffer = unsafe { Pin::new(&mut BUFFER) };
+    let mut buffer: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) };
+
     loop {
         // Read using DMA
-        let mut transfer = rx.read_all(buffer, &dma, rx_stream);
-        let res = interrupt::free(|_| {
-            transfer.enable_interrupts(
-                &dma,
-                dma::Interrupts {
-                    transfer_complete: true,
-                    transfer_error: true,
-                    direct_mode_error: true,
-                    ..dma::Interrupts::default()
-                },
-            );
-
-            let transfer = transfer.start(&dma);
-
-            asm::wfi();
-
-            transfer.wait(&dma).unwrap()
-        });
-        buffer = res.buffer;
-        rx = res.target;
-        rx_stream = res.stream;
+        let transfer = rx.read(buffer).map_err(|_| ()).unwrap();
+        let (received, rx_dma) = transfer.wait().map_err(|_| ()).unwrap();
+        rx = rx_dma;
 
         // Write using DMA
-        let mut transfer = tx.write_all(buffer, &dma, tx_stream);
-        let res = interrupt::free(|_| {
-            transfer.enable_interrupts(
-                &dma,
-                dma::Interrupts {
-                    transfer_complete: true,
-                    transfer_error: true,
-                    direct_mode_error: true,
-                    ..dma::Interrupts::default()
-                },
-            );
-
-            let transfer = transfer.start(&dma);
-
-            asm::wfi();
+        let transfer = tx.write(received).map_err(|_| ()).unwrap();
+        let (sent, tx_dma) = transfer.wait().map_err(|_| ()).unwrap();
+        tx = tx_dma;
 
-            transfer.wait(&dma).unwrap()
-        });
-        buffer = res.buffer;
-        tx = res.target;
-        tx_stream = res.stream;
+        buffer = sent;
     }
 }
@@ -22,7 +22,7 @@ use stm32f7xx_hal::{
 fn main() -> ! {
     let p = device::Peripherals::take().unwrap();
 
-    let rcc = p.RCC.constrain();
+    let mut rcc = p.RCC.constrain();
     let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
 
     let gpioa = p.GPIOA.split();
@@ -31,14 +31,15 @@ fn main() -> ! {
     let tx = gpioa.pa9.into_alternate_af7();
     let rx = gpiob.pb7.into_alternate_af7();
 
-    let serial = Serial::new(
+    let serial = Serial::usart1(
         p.USART1,
         (tx, rx),
-        clocks,
         serial::Config {
-            baud_rate: 115_200.bps(),
-            oversampling: serial::Oversampling::By16,
+            baudrate: 115_200.bps(),
+            ..serial::Config::default()
         },
+        clocks,
+        &mut rcc.apb2,
     );
 
     let (mut tx, mut rx) = serial.split();
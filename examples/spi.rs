@@ -6,6 +6,7 @@ extern crate panic_semihosting;
 use cortex_m_rt::entry;
 use stm32f7xx_hal::{
     device,
+    gpio::{AltConfig, Speed},
     prelude::*,
     spi::{self, Spi},
 };
@@ -26,21 +27,30 @@ fn main() -> ! {
 
     // Prepare pins for SPI
     let mut ncs = gpioc.pc9.into_push_pull_output();
-    let sck = gpioc.pc10.into_alternate_af6();
-    let miso = gpioc.pc11.into_alternate_af6();
-    let mosi = gpioc.pc12.into_alternate_af6();
+    let alt = AltConfig {
+        speed: Speed::High,
+        ..AltConfig::default()
+    };
+    let sck = gpioc.pc10.into_alternate_af6_with(alt);
+    let miso = gpioc.pc11.into_alternate_af6_with(alt);
+    let mosi = gpioc.pc12.into_alternate_af6_with(alt);
 
     // Set NCS pin to high (disabled) initially
     ncs.set_high().unwrap();
 
+    let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
+
     // Initialize SPI
-    let mut spi = Spi::new(p.SPI3, (sck, miso, mosi)).enable::<u8>(
-        &mut rcc,
-        spi::ClockDivider::DIV32,
+    let mut spi = Spi::spi3(
+        p.SPI3,
+        (sck, miso, mosi),
         spi::Mode {
             polarity: spi::Polarity::IdleHigh,
             phase: spi::Phase::CaptureOnSecondTransition,
         },
+        1.mhz(),
+        clocks,
+        &mut rcc.apb1,
     );
 
     loop {
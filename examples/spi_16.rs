@@ -1,14 +1,14 @@
+//! Drives a Maxim Integrated MAX5214 DAC with 16-bit SPI frames
+//!
+//! Note: This example is for the NUCLEO-F746ZG board
+
 #![no_main]
 #![no_std]
 
 extern crate panic_semihosting;
 
 use cortex_m_rt::entry;
-use stm32f7xx_hal::{
-    device,
-    prelude::*,
-    spi::{self, Spi},
-};
+use stm32f7xx_hal::{device, prelude::*, spi::Spi};
 
 #[entry]
 fn main() -> ! {
@@ -24,20 +24,28 @@ fn main() -> ! {
     // on the NUCLEO-F746ZG board.
     let button = gpioc.pc13.into_floating_input();
 
-    // Prepare pins for SPI
+    // Prepare pins for SPI. The DAC has no data output, but the bus still
+    // takes a MISO pin.
     let mut ncs = gpiod.pd14.into_push_pull_output();
     let sck = gpioa.pa5.into_alternate_af5();
+    let miso = gpioa.pa6.into_alternate_af5();
     let mosi = gpioa.pa7.into_alternate_af5();
 
     // Set NCS pin to high (disabled) initially
     ncs.set_high().unwrap();
 
+    let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
+
     // Initialize SPI
-    let mut spi = Spi::new(p.SPI1, (sck, spi::NoMiso, mosi)).enable::<u16>(
-        &mut rcc,
-        spi::ClockDivider::DIV32,
+    let mut spi = Spi::spi1(
+        p.SPI1,
+        (sck, miso, mosi),
         embedded_hal::spi::MODE_0,
-    );
+        1.mhz(),
+        clocks,
+        &mut rcc.apb2,
+    )
+    .into_16bit();
 
     // Use a button to control output via the Maxim Integrated MAX5214 DAC.
     loop {
@@ -1,15 +1,15 @@
+//! Reads the WHO_AM_I register of an MPU9250 sensor using SPI with DMA
+//!
+//! Note: This example is for the NUCLEO-F746ZG board
+
 #![no_main]
 #![no_std]
 
 extern crate panic_semihosting;
 
-use core::pin::Pin;
-
-use cortex_m::{asm, interrupt};
 use cortex_m_rt::entry;
 use stm32f7xx_hal::{
     device,
-    dma::{self, DMA},
     prelude::*,
     spi::{self, Spi},
 };
@@ -20,7 +20,7 @@ fn main() -> ! {
 
     let mut rcc = p.RCC.constrain();
 
-    let dma = DMA::new(p.DMA1);
+    let streams = p.DMA1.split(&mut rcc.ahb1);
     let gpiob = p.GPIOB.split();
     let gpioc = p.GPIOC.split();
 
@@ -35,68 +35,45 @@ fn main() -> ! {
     let miso = gpioc.pc11.into_alternate_af6();
     let mosi = gpioc.pc12.into_alternate_af6();
 
-    // Prepare DMA streams
-    let mut rx_stream = dma.streams.stream0;
-    let mut tx_stream = dma.streams.stream5;
-
-    let dma = dma.handle.enable(&mut rcc);
-
     // Set NCS pin to high (disabled) initially
     ncs.set_high().unwrap();
 
+    let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
+
     // Initialize SPI
-    let mut spi = Spi::new(p.SPI3, (sck, miso, mosi)).enable(
-        &mut rcc,
-        spi::ClockDivider::DIV32,
+    let spi = Spi::spi3(
+        p.SPI3,
+        (sck, miso, mosi),
         spi::Mode {
             polarity: spi::Polarity::IdleHigh,
             phase: spi::Phase::CaptureOnSecondTransition,
         },
+        1.mhz(),
+        clocks,
+        &mut rcc.apb1,
     );
+    let mut spi = spi.with_dma(streams.0, streams.5);
 
     // Create the buffer we're going to use for DMA. This is safe, as this
     // function won't return as long as the program runs, so there's no chance
     // of anyone else using the same static.
     static mut BUFFER: [u8; 2] = [0; 2];
-    let mut buffer = unsafe { Pin::new(&mut BUFFER) };
+    let mut buffer: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) };
 
     loop {
-        // Read WHO_AM_I register of an MPU9250 sensor.
-        // Write address for WHO_AM_I register an an MPU9250 sensor
+        // Write address for WHO_AM_I register of an MPU9250 sensor
         buffer[0] = 0x75 | 0x80;
 
-        // Prepare DMA transfer
-        let mut transfer = spi.transfer_all(buffer, &dma, &dma, rx_stream, tx_stream);
-
         // Start DMA transfer and wait for it to finish
         ncs.set_low().unwrap();
-        let res = interrupt::free(|_| {
-            transfer.enable_interrupts(
-                &dma,
-                &dma,
-                dma::Interrupts {
-                    transfer_complete: true,
-                    transfer_error: true,
-                    direct_mode_error: true,
-                    ..dma::Interrupts::default()
-                },
-            );
-
-            let transfer = transfer.start(&dma, &dma);
-
-            asm::wfi();
-
-            transfer.wait(&dma, &dma).unwrap()
-        });
+        let transfer = spi.transfer(buffer).map_err(|_| ()).unwrap();
+        let (received, spi_dma) = transfer.wait().map_err(|_| ()).unwrap();
         ncs.set_high().unwrap();
 
-        // Assign everything we've moved to the DMA transfer to the local
-        // variables it came from, so it's available again in the next loop
-        // iteration.
-        buffer = res.buffer;
-        spi = res.target;
-        rx_stream = res.rx_stream;
-        tx_stream = res.tx_stream;
+        // Hand everything moved into the transfer back to the local variables
+        // it came from, so it's available again in the next loop iteration.
+        buffer = received;
+        spi = spi_dma;
 
         // The WHO_AM_I register should always return 0x71.
         if buffer[1] == 0x71 {
@@ -1,18 +1,14 @@
+//! Drives a Maxim Integrated MAX5214 DAC with 16-bit SPI frames sent by DMA
+//!
+//! Note: This example is for the NUCLEO-F746ZG board
+
 #![no_main]
 #![no_std]
 
 extern crate panic_semihosting;
 
-use core::pin::Pin;
-
-use cortex_m::{asm, interrupt};
 use cortex_m_rt::entry;
-use stm32f7xx_hal::{
-    device,
-    dma::{self, DMA},
-    prelude::*,
-    spi::{self, Spi},
-};
+use stm32f7xx_hal::{device, prelude::*, spi::Spi};
 
 #[entry]
 fn main() -> ! {
@@ -20,7 +16,7 @@ fn main() -> ! {
 
     let mut rcc = p.RCC.constrain();
 
-    let dma = DMA::new(p.DMA2);
+    let streams = p.DMA2.split(&mut rcc.ahb1);
     let gpioa = p.GPIOA.split();
     let gpioc = p.GPIOC.split();
     let gpiod = p.GPIOD.split();
@@ -29,32 +25,35 @@ fn main() -> ! {
     // on the NUCLEO-F746ZG board.
     let button = gpioc.pc13.into_floating_input();
 
-    // Prepare pins for SPI
+    // Prepare pins for SPI. The DAC has no data output, but the bus still
+    // takes a MISO pin.
     let mut ncs = gpiod.pd14.into_push_pull_output();
     let sck = gpioa.pa5.into_alternate_af5();
+    let miso = gpioa.pa6.into_alternate_af5();
     let mosi = gpioa.pa7.into_alternate_af5();
 
-    // Prepare DMA streams
-    let mut rx_stream = dma.streams.stream0;
-    let mut tx_stream = dma.streams.stream3;
-
-    let dma = dma.handle.enable(&mut rcc);
-
     // Set NCS pin to high (disabled) initially
     ncs.set_high().unwrap();
 
+    let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
+
     // Initialize SPI
-    let mut spi = Spi::new(p.SPI1, (sck, spi::NoMiso, mosi)).enable(
-        &mut rcc,
-        spi::ClockDivider::DIV32,
+    let spi = Spi::spi1(
+        p.SPI1,
+        (sck, miso, mosi),
         embedded_hal::spi::MODE_0,
-    );
+        1.mhz(),
+        clocks,
+        &mut rcc.apb2,
+    )
+    .into_16bit();
+    let mut spi = spi.with_dma(streams.0, streams.3);
 
     // Create the buffer we're going to use for DMA. This is safe, as this
     // function won't return as long as the program runs, so there's no chance
     // of anyone else using the same static.
     static mut BUFFER: [u16; 1] = [0; 1];
-    let mut buffer = unsafe { Pin::new(&mut BUFFER) };
+    let mut buffer: &'static mut [u16] = unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) };
 
     // Use a button to control output via the Maxim Integrated MAX5214 DAC.
     loop {
@@ -67,34 +66,13 @@ fn main() -> ! {
         buffer[0] = (0b01 << 14) |   // write-through mode
             (data & 0x3fff); // data bits
 
-        // Prepare DMA transfer
-        let mut transfer = spi.transfer_all(buffer, &dma, &dma, rx_stream, tx_stream);
-
         // Start DMA transfer and wait for it to finish
         ncs.set_low().unwrap();
-        let res = interrupt::free(|_| {
-            transfer.enable_interrupts(
-                &dma,
-                &dma,
-                dma::Interrupts {
-                    transfer_complete: true,
-                    transfer_error: true,
-                    direct_mode_error: true,
-                    ..dma::Interrupts::default()
-                },
-            );
-
-            let transfer = transfer.start(&dma, &dma);
-
-            asm::wfi();
-
-            transfer.wait(&dma, &dma).unwrap()
-        });
+        let transfer = spi.transfer(buffer).map_err(|_| ()).unwrap();
+        let (sent, spi_dma) = transfer.wait().map_err(|_| ()).unwrap();
         ncs.set_high().unwrap();
 
-        buffer = res.buffer;
-        spi = res.target;
-        rx_stream = res.rx_stream;
-        tx_stream = res.tx_stream;
+        buffer = sent;
+        spi = spi_dma;
     }
 }
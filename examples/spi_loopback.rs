@@ -0,0 +1,70 @@
+//! Checks SPI full-duplex transfers with MOSI wired to MISO
+//!
+//! Connect PA7 (MOSI) to PA6 (MISO). The green LED lights up if every transfer reads back what
+//! it sent, the red one if a byte came back different.
+
+#![no_main]
+#![no_std]
+
+extern crate panic_semihosting;
+
+use cortex_m_rt::entry;
+use stm32f7xx_hal::{
+    device,
+    gpio::{AltConfig, Speed},
+    prelude::*,
+    spi::Spi,
+};
+
+#[entry]
+fn main() -> ! {
+    let p = device::Peripherals::take().unwrap();
+
+    let mut rcc = p.RCC.constrain();
+
+    let gpioa = p.GPIOA.split();
+    let gpiob = p.GPIOB.split();
+
+    // Prepare status LEDS. These happen to be the red and green ones on the
+    // NUCLEO-F746ZG board.
+    let mut green = gpiob.pb0.into_push_pull_output();
+    let mut red = gpiob.pb14.into_push_pull_output();
+
+    let alt = AltConfig {
+        speed: Speed::High,
+        ..AltConfig::default()
+    };
+    let sck = gpioa.pa5.into_alternate_af5_with(alt);
+    let miso = gpioa.pa6.into_alternate_af5_with(alt);
+    let mosi = gpioa.pa7.into_alternate_af5_with(alt);
+
+    let clocks = rcc.cfgr.sysclk(216.mhz()).freeze();
+
+    let mut spi = Spi::spi1(
+        p.SPI1,
+        (sck, miso, mosi),
+        embedded_hal::spi::MODE_0,
+        1.mhz(),
+        clocks,
+        &mut rcc.apb2,
+    );
+
+    let mut expected = [0; 64];
+    for (i, byte) in expected.iter_mut().enumerate() {
+        *byte = i as u8 ^ 0xa5;
+    }
+
+    loop {
+        let mut buffer = expected;
+        spi.transfer_in_place(&mut buffer).unwrap();
+
+        // With MOSI looped back to MISO, the buffer round-trips unchanged
+        if buffer == expected {
+            green.set_high().unwrap();
+            red.set_low().unwrap();
+        } else {
+            red.set_high().unwrap();
+            green.set_low().unwrap();
+        }
+    }
+}
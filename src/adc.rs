@@ -0,0 +1,615 @@
+//! Analog to Digital Converter (ADC)
+//!
+//! ```ignore
+//! let mut pa3 = gpioa.pa3.into_analog();
+//! let mut adc = Adc::adc1(dp.ADC1, &mut rcc.apb2, clocks);
+//!
+//! let counts: u16 = adc.read(&mut pa3).unwrap();
+//! let millivolts = adc.read_millivolts(&mut pa3);
+//!
+//! // Or without blocking while the ADC converts
+//! adc.start_conversion(&mut pa3).unwrap();
+//! let counts = loop {
+//!     match adc.read_result() {
+//!         Ok(counts) => break counts,
+//!         Err(nb::Error::WouldBlock) => { /* do something else */ }
+//!         Err(nb::Error::Other(e)) => match e {},
+//!     }
+//! };
+//! ```
+//!
+//! # Discontinuous mode
+//!
+//! [`Adc::into_discontinuous`] sets up a sequence of up to 16 channels, converted a chunk of
+//! up to 8 channels per trigger, e.g. once per PWM period:
+//!
+//! ```ignore
+//! let channels = [
+//!     <PA3<Analog> as Channel<ADC1>>::channel(),
+//!     <PA4<Analog> as Channel<ADC1>>::channel(),
+//!     <Temperature as Channel<ADC1>>::channel(),
+//! ];
+//! let trigger = Trigger::External(ExternalTrigger::Tim1Trgo, TriggerEdge::Rising);
+//! let mut adc = adc.into_discontinuous(&channels, 1, trigger);
+//!
+//! // One conversion per timer update
+//! let counts = nb::block!(adc.read_next()).unwrap();
+//! ```
+
+use core::ptr;
+
+use cortex_m::asm;
+use void::Void;
+
+use crate::device::{ADC1, ADC_COMMON};
+use crate::gpio::gpioa::{PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7};
+use crate::gpio::gpiob::{PB0, PB1};
+use crate::gpio::gpioc::{PC0, PC1, PC2, PC3, PC4, PC5};
+use crate::gpio::Analog;
+use crate::hal::adc::{Channel, OneShot};
+use crate::rcc::{Clocks, Reset, APB2};
+
+/// Maximum ADC clock frequency with VDDA between 2.4 V and 3.6 V
+const ADCCLK_MAX: u32 = 36_000_000;
+/// Full scale count at 12-bit resolution
+const FULL_SCALE: u32 = 4095;
+
+/// VREFINT count measured in production, at VDDA = 3.3 V
+const VREFINT_CAL: *const u16 = 0x1FF0_F44A as *const u16;
+/// Temperature sensor count measured in production at 30 °C, at VDDA = 3.3 V
+const TS_CAL1: *const u16 = 0x1FF0_F44C as *const u16;
+/// Temperature sensor count measured in production at 110 °C, at VDDA = 3.3 V
+const TS_CAL2: *const u16 = 0x1FF0_F44E as *const u16;
+/// VDDA the calibration values were measured at, in millivolts
+const CAL_VDDA: u32 = 3300;
+
+/// Minimum sampling time of the temperature sensor and of VREFINT, in nanoseconds
+const INTERNAL_SAMPLING_NS: u32 = 10_000;
+
+/// Internal channel of the temperature sensor
+const CHANNEL_TEMPERATURE: u8 = 18;
+/// Internal channel of the voltage reference
+const CHANNEL_VREFINT: u8 = 17;
+
+/// ADC error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// A conversion started with [`Adc::start_conversion`] hasn't been read yet
+    Busy,
+    /// A conversion finished before the result of the previous one was read, which is lost
+    Overrun,
+}
+
+/// Event starting the conversion of a chunk in discontinuous mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    /// [`Discontinuous::trigger`] starts the next chunk
+    Software,
+    /// An edge of a timer event or of EXTI line 11
+    External(ExternalTrigger, TriggerEdge),
+}
+
+/// External trigger of the regular conversions (EXTSEL)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExternalTrigger {
+    Tim1Cc1 = 0b0000,
+    Tim1Cc2 = 0b0001,
+    Tim1Cc3 = 0b0010,
+    Tim2Cc2 = 0b0011,
+    Tim5Trgo = 0b0100,
+    Tim4Cc4 = 0b0101,
+    Tim3Cc4 = 0b0110,
+    Tim8Trgo = 0b0111,
+    Tim8Trgo2 = 0b1000,
+    Tim1Trgo = 0b1001,
+    Tim1Trgo2 = 0b1010,
+    Tim2Trgo = 0b1011,
+    Tim4Trgo = 0b1100,
+    Tim6Trgo = 0b1101,
+    Exti11 = 0b1111,
+}
+
+/// Edge of the external trigger signal that starts a conversion (EXTEN)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerEdge {
+    Rising = 0b01,
+    Falling = 0b10,
+    Both = 0b11,
+}
+
+/// Returns SQR1, SQR2 and SQR3 for the regular sequence `channels`
+fn sequence_registers(channels: &[u8]) -> [u32; 3] {
+    let mut sqr = [0; 3];
+    for (k, &channel) in channels.iter().enumerate() {
+        // SQ1 - SQ6 are in SQR3, SQ7 - SQ12 in SQR2 and SQ13 - SQ16 in SQR1
+        sqr[2 - k / 6] |= u32::from(channel) << (5 * (k % 6));
+    }
+    // The sequence length L is stored minus one
+    sqr[0] |= ((channels.len() as u32).max(1) - 1) << 20;
+
+    sqr
+}
+
+/// Duration the input is sampled for, in ADC clock cycles
+///
+/// High impedance sources need longer sampling times to charge the sampling capacitor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleTime {
+    Cycles3 = 0b000,
+    Cycles15 = 0b001,
+    Cycles28 = 0b010,
+    Cycles56 = 0b011,
+    Cycles84 = 0b100,
+    Cycles112 = 0b101,
+    Cycles144 = 0b110,
+    Cycles480 = 0b111,
+}
+
+impl SampleTime {
+    const ALL: [SampleTime; 8] = [
+        SampleTime::Cycles3,
+        SampleTime::Cycles15,
+        SampleTime::Cycles28,
+        SampleTime::Cycles56,
+        SampleTime::Cycles84,
+        SampleTime::Cycles112,
+        SampleTime::Cycles144,
+        SampleTime::Cycles480,
+    ];
+
+    fn cycles(self) -> u32 {
+        match self {
+            SampleTime::Cycles3 => 3,
+            SampleTime::Cycles15 => 15,
+            SampleTime::Cycles28 => 28,
+            SampleTime::Cycles56 => 56,
+            SampleTime::Cycles84 => 84,
+            SampleTime::Cycles112 => 112,
+            SampleTime::Cycles144 => 144,
+            SampleTime::Cycles480 => 480,
+        }
+    }
+
+    /// Returns the shortest sample time lasting at least `nanos` at `adcclk`, or the longest
+    /// one if none does
+    fn at_least(adcclk: u32, nanos: u32) -> SampleTime {
+        let cycles = (u64::from(adcclk) * u64::from(nanos)).div_ceil(1_000_000_000);
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|sample_time| u64::from(sample_time.cycles()) >= cycles)
+            .unwrap_or(SampleTime::Cycles480)
+    }
+}
+
+/// Internal temperature sensor, read by [`Adc::read_temperature`]
+pub struct Temperature;
+
+/// Internal voltage reference, read by [`Adc::read_vdda`]
+pub struct Vrefint;
+
+macro_rules! channels {
+    ($ADC:ident, [$($PIN:ty: $channel:expr,)+]) => {
+        $(
+            impl Channel<$ADC> for $PIN {
+                type ID = u8;
+
+                fn channel() -> u8 {
+                    $channel
+                }
+            }
+        )+
+    }
+}
+
+channels!(ADC1, [
+    PA0<Analog>: 0,
+    PA1<Analog>: 1,
+    PA2<Analog>: 2,
+    PA3<Analog>: 3,
+    PA4<Analog>: 4,
+    PA5<Analog>: 5,
+    PA6<Analog>: 6,
+    PA7<Analog>: 7,
+    PB0<Analog>: 8,
+    PB1<Analog>: 9,
+    PC0<Analog>: 10,
+    PC1<Analog>: 11,
+    PC2<Analog>: 12,
+    PC3<Analog>: 13,
+    PC4<Analog>: 14,
+    PC5<Analog>: 15,
+    Vrefint: CHANNEL_VREFINT,
+    Temperature: CHANNEL_TEMPERATURE,
+]);
+
+/// Analog to Digital Converter, with 12-bit resolution
+pub struct Adc<ADC> {
+    adc: ADC,
+    sample_time: SampleTime,
+    /// Sample time of the internal channels, long enough for their 10 us minimum
+    internal_sample_time: SampleTime,
+    /// A conversion was started and its result not read yet
+    converting: bool,
+}
+
+impl Adc<ADC1> {
+    /// Enables and powers up ADC1, with the temperature sensor and VREFINT
+    ///
+    /// ADCCLK runs at the fastest division of the APB2 clock that stays within the 36 MHz
+    /// limit. All ADCs share the clock configuration and the reset line, so this also resets
+    /// ADC2 and ADC3.
+    pub fn adc1(mut adc: ADC1, apb2: &mut APB2, clocks: Clocks) -> Self {
+        // reset and enable peripheral to a clean slate state
+        adc.reset(apb2);
+        apb2.enr().modify(|_, w| w.adc1en().set_bit());
+
+        // NOTE(unsafe) the common registers only hold settings of ADC1 as long as ADC2 and ADC3
+        // aren't used
+        let common = unsafe { &*ADC_COMMON::ptr() };
+        let pclk2 = clocks.pclk2().0;
+        let prescaler = [2, 4, 6, 8]
+            .iter()
+            .copied()
+            .find(|prescaler| pclk2 / prescaler <= ADCCLK_MAX)
+            .unwrap_or(8);
+        common.ccr.modify(|_, w| {
+            let w = w.tsvrefe().set_bit();
+            match prescaler {
+                2 => w.adcpre().div2(),
+                4 => w.adcpre().div4(),
+                6 => w.adcpre().div6(),
+                _ => w.adcpre().div8(),
+            }
+        });
+        let adcclk = pclk2 / prescaler;
+
+        // Single conversion of the first channel in the regular sequence
+        adc.sqr1.write(|w| w.l().bits(0));
+        adc.cr2.write(|w| w.adon().set_bit());
+
+        // Wait out tSTAB of the ADC (3 us) and tSTART of the temperature sensor (10 us)
+        asm::delay(clocks.sysclk().0 / 1_000_000 * 10);
+
+        Adc {
+            adc,
+            sample_time: SampleTime::Cycles480,
+            internal_sample_time: SampleTime::at_least(adcclk, INTERNAL_SAMPLING_NS),
+            converting: false,
+        }
+    }
+
+    /// Sets the sample time of the following conversions
+    ///
+    /// Defaults to `Cycles480`, the slowest and most forgiving setting.
+    pub fn set_sample_time(&mut self, sample_time: SampleTime) {
+        self.sample_time = sample_time;
+    }
+
+    /// Returns the sample time of the following conversions
+    pub fn sample_time(&self) -> SampleTime {
+        self.sample_time
+    }
+
+    /// Measures the analog supply voltage VDDA in millivolts, using VREFINT
+    pub fn read_vdda(&mut self) -> u16 {
+        let vrefint = u32::from(self.convert(CHANNEL_VREFINT, self.internal_sample_time));
+
+        // NOTE(unsafe) read of a factory programmed value in system memory
+        let cal = u32::from(unsafe { ptr::read(VREFINT_CAL) });
+
+        (CAL_VDDA * cal / vrefint) as u16
+    }
+
+    /// Converts the voltage on `pin` and returns it in millivolts
+    ///
+    /// The conversion is scaled by VDDA, measured with VREFINT right before.
+    pub fn read_millivolts<PIN>(&mut self, _pin: &mut PIN) -> u16
+    where
+        PIN: Channel<ADC1, ID = u8>,
+    {
+        let vdda = u32::from(self.read_vdda());
+        let counts = u32::from(self.convert(PIN::channel(), self.sample_time));
+
+        (counts * vdda / FULL_SCALE) as u16
+    }
+
+    /// Measures the die temperature in degrees Celsius with the internal temperature sensor
+    ///
+    /// The result is interpolated between the two factory calibration points at 30 °C and
+    /// 110 °C, after correcting the conversion for VDDA. The sensor is always sampled for at
+    /// least the 10 us it needs, whatever [`set_sample_time`](Self::set_sample_time) selected.
+    pub fn read_temperature(&mut self) -> f32 {
+        self.read_temperature_averaged(1)
+    }
+
+    /// Measures the die temperature like [`read_temperature`](Self::read_temperature), from
+    /// the average of `samples` conversions
+    ///
+    /// Averaging smooths out the noise of the sensor, each conversion takes about 10 us.
+    /// `samples` is at least 1.
+    pub fn read_temperature_averaged(&mut self, samples: u16) -> f32 {
+        let samples = samples.max(1);
+        let vdda = self.read_vdda() as f32;
+
+        let sample_time = self.internal_sample_time;
+        let sum = (0..samples).fold(0, |sum, _| {
+            sum + u32::from(self.convert(CHANNEL_TEMPERATURE, sample_time))
+        });
+        let counts = sum as f32 / f32::from(samples) * vdda / CAL_VDDA as f32;
+
+        // NOTE(unsafe) read of factory programmed values in system memory
+        let (cal1, cal2) = unsafe { (ptr::read(TS_CAL1) as f32, ptr::read(TS_CAL2) as f32) };
+
+        (counts - cal1) * (110.0 - 30.0) / (cal2 - cal1) + 30.0
+    }
+
+    /// Starts a conversion of `pin` and returns right away
+    ///
+    /// The result is picked up with [`read_result`](Self::read_result). Only one conversion can
+    /// be pending, starting another one before its result was read returns [`Error::Busy`].
+    pub fn start_conversion<PIN>(&mut self, _pin: &mut PIN) -> Result<(), Error>
+    where
+        PIN: Channel<ADC1, ID = u8>,
+    {
+        if self.converting {
+            return Err(Error::Busy);
+        }
+
+        self.start(PIN::channel(), self.sample_time);
+        Ok(())
+    }
+
+    /// Returns the result of the conversion started with
+    /// [`start_conversion`](Self::start_conversion)
+    ///
+    /// Returns `WouldBlock` until the conversion is done, and also if none was started.
+    pub fn read_result(&mut self) -> nb::Result<u16, Void> {
+        if !self.converting || self.adc.sr.read().eoc().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.converting = false;
+        // Reading DR clears EOC
+        Ok(self.adc.dr.read().data().bits())
+    }
+
+    /// Powers down the ADC and releases the peripheral
+    pub fn free(self) -> ADC1 {
+        self.adc.cr2.modify(|_, w| w.adon().clear_bit());
+        self.adc
+    }
+
+    /// Runs a single conversion of `channel`, blocking until the result is ready
+    ///
+    /// A pending conversion started with `start_conversion` is waited for and its result
+    /// dropped.
+    fn convert(&mut self, channel: u8, sample_time: SampleTime) -> u16 {
+        if self.converting {
+            let _ = nb::block!(self.read_result());
+        }
+
+        self.start(channel, sample_time);
+        match nb::block!(self.read_result()) {
+            Ok(counts) => counts,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Selects `channel` as the only one in the regular sequence and starts its conversion
+    fn start(&mut self, channel: u8, sample_time: SampleTime) {
+        self.write_sample_time(channel, sample_time);
+        self.adc.sqr3.write(|w| unsafe { w.sq1().bits(channel) });
+
+        self.adc.cr2.modify(|_, w| w.swstart().set_bit());
+        self.converting = true;
+    }
+
+    /// Programs the sample time of `channel`
+    ///
+    /// The internal channels are sampled for at least their minimum sampling time.
+    fn write_sample_time(&mut self, channel: u8, sample_time: SampleTime) {
+        let internal = channel == CHANNEL_TEMPERATURE || channel == CHANNEL_VREFINT;
+        let sample_time = if internal && sample_time.cycles() < self.internal_sample_time.cycles() {
+            self.internal_sample_time
+        } else {
+            sample_time
+        };
+
+        let smp = sample_time as u32;
+        if channel < 10 {
+            let offset = 3 * u32::from(channel);
+            self.adc.smpr2.modify(|r, w| unsafe {
+                w.bits((r.bits() & !(0b111 << offset)) | (smp << offset))
+            });
+        } else {
+            let offset = 3 * u32::from(channel - 10);
+            self.adc.smpr1.modify(|r, w| unsafe {
+                w.bits((r.bits() & !(0b111 << offset)) | (smp << offset))
+            });
+        }
+    }
+
+    /// Converts the sequence `channels` in chunks of `chunk` channels, one chunk per trigger
+    ///
+    /// The channel numbers are those of [`Channel::channel`], the internal channels included.
+    /// Each trigger converts the next chunk and raises EOC after every conversion, the
+    /// sequence starts over once its last chunk was converted. The last chunk is shorter if
+    /// `chunk` doesn't divide the sequence length. All channels use the current sample time.
+    ///
+    /// An external trigger arriving while the chunk of the previous one is still being
+    /// converted is ignored, so the chunk has to be converted within one trigger period. A
+    /// pending conversion started with `start_conversion` is waited for and its result
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// If `channels` is empty or longer than 16, or `chunk` isn't in 1 - 8.
+    pub fn into_discontinuous(
+        mut self,
+        channels: &[u8],
+        chunk: u8,
+        trigger: Trigger,
+    ) -> Discontinuous<ADC1> {
+        assert!((1..=16).contains(&channels.len()));
+        assert!((1..=8).contains(&chunk));
+
+        if self.converting {
+            let _ = nb::block!(self.read_result());
+        }
+
+        let sample_time = self.sample_time;
+        for &channel in channels {
+            self.write_sample_time(channel, sample_time);
+        }
+        let [sqr1, sqr2, sqr3] = sequence_registers(channels);
+        // NOTE(unsafe) the sequence holds 5-bit channel numbers and its length minus one
+        unsafe {
+            self.adc.sqr1.write(|w| w.bits(sqr1));
+            self.adc.sqr2.write(|w| w.bits(sqr2));
+            self.adc.sqr3.write(|w| w.bits(sqr3));
+        }
+
+        self.adc.cr1.modify(|_, w| {
+            w.scan()
+                .set_bit()
+                .discen()
+                .set_bit()
+                .discnum()
+                .bits(chunk - 1)
+        });
+        // NOTE(unsafe) EXTSEL and EXTEN are written with the values of the enums
+        self.adc.cr2.modify(|_, w| unsafe {
+            let w = w.eocs().set_bit();
+            match trigger {
+                Trigger::Software => w.exten().disabled(),
+                Trigger::External(source, edge) => {
+                    w.extsel().bits(source as u8).exten().bits(edge as u8)
+                }
+            }
+        });
+
+        Discontinuous { adc: self }
+    }
+}
+
+/// ADC converting a sequence in chunks, see [`Adc::into_discontinuous`]
+pub struct Discontinuous<ADC> {
+    adc: Adc<ADC>,
+}
+
+impl Discontinuous<ADC1> {
+    /// Starts the conversion of the next chunk, with [`Trigger::Software`]
+    pub fn trigger(&mut self) {
+        self.adc.adc.cr2.modify(|_, w| w.swstart().set_bit());
+    }
+
+    /// Returns the result of the next conversion of the sequence
+    ///
+    /// Returns `WouldBlock` until it's done. After an overrun the conversions of the chunk
+    /// stop, and the next trigger starts over with the first chunk of the sequence.
+    pub fn read_next(&mut self) -> nb::Result<u16, Error> {
+        let adc = &self.adc.adc;
+        let sr = adc.sr.read();
+
+        if sr.ovr().bit_is_set() {
+            adc.sr.modify(|_, w| w.ovr().clear_bit());
+            return Err(nb::Error::Other(Error::Overrun));
+        }
+        if sr.eoc().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // Reading DR clears EOC
+        Ok(adc.dr.read().data().bits())
+    }
+
+    /// Stops the triggers and returns to single conversions
+    pub fn into_single(self) -> Adc<ADC1> {
+        let adc = self.adc;
+
+        adc.adc
+            .cr2
+            .modify(|_, w| w.exten().disabled().eocs().clear_bit());
+        adc.adc
+            .cr1
+            .modify(|_, w| w.scan().clear_bit().discen().clear_bit());
+        adc.adc.sqr1.write(|w| w.l().bits(0));
+        adc.adc
+            .sr
+            .modify(|_, w| w.ovr().clear_bit().eoc().clear_bit());
+
+        adc
+    }
+}
+
+impl<PIN> OneShot<ADC1, u16, PIN> for Adc<ADC1>
+where
+    PIN: Channel<ADC1, ID = u8>,
+{
+    type Error = Void;
+
+    /// Converts `pin`, blocking until the result is ready
+    ///
+    /// A conversion still pending from [`Adc::start_conversion`] is completed first and its
+    /// result dropped.
+    fn read(&mut self, _pin: &mut PIN) -> nb::Result<u16, Void> {
+        Ok(self.convert(PIN::channel(), self.sample_time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_sample_time_lasts_10_us() {
+        // 36 MHz needs 360 cycles, 27 MHz 270
+        assert_eq!(
+            SampleTime::at_least(36_000_000, 10_000),
+            SampleTime::Cycles480
+        );
+        assert_eq!(
+            SampleTime::at_least(27_000_000, 10_000),
+            SampleTime::Cycles480
+        );
+        // 13.5 MHz needs 135 cycles
+        assert_eq!(
+            SampleTime::at_least(13_500_000, 10_000),
+            SampleTime::Cycles144
+        );
+        assert_eq!(
+            SampleTime::at_least(2_000_000, 10_000),
+            SampleTime::Cycles28
+        );
+    }
+
+    #[test]
+    fn sequence_fills_sqr3_first() {
+        assert_eq!(sequence_registers(&[3]), [0, 0, 3]);
+        assert_eq!(
+            sequence_registers(&[1, 2, 3, 4, 5, 6, 7]),
+            [
+                6 << 20,
+                7,
+                1 | 2 << 5 | 3 << 10 | 4 << 15 | 5 << 20 | 6 << 25
+            ]
+        );
+    }
+
+    #[test]
+    fn sequence_of_16_channels_uses_sqr1() {
+        let channels = [18; 16];
+        let [sqr1, sqr2, sqr3] = sequence_registers(&channels);
+        assert_eq!(sqr1, 15 << 20 | 18 | 18 << 5 | 18 << 10 | 18 << 15);
+        assert_eq!(sqr2, sqr3);
+    }
+
+    #[test]
+    fn longest_sample_time_when_none_is_enough() {
+        assert_eq!(
+            SampleTime::at_least(100_000_000, 10_000),
+            SampleTime::Cycles480
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Digital camera interface (DCMI)
+//!
+//! Captures the frames of a camera with a parallel bus (OV2640, OV7670, ...) into memory
+//! through DMA. The camera drives the pixel clock and the sync signals, the DCMI samples
+//! the data lines on every pixel clock edge and packs them into 32-bit words.
+//!
+//! ```ignore
+//! let dcmi = Dcmi::new(dp.DCMI, pins, dcmi::Config::default(), &mut rcc.ahb2);
+//! let streams = dp.DMA2.split(&mut rcc.ahb1);
+//!
+//! static mut FRAME: [u32; 160 * 120 / 2] = [0; 160 * 120 / 2];
+//! let transfer = dcmi.with_dma(streams.1).capture(unsafe { &mut FRAME });
+//! let transfer = transfer.map_err(|(error, ..)| error).unwrap();
+//! let (frame, dcmi) = transfer.wait().map_err(|(error, ..)| error).unwrap();
+//! ```
+//!
+//! # Clocks
+//!
+//! The interface runs on HCLK, PLLSAI isn't involved, and the pixel clock has to stay below
+//! 54 MHz. Most cameras need a master clock of their own (XCLK), which has to come from a
+//! timer output, the MCO pins or an oscillator on the camera module.
+//!
+//! # Data width
+//!
+//! The bus is 8, 10, 12 or 14 bits wide. The typed pins cover the sync signals and D0 - D7;
+//! for a wider bus D8 - D13 have to be put into AF13 as well. An 8-bit bus packs 4 samples
+//! into a word, the wider ones 2.
+//!
+//! # Buffers
+//!
+//! A transfer moves at most 65535 words, 256 KiB, and only completes once the buffer is
+//! full. In snapshot mode the buffer has to be the size of exactly one frame, in continuous
+//! mode the frames are written one behind the other until it's full.
+
+use core::ptr;
+
+use crate::device::{DCMI, DMA2};
+use crate::dma::{self, DmaStream, Stream, Transfer};
+use crate::gpio::gpioa::{PA10, PA4, PA6, PA9};
+use crate::gpio::gpiob::{PB6, PB7, PB8, PB9};
+use crate::gpio::gpioc::{PC11, PC6, PC7, PC8, PC9};
+use crate::gpio::gpiod::PD3;
+use crate::gpio::gpioe::{PE0, PE1, PE4, PE5, PE6};
+use crate::gpio::gpiog::{PG10, PG11, PG9};
+use crate::gpio::gpioh::{PH10, PH11, PH12, PH14, PH8, PH9};
+use crate::gpio::gpioi::{PI4, PI5, PI6, PI7};
+use crate::gpio::{Alternate, AF13};
+use crate::rcc::{Reset, AHB2};
+
+/// Which frames are captured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureMode {
+    /// A single frame, the capture stops at the end of it
+    Snapshot,
+    /// Every frame, until the buffer is full
+    Continuous,
+}
+
+/// Number of data lines sampled on every pixel clock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataWidth {
+    Bits8 = 0b00,
+    Bits10 = 0b01,
+    Bits12 = 0b10,
+    Bits14 = 0b11,
+}
+
+/// Level of a sync signal while no data is transferred, during blanking
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolarity {
+    Low,
+    High,
+}
+
+/// Edge of the pixel clock the data lines are sampled on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelClockEdge {
+    Falling,
+    Rising,
+}
+
+/// DCMI configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub mode: CaptureMode,
+    pub width: DataWidth,
+    pub vsync: SyncPolarity,
+    pub hsync: SyncPolarity,
+    pub pixel_clock: PixelClockEdge,
+    /// Compressed data, whose lines have no fixed length. HSYNC then only marks valid data.
+    pub jpeg: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            mode: CaptureMode::Snapshot,
+            width: DataWidth::Bits8,
+            vsync: SyncPolarity::Low,
+            hsync: SyncPolarity::Low,
+            pixel_clock: PixelClockEdge::Rising,
+            jpeg: false,
+        }
+    }
+}
+
+/// Interrupt event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// A frame was captured completely
+    Frame,
+    /// A line was captured completely
+    Line,
+    /// VSYNC changed from the blanking to the active level, a frame starts
+    Vsync,
+    /// A word was received before the DMA read the previous one
+    Overrun,
+}
+
+/// Pins usable as the pixel clock input
+pub trait PinPixclk {}
+
+/// Pins usable as the horizontal sync input
+pub trait PinHsync {}
+
+/// Pins usable as the vertical sync input
+pub trait PinVsync {}
+
+/// Pins usable as data line `N`
+pub trait PinData<const N: usize> {}
+
+/// PIXCLK / HSYNC / VSYNC and D0 - D7 pins of the DCMI
+pub trait Pins {}
+
+impl<PIXCLK, HSYNC, VSYNC, D0, D1, D2, D3, D4, D5, D6, D7> Pins
+    for (PIXCLK, HSYNC, VSYNC, D0, D1, D2, D3, D4, D5, D6, D7)
+where
+    PIXCLK: PinPixclk,
+    HSYNC: PinHsync,
+    VSYNC: PinVsync,
+    D0: PinData<0>,
+    D1: PinData<1>,
+    D2: PinData<2>,
+    D3: PinData<3>,
+    D4: PinData<4>,
+    D5: PinData<5>,
+    D6: PinData<6>,
+    D7: PinData<7>,
+{
+}
+
+macro_rules! pins {
+    ($($trait:ty: [$($PIN:ident),+],)+) => {
+        $($(
+            impl $trait for $PIN<Alternate<AF13>> {}
+        )+)+
+    }
+}
+
+pins! {
+    PinPixclk: [PA6],
+    PinHsync: [PA4, PH8],
+    PinVsync: [PB7, PG9, PI5],
+    PinData<0>: [PA9, PC6, PH9],
+    PinData<1>: [PA10, PC7, PH10],
+    PinData<2>: [PC8, PE0, PG10, PH11],
+    PinData<3>: [PC9, PE1, PG11, PH12],
+    PinData<4>: [PC11, PE4, PH14],
+    PinData<5>: [PB6, PD3, PI4],
+    PinData<6>: [PB8, PE5, PI6],
+    PinData<7>: [PB9, PE6, PI7],
+}
+
+/// DMA stream and channel that can read the DCMI data register
+pub trait DcmiStream: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+impl DcmiStream for Stream<DMA2, 1> {
+    const CHANNEL: u8 = 1;
+}
+
+impl DcmiStream for Stream<DMA2, 7> {
+    const CHANNEL: u8 = 1;
+}
+
+/// Digital camera interface
+pub struct Dcmi<PINS> {
+    dcmi: DCMI,
+    pins: PINS,
+}
+
+impl<PINS> Dcmi<PINS>
+where
+    PINS: Pins,
+{
+    /// Configures the DCMI, which then waits for a capture to be started
+    pub fn new(mut dcmi: DCMI, pins: PINS, config: Config, ahb2: &mut AHB2) -> Self {
+        // reset and enable peripheral to a clean slate state
+        dcmi.reset(ahb2);
+        ahb2.enr().modify(|_, w| w.dcmien().set_bit());
+
+        // NOTE(unsafe) EDM is written with one of the 4 valid widths
+        dcmi.cr.write(|w| unsafe {
+            w.cm()
+                .bit(config.mode == CaptureMode::Snapshot)
+                .edm()
+                .bits(config.width as u8)
+                .vspol()
+                .bit(config.vsync == SyncPolarity::High)
+                .hspol()
+                .bit(config.hsync == SyncPolarity::High)
+                .pckpol()
+                .bit(config.pixel_clock == PixelClockEdge::Rising)
+                .jpeg()
+                .bit(config.jpeg)
+        });
+        // The configuration must be complete before the interface is enabled
+        dcmi.cr.modify(|_, w| w.enable().set_bit());
+
+        Dcmi { dcmi, pins }
+    }
+
+    /// Starts listening for an interrupt event
+    pub fn listen(&mut self, event: Event) {
+        self.dcmi.ier.modify(|_, w| match event {
+            Event::Frame => w.frame_ie().set_bit(),
+            Event::Line => w.line_ie().set_bit(),
+            Event::Vsync => w.vsync_ie().set_bit(),
+            Event::Overrun => w.ovr_ie().set_bit(),
+        });
+    }
+
+    /// Stops listening for an interrupt event
+    pub fn unlisten(&mut self, event: Event) {
+        self.dcmi.ier.modify(|_, w| match event {
+            Event::Frame => w.frame_ie().clear_bit(),
+            Event::Line => w.line_ie().clear_bit(),
+            Event::Vsync => w.vsync_ie().clear_bit(),
+            Event::Overrun => w.ovr_ie().clear_bit(),
+        });
+    }
+
+    /// Returns `true` if `event` occurred since its flag was last cleared
+    pub fn is_pending(&self, event: Event) -> bool {
+        let ris = self.dcmi.ris.read();
+        match event {
+            Event::Frame => ris.frame_ris().bit_is_set(),
+            Event::Line => ris.line_ris().bit_is_set(),
+            Event::Vsync => ris.vsync_ris().bit_is_set(),
+            Event::Overrun => ris.ovr_ris().bit_is_set(),
+        }
+    }
+
+    /// Clears the flag of `event`, which also ends its interrupt
+    pub fn clear_pending(&mut self, event: Event) {
+        self.dcmi.icr.write(|w| match event {
+            Event::Frame => w.frame_isc().set_bit(),
+            Event::Line => w.line_isc().set_bit(),
+            Event::Vsync => w.vsync_isc().set_bit(),
+            Event::Overrun => w.ovr_isc().set_bit(),
+        });
+    }
+
+    /// Binds the DCMI to a DMA stream
+    pub fn with_dma<STREAM>(self, stream: STREAM) -> DcmiDma<PINS, STREAM>
+    where
+        STREAM: DcmiStream,
+    {
+        DcmiDma { dcmi: self, stream }
+    }
+
+    /// Disables the DCMI and releases it and the pins
+    pub fn free(self) -> (DCMI, PINS) {
+        self.dcmi
+            .cr
+            .modify(|_, w| w.capture().clear_bit().enable().clear_bit());
+        (self.dcmi, self.pins)
+    }
+}
+
+/// DCMI bound to a DMA stream
+pub struct DcmiDma<PINS, STREAM> {
+    dcmi: Dcmi<PINS>,
+    stream: STREAM,
+}
+
+impl<PINS, STREAM> DcmiDma<PINS, STREAM>
+where
+    PINS: Pins,
+    STREAM: DcmiStream,
+{
+    /// Starts capturing into `buffer`, with the next frame
+    ///
+    /// The transfer completes once the buffer is full, see the
+    /// [module documentation](self#buffers) for its size.
+    #[allow(clippy::type_complexity)]
+    pub fn capture(
+        self,
+        buffer: &'static mut [u32],
+    ) -> Result<Transfer<Self, &'static mut [u32]>, (dma::Error, &'static mut [u32], Self)> {
+        // A continuous capture keeps running after its buffer is full
+        self.stop();
+        self.dcmi.dcmi.icr.write(|w| w.ovr_isc().set_bit());
+
+        let stream = self.stream.handle();
+        let started = stream.start(dma::Config {
+            channel: STREAM::CHANNEL,
+            direction: dma::Direction::PeripheralToMemory,
+            width: dma::Width::Word,
+            // NOTE(unsafe) only the address of the register is taken
+            peripheral: unsafe { ptr::addr_of!((*DCMI::ptr()).dr) as u32 },
+            memory0: buffer.as_ptr() as u32,
+            memory1: None,
+            len: buffer.len(),
+        });
+        if let Err(error) = started {
+            return Err((error, buffer, self));
+        }
+
+        // The capture starts with the next frame, once the stream is ready for it
+        self.dcmi.dcmi.cr.modify(|_, w| w.capture().set_bit());
+
+        Ok(Transfer::new(self, buffer, stream))
+    }
+
+    /// Returns the DCMI, e.g. to look at its events while no transfer runs
+    pub fn dcmi(&mut self) -> &mut Dcmi<PINS> {
+        &mut self.dcmi
+    }
+
+    /// Stops capturing and releases the DCMI and the stream
+    pub fn free(self) -> (Dcmi<PINS>, STREAM) {
+        self.stop();
+        (self.dcmi, self.stream)
+    }
+
+    fn stop(&self) {
+        self.dcmi.dcmi.cr.modify(|_, w| w.capture().clear_bit());
+    }
+}
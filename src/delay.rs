@@ -1,11 +1,38 @@
 //! Delays
 
-use cast::u32;
+use cast::{u32, u64};
+use cortex_m::asm;
+use cortex_m::interrupt;
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m::peripheral::SYST;
 
 use crate::hal::blocking::delay::{DelayMs, DelayUs};
 use crate::rcc::Clocks;
+use crate::time::Hertz;
+
+/// Returns the number of SysTick cycles, at HCLK / 8, in `count` periods of `1 / per_second` s
+///
+/// Rounds up, so a delay is never shorter than requested.
+fn ticks(hclk: Hertz, count: u32, per_second: u32) -> u64 {
+    (u64(count) * u64(hclk.0 / 8)).div_ceil(u64(per_second))
+}
+
+/// Returns the reload values of the SysTick windows making up `ticks` cycles
+///
+/// Every window but the last one is the full 2^24 cycles the 24-bit reload allows.
+fn reloads(mut ticks: u64) -> impl Iterator<Item = u32> {
+    core::iter::from_fn(move || {
+        if ticks == 0 {
+            return None;
+        }
+
+        let chunk = ticks.min(1 << 24);
+        ticks -= chunk;
+
+        // `chunk` is at least 1 and at most 2^24, so the reload fits in 24 bits
+        Some(chunk as u32 - 1)
+    })
+}
 
 /// System timer (SysTick) as a delay provider
 pub struct Delay {
@@ -25,11 +52,24 @@ impl Delay {
     pub fn free(self) -> SYST {
         self.syst
     }
+
+    /// Waits for `ticks` SysTick clock cycles, in chunks of up to 2^24 cycles
+    fn spin(&mut self, ticks: u64) {
+        for reload in reloads(ticks) {
+            self.syst.set_reload(reload);
+            self.syst.clear_current();
+            self.syst.enable_counter();
+
+            while !self.syst.has_wrapped() {}
+
+            self.syst.disable_counter();
+        }
+    }
 }
 
 impl DelayMs<u32> for Delay {
     fn delay_ms(&mut self, ms: u32) {
-        self.delay_us(ms * 1_000);
+        self.spin(ticks(self.clocks.hclk(), ms, 1_000));
     }
 }
 
@@ -47,28 +87,161 @@ impl DelayMs<u8> for Delay {
 
 impl DelayUs<u32> for Delay {
     fn delay_us(&mut self, us: u32) {
-        let rvr = us * (self.clocks.hclk().0 / 8_000_000);
+        self.spin(ticks(self.clocks.hclk(), us, 1_000_000));
+    }
+}
+
+impl DelayUs<u16> for Delay {
+    fn delay_us(&mut self, us: u16) {
+        self.delay_us(u32(us))
+    }
+}
+
+impl DelayUs<u8> for Delay {
+    fn delay_us(&mut self, us: u8) {
+        self.delay_us(u32(us))
+    }
+}
 
-        assert!(rvr < (1 << 24));
+/// System timer (SysTick) as a delay provider that sleeps between ticks
+///
+/// Instead of spinning on the counter, the core executes `WFI` until the SysTick interrupt wakes
+/// it up, which lowers the current consumption during long delays. This requires a `SysTick`
+/// exception handler (an empty one will do) and the SysTick exception must not be masked by
+/// `BASEPRI`. Any other interrupt also wakes the core; it is served and the delay goes back to
+/// sleep, so a delay never ends early but may end up to one wake-up latency late.
+///
+/// [`Delay`] remains available as the busy-waiting fallback.
+pub struct WfiDelay {
+    clocks: Clocks,
+    syst: SYST,
+}
 
-        self.syst.set_reload(rvr);
-        self.syst.clear_current();
-        self.syst.enable_counter();
+impl WfiDelay {
+    /// Configures the system timer (SysTick) as a sleeping delay provider
+    pub fn new(mut syst: SYST, clocks: Clocks) -> Self {
+        syst.set_clock_source(SystClkSource::External);
+
+        WfiDelay { syst, clocks }
+    }
 
-        while !self.syst.has_wrapped() {}
+    /// Releases the system timer (SysTick) resource
+    pub fn free(self) -> SYST {
+        self.syst
+    }
 
-        self.syst.disable_counter();
+    /// Sleeps for `ticks` SysTick clock cycles, in chunks of up to 2^24 cycles
+    fn sleep(&mut self, ticks: u64) {
+        self.syst.enable_interrupt();
+
+        for reload in reloads(ticks) {
+            self.syst.set_reload(reload);
+            self.syst.clear_current();
+            self.syst.enable_counter();
+
+            // Checking the flag and going to sleep has to be atomic, otherwise the wake-up could
+            // slip in between and the core would sleep for another period. A pending interrupt
+            // still ends `WFI` while interrupts are masked; it is served right after.
+            while !interrupt::free(|_| {
+                let wrapped = self.syst.has_wrapped();
+                if !wrapped {
+                    asm::wfi();
+                }
+                wrapped
+            }) {}
+
+            self.syst.disable_counter();
+        }
+
+        self.syst.disable_interrupt();
     }
 }
 
-impl DelayUs<u16> for Delay {
+impl DelayMs<u32> for WfiDelay {
+    fn delay_ms(&mut self, ms: u32) {
+        self.sleep(ticks(self.clocks.hclk(), ms, 1_000));
+    }
+}
+
+impl DelayMs<u16> for WfiDelay {
+    fn delay_ms(&mut self, ms: u16) {
+        self.delay_ms(u32(ms));
+    }
+}
+
+impl DelayMs<u8> for WfiDelay {
+    fn delay_ms(&mut self, ms: u8) {
+        self.delay_ms(u32(ms));
+    }
+}
+
+impl DelayUs<u32> for WfiDelay {
+    fn delay_us(&mut self, us: u32) {
+        self.sleep(ticks(self.clocks.hclk(), us, 1_000_000));
+    }
+}
+
+impl DelayUs<u16> for WfiDelay {
     fn delay_us(&mut self, us: u16) {
         self.delay_us(u32(us))
     }
 }
 
-impl DelayUs<u8> for Delay {
+impl DelayUs<u8> for WfiDelay {
     fn delay_us(&mut self, us: u8) {
         self.delay_us(u32(us))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_run_at_an_eighth_of_hclk() {
+        assert_eq!(ticks(Hertz(216_000_000), 1, 1_000), 27_000);
+        assert_eq!(ticks(Hertz(216_000_000), 1, 1_000_000), 27);
+        assert_eq!(ticks(Hertz(16_000_000), 5, 1_000_000), 10);
+    }
+
+    #[test]
+    fn short_delays_round_up() {
+        // 1 us at 1 MHz HCLK is an eighth of a tick
+        assert_eq!(ticks(Hertz(1_000_000), 1, 1_000_000), 1);
+        assert_eq!(ticks(Hertz(1_000_000), 8, 1_000_000), 1);
+        assert_eq!(ticks(Hertz(1_000_000), 9, 1_000_000), 2);
+        assert_eq!(ticks(Hertz(1_000_000), 0, 1_000_000), 0);
+    }
+
+    #[test]
+    fn no_window_without_ticks() {
+        assert_eq!(reloads(0).count(), 0);
+    }
+
+    #[test]
+    fn short_delay_fits_one_window() {
+        assert!(reloads(1).eq([0]));
+        assert!(reloads(27_000).eq([26_999]));
+        assert!(reloads(1 << 24).eq([0xff_ffff]));
+    }
+
+    #[test]
+    fn long_delay_spans_full_windows() {
+        assert!(reloads((1 << 24) + 1).eq([0xff_ffff, 0]));
+        assert!(reloads(3 << 24).eq([0xff_ffff; 3]));
+
+        // 10 s at 27 MHz
+        let ticks = 270_000_000;
+        let total: u64 = reloads(ticks).map(|reload| u64::from(reload) + 1).sum();
+        assert_eq!(total, ticks);
+        assert_eq!(reloads(ticks).count(), 17);
+    }
+
+    #[test]
+    fn long_delays_do_not_overflow() {
+        assert_eq!(
+            ticks(Hertz(216_000_000), u32::MAX, 1_000),
+            u64::from(u32::MAX) * 27_000
+        );
+    }
+}
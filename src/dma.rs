@@ -0,0 +1,582 @@
+//! Direct Memory Access (DMA) streams
+//!
+//! The controllers are split into their 8 streams. Peripheral drivers take a stream with a
+//! `with_dma` method and return [`Transfer`]s which own the buffer and the peripheral until
+//! the stream is done with them.
+//!
+//! ```ignore
+//! let streams = dp.DMA1.split(&mut rcc.ahb1);
+//! let (tx, _rx) = serial.split();
+//!
+//! static mut BUFFER: [u8; 5] = *b"hello";
+//! let transfer = tx.with_dma(streams.3).write(unsafe { &mut BUFFER });
+//! let transfer = transfer.map_err(|(error, ..)| error).unwrap();
+//! let (buffer, tx) = transfer.wait().map_err(|(error, ..)| error).unwrap();
+//! ```
+//!
+//! A stream moves 1 to 65535 items per transfer, starting one with an empty or a longer
+//! buffer returns [`Error::BufferLength`] along with the buffer and the peripheral.
+//!
+//! A transfer stops its stream before it hands the buffer and the peripheral back, and also
+//! when it's dropped, so the stream never keeps accessing a buffer that has been given up.
+//!
+//! The data cache doesn't see DMA accesses. Keep the buffers in DTCM or in a region the MPU
+//! makes non-cacheable, or clean / invalidate the cache around transfers.
+
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use crate::device::dma2::{RegisterBlock, ST};
+use crate::device::{DMA1, DMA2};
+use crate::rcc::{Reset, AHB1};
+
+/// FIFO error flag
+const FEIF: u32 = 1 << 0;
+/// Direct mode error flag
+const DMEIF: u32 = 1 << 2;
+/// Transfer error flag
+const TEIF: u32 = 1 << 3;
+/// Half transfer flag
+const HTIF: u32 = 1 << 4;
+/// Transfer complete flag
+const TCIF: u32 = 1 << 5;
+const FLAGS: u32 = FEIF | DMEIF | TEIF | HTIF | TCIF;
+
+/// Offset of the flags of streams 0 - 3 in LISR, and of 4 - 7 in HISR
+const FLAG_OFFSETS: [usize; 4] = [0, 6, 16, 22];
+
+/// Largest number of items of a transfer, NDTR is 16 bits wide
+const MAX_LEN: usize = 0xffff;
+
+/// DMA error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// A bus error occurred, e.g. the stream accessed a reserved address
+    Transfer,
+    /// The FIFO overran or underran
+    Fifo,
+    /// The buffer was used after the stream had already filled it again
+    Overrun,
+    /// The buffer is empty or holds more than 65535 items
+    BufferLength,
+}
+
+/// Returns `BufferLength` unless a stream can transfer `len` items in one go
+pub(crate) fn check_len(len: usize) -> Result<(), Error> {
+    if (1..=MAX_LEN).contains(&len) {
+        Ok(())
+    } else {
+        Err(Error::BufferLength)
+    }
+}
+
+/// Priority of a stream over the other streams of its controller
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+/// Extension trait to split a DMA controller into its streams
+pub trait DmaExt {
+    /// The streams to split the controller into
+    type Streams;
+
+    /// Enables and resets the controller, then splits it into its streams
+    fn split(self, ahb1: &mut AHB1) -> Self::Streams;
+}
+
+mod sealed {
+    use crate::device::dma2::RegisterBlock;
+
+    pub trait Instance {
+        fn ptr() -> *const RegisterBlock;
+    }
+}
+
+/// DMA stream `N` of controller `DMA`
+pub struct Stream<DMA, const N: usize> {
+    priority: Priority,
+    _dma: PhantomData<DMA>,
+}
+
+impl<DMA, const N: usize> Stream<DMA, N>
+where
+    DMA: sealed::Instance,
+{
+    fn new() -> Self {
+        Stream {
+            priority: Priority::Medium,
+            _dma: PhantomData,
+        }
+    }
+
+    /// Sets the priority of the following transfers, `Medium` by default
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+}
+
+/// A DMA stream of either controller
+pub trait DmaStream {
+    #[doc(hidden)]
+    fn handle(&self) -> Handle;
+}
+
+impl<DMA, const N: usize> DmaStream for Stream<DMA, N>
+where
+    DMA: sealed::Instance,
+{
+    fn handle(&self) -> Handle {
+        Handle {
+            dma: DMA::ptr(),
+            n: N,
+            priority: self.priority,
+        }
+    }
+}
+
+/// The 8 streams of a DMA controller
+pub struct StreamsTuple<DMA>(
+    pub Stream<DMA, 0>,
+    pub Stream<DMA, 1>,
+    pub Stream<DMA, 2>,
+    pub Stream<DMA, 3>,
+    pub Stream<DMA, 4>,
+    pub Stream<DMA, 5>,
+    pub Stream<DMA, 6>,
+    pub Stream<DMA, 7>,
+);
+
+macro_rules! dma {
+    ($($DMAX:ident: ($dmaXen:ident),)+) => {
+        $(
+            impl sealed::Instance for $DMAX {
+                fn ptr() -> *const RegisterBlock {
+                    $DMAX::ptr()
+                }
+            }
+
+            impl DmaExt for $DMAX {
+                type Streams = StreamsTuple<$DMAX>;
+
+                fn split(mut self, ahb1: &mut AHB1) -> StreamsTuple<$DMAX> {
+                    // reset and enable peripheral to a clean slate state
+                    self.reset(ahb1);
+                    ahb1.enr().modify(|_, w| w.$dmaXen().set_bit());
+
+                    StreamsTuple(
+                        Stream::new(),
+                        Stream::new(),
+                        Stream::new(),
+                        Stream::new(),
+                        Stream::new(),
+                        Stream::new(),
+                        Stream::new(),
+                        Stream::new(),
+                    )
+                }
+            }
+        )+
+    }
+}
+
+dma! {
+    DMA1: (dma1en),
+    DMA2: (dma2en),
+}
+
+/// Direction of a transfer
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    PeripheralToMemory,
+    MemoryToPeripheral,
+}
+
+/// Size of the items moved by a transfer, the same on both ends
+#[derive(Clone, Copy)]
+pub(crate) enum Width {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+/// Transfer between a peripheral register and memory
+pub(crate) struct Config {
+    pub channel: u8,
+    pub direction: Direction,
+    pub width: Width,
+    /// Address of the peripheral data register
+    pub peripheral: u32,
+    pub memory0: u32,
+    /// Second buffer, enables the double buffer mode
+    pub memory1: Option<u32>,
+    /// Number of items, not bytes
+    pub len: usize,
+}
+
+/// The registers and flags of a stream, erased from its type
+///
+/// Only exists while its stream is moved into a transfer.
+#[doc(hidden)]
+pub struct Handle {
+    dma: *const RegisterBlock,
+    n: usize,
+    priority: Priority,
+}
+
+// NOTE(unsafe) a handle stands in for its stream, which is a zero-sized owner of the same
+// registers
+unsafe impl Send for Handle {}
+
+impl Handle {
+    fn st(&self) -> &ST {
+        // NOTE(unsafe) the stream registers are owned by the stream this handle stands in for
+        unsafe { &(*self.dma).st[self.n] }
+    }
+
+    fn flags(&self) -> u32 {
+        // NOTE(unsafe) atomic read with no side effects
+        let dma = unsafe { &*self.dma };
+        let isr = if self.n < 4 {
+            dma.lisr.read().bits()
+        } else {
+            dma.hisr.read().bits()
+        };
+
+        (isr >> FLAG_OFFSETS[self.n % 4]) & FLAGS
+    }
+
+    fn clear_flags(&self, flags: u32) {
+        // NOTE(unsafe) write-one-to-clear of the flags of this stream only
+        let dma = unsafe { &*self.dma };
+        let flags = flags << FLAG_OFFSETS[self.n % 4];
+        if self.n < 4 {
+            dma.lifcr.write(|w| unsafe { w.bits(flags) });
+        } else {
+            dma.hifcr.write(|w| unsafe { w.bits(flags) });
+        }
+    }
+
+    /// Configures the stream and starts it
+    ///
+    /// Leaves the stream alone and returns `BufferLength` if it can't transfer `config.len`
+    /// items.
+    pub(crate) fn start(&self, config: Config) -> Result<(), Error> {
+        check_len(config.len)?;
+        assert!(config.channel < 8);
+
+        self.stop();
+
+        let st = self.st();
+        st.par.write(|w| w.pa().bits(config.peripheral));
+        st.m0ar.write(|w| w.m0a().bits(config.memory0));
+        if let Some(memory1) = config.memory1 {
+            st.m1ar.write(|w| w.m1a().bits(memory1));
+        }
+        st.ndtr.write(|w| w.ndt().bits(config.len as u16));
+        // FIFO mode, so FIFO errors are reported instead of dropped in direct mode
+        st.fcr.write(|w| w.dmdis().disabled().fth().half());
+
+        let double_buffer = config.memory1.is_some();
+        st.cr.write(|w| {
+            let w = unsafe { w.chsel().bits(config.channel) };
+            let w = match self.priority {
+                Priority::Low => w.pl().low(),
+                Priority::Medium => w.pl().medium(),
+                Priority::High => w.pl().high(),
+                Priority::VeryHigh => w.pl().very_high(),
+            };
+            let w = match config.direction {
+                Direction::PeripheralToMemory => w.dir().peripheral_to_memory(),
+                Direction::MemoryToPeripheral => w.dir().memory_to_peripheral(),
+            };
+            let w = match config.width {
+                Width::Byte => w.msize().bits8().psize().bits8(),
+                Width::HalfWord => w.msize().bits16().psize().bits16(),
+                Width::Word => w.msize().bits32().psize().bits32(),
+            };
+            w.minc()
+                .incremented()
+                .pinc()
+                .fixed()
+                .circ()
+                .bit(double_buffer)
+                .dbm()
+                .bit(double_buffer)
+                .ct()
+                .memory0()
+        });
+
+        // The buffer must be written before the stream reads it
+        compiler_fence(Ordering::Release);
+        st.cr.modify(|_, w| w.en().enabled());
+
+        Ok(())
+    }
+
+    /// Stops the stream, waiting until it's done with its current access
+    pub(crate) fn stop(&self) {
+        let st = self.st();
+        st.cr.modify(|_, w| w.en().disabled());
+        while st.cr.read().en().is_enabled() {}
+        self.clear_flags(FLAGS);
+
+        // The buffer must not be read before the stream is done writing
+        compiler_fence(Ordering::Acquire);
+    }
+
+    /// Returns the error flagged by the stream, if any
+    fn error(&self, flags: u32) -> Option<Error> {
+        if flags & TEIF != 0 {
+            Some(Error::Transfer)
+        } else if flags & FEIF != 0 {
+            Some(Error::Fifo)
+        } else {
+            None
+        }
+    }
+}
+
+/// One-shot transfer, owning the buffer and the peripheral while the stream runs
+pub struct Transfer<PERIPHERAL, BUFFER> {
+    peripheral: PERIPHERAL,
+    buffer: BUFFER,
+    stream: Handle,
+}
+
+impl<PERIPHERAL, BUFFER> Transfer<PERIPHERAL, BUFFER> {
+    pub(crate) fn new(peripheral: PERIPHERAL, buffer: BUFFER, stream: Handle) -> Self {
+        Transfer {
+            peripheral,
+            buffer,
+            stream,
+        }
+    }
+
+    /// Returns `true` once the whole buffer has been transferred
+    pub fn is_complete(&self) -> bool {
+        self.stream.flags() & TCIF != 0
+    }
+
+    /// Blocks until the transfer is complete, returning the buffer and the peripheral
+    ///
+    /// On a transfer or FIFO error the stream is stopped and they are returned with the error.
+    /// Either way the stream is disabled, with EN read back as 0 and its flags cleared, before
+    /// they are returned. The stream itself is released by the `free` of the peripheral.
+    #[allow(clippy::type_complexity)]
+    pub fn wait(self) -> Result<(BUFFER, PERIPHERAL), (Error, BUFFER, PERIPHERAL)> {
+        let result = loop {
+            let flags = self.stream.flags();
+
+            if let Some(error) = self.stream.error(flags) {
+                break Err(error);
+            }
+            if flags & TCIF != 0 {
+                break Ok(());
+            }
+        };
+
+        let (buffer, peripheral) = self.abort();
+        match result {
+            Ok(()) => Ok((buffer, peripheral)),
+            Err(error) => Err((error, buffer, peripheral)),
+        }
+    }
+
+    /// Returns the error flagged by the stream, if any
+    pub(crate) fn error(&self) -> Option<Error> {
+        self.stream.error(self.stream.flags())
+    }
+
+    /// Returns the peripheral, for drivers that have to service it during the transfer
+    pub(crate) fn peripheral(&self) -> &PERIPHERAL {
+        &self.peripheral
+    }
+
+    /// Stops the transfer, complete or not, returning the buffer and the peripheral
+    ///
+    /// The stream is disabled, with EN read back as 0, before they are returned. The buffer
+    /// then holds whatever had been transferred up to that point.
+    pub fn abort(self) -> (BUFFER, PERIPHERAL) {
+        self.stream.stop();
+
+        let this = ManuallyDrop::new(self);
+        // NOTE(unsafe) the fields are read once and `this` is never dropped
+        unsafe { (ptr::read(&this.buffer), ptr::read(&this.peripheral)) }
+    }
+}
+
+impl<PERIPHERAL, BUFFER> Drop for Transfer<PERIPHERAL, BUFFER> {
+    /// Stops the stream, so it doesn't access the buffer anymore
+    fn drop(&mut self) {
+        self.stream.stop();
+    }
+}
+
+/// One-shot transfer in both directions at once, e.g. over a full-duplex bus
+///
+/// A transmit stream sends the buffer, and a receive stream replaces every item with the one
+/// received in its place. The transfer is complete once the receive stream is, which comes
+/// after the last item was sent.
+pub struct DuplexTransfer<PERIPHERAL, BUFFER> {
+    peripheral: PERIPHERAL,
+    buffer: BUFFER,
+    rx: Handle,
+    tx: Handle,
+}
+
+impl<PERIPHERAL, BUFFER> DuplexTransfer<PERIPHERAL, BUFFER> {
+    pub(crate) fn new(peripheral: PERIPHERAL, buffer: BUFFER, rx: Handle, tx: Handle) -> Self {
+        DuplexTransfer {
+            peripheral,
+            buffer,
+            rx,
+            tx,
+        }
+    }
+
+    /// Returns `true` once the whole buffer has been sent and received
+    pub fn is_complete(&self) -> bool {
+        self.rx.flags() & TCIF != 0
+    }
+
+    /// Blocks until the transfer is complete, returning the buffer and the peripheral
+    ///
+    /// On a transfer or FIFO error of either stream, both are stopped and they are returned
+    /// with the error. See [`Transfer::wait`].
+    #[allow(clippy::type_complexity)]
+    pub fn wait(self) -> Result<(BUFFER, PERIPHERAL), (Error, BUFFER, PERIPHERAL)> {
+        let result = loop {
+            let flags = self.rx.flags();
+            let tx_flags = self.tx.flags();
+            let error = self.rx.error(flags).or(self.tx.error(tx_flags));
+
+            if let Some(error) = error {
+                break Err(error);
+            }
+            if flags & TCIF != 0 {
+                break Ok(());
+            }
+        };
+
+        let (buffer, peripheral) = self.abort();
+        match result {
+            Ok(()) => Ok((buffer, peripheral)),
+            Err(error) => Err((error, buffer, peripheral)),
+        }
+    }
+
+    /// Stops the transfer, complete or not, returning the buffer and the peripheral
+    ///
+    /// See [`Transfer::abort`].
+    pub fn abort(self) -> (BUFFER, PERIPHERAL) {
+        self.tx.stop();
+        self.rx.stop();
+
+        let this = ManuallyDrop::new(self);
+        // NOTE(unsafe) the fields are read once and `this` is never dropped
+        unsafe { (ptr::read(&this.buffer), ptr::read(&this.peripheral)) }
+    }
+}
+
+impl<PERIPHERAL, BUFFER> Drop for DuplexTransfer<PERIPHERAL, BUFFER> {
+    /// Stops both streams, so they don't access the buffer anymore
+    fn drop(&mut self) {
+        self.tx.stop();
+        self.rx.stop();
+    }
+}
+
+/// Continuous transfer into two buffers in turn, using the double buffer mode
+///
+/// While the stream fills one buffer, the other one can be read with [`DoubleBuffer::read`].
+pub struct DoubleBuffer<PERIPHERAL> {
+    peripheral: PERIPHERAL,
+    buffers: [&'static mut [u8]; 2],
+    stream: Handle,
+}
+
+impl<PERIPHERAL> DoubleBuffer<PERIPHERAL> {
+    pub(crate) fn new(
+        peripheral: PERIPHERAL,
+        buffers: [&'static mut [u8]; 2],
+        stream: Handle,
+    ) -> Self {
+        DoubleBuffer {
+            peripheral,
+            buffers,
+            stream,
+        }
+    }
+
+    /// Passes the buffer the stream has just completed to `f`
+    ///
+    /// Returns `WouldBlock` until a buffer has been completed since the last call. `f` has to
+    /// return before the stream completes the other buffer, otherwise the data it got may have
+    /// been overwritten in the meantime and `Overrun` is returned instead.
+    pub fn read<F, R>(&mut self, f: F) -> nb::Result<R, Error>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let flags = self.stream.flags();
+
+        if let Some(error) = self.stream.error(flags) {
+            self.stream.clear_flags(FEIF | TEIF);
+            return Err(nb::Error::Other(error));
+        }
+        if flags & TCIF == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.stream.clear_flags(TCIF);
+        compiler_fence(Ordering::Acquire);
+
+        // The stream has moved on to the other buffer
+        let completed = if self.stream.st().cr.read().ct().is_memory1() {
+            0
+        } else {
+            1
+        };
+        let result = f(self.buffers[completed]);
+
+        compiler_fence(Ordering::SeqCst);
+        if self.stream.flags() & TCIF != 0 {
+            return Err(nb::Error::Other(Error::Overrun));
+        }
+
+        Ok(result)
+    }
+
+    /// Stops the stream, returning the buffers and the peripheral
+    pub fn stop(self) -> ([&'static mut [u8]; 2], PERIPHERAL) {
+        self.stream.stop();
+
+        let this = ManuallyDrop::new(self);
+        // NOTE(unsafe) the fields are read once and `this` is never dropped
+        unsafe { (ptr::read(&this.buffers), ptr::read(&this.peripheral)) }
+    }
+}
+
+impl<PERIPHERAL> Drop for DoubleBuffer<PERIPHERAL> {
+    /// Stops the stream, so it doesn't access the buffers anymore
+    fn drop(&mut self) {
+        self.stream.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_len_fits_ndtr() {
+        assert_eq!(check_len(1), Ok(()));
+        assert_eq!(check_len(0xffff), Ok(()));
+        assert_eq!(check_len(0), Err(Error::BufferLength));
+        assert_eq!(check_len(0x1_0000), Err(Error::BufferLength));
+    }
+}
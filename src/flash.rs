@@ -0,0 +1,548 @@
+//! Flash memory
+//!
+//! Sectors can only be erased and programmed once they have been allowed with
+//! [`Flash::set_writable_sectors`], so a stray address can't wipe out the running program.
+//! Sector numbers follow the single bank layout (nDBANK set, as shipped from the factory).
+//!
+//! The main memory is mapped at [`FLASH_START`], so reading it back needs no unlocking:
+//! [`Flash::read`] copies a range out and [`Flash::region`] borrows it as a slice.
+//!
+//! # `embedded-storage`
+//!
+//! With the `embedded-storage` feature, `Flash` implements `ReadNorFlash` and `NorFlash`,
+//! addressed by offsets from [`FLASH_START`]. The sectors aren't all the same size, so
+//! `ERASE_SIZE` is the smallest one, 32 KiB, and an erase has to start and end on the
+//! boundaries of the actual sectors: erasing 32 KiB inside a 256 KiB sector fails with
+//! `NotAligned` instead of wiping the rest of it. Like [`Flash::erase_sector`], the trait
+//! methods need the control register to be unlocked and the sectors to be writable.
+
+use core::ops::RangeInclusive;
+use core::ptr;
+
+#[cfg(feature = "embedded-storage")]
+use core::ops::Range;
+#[cfg(feature = "embedded-storage")]
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::device::FLASH;
+use crate::signature::FlashSize;
+
+/// Start address of the main memory on the AXIM interface
+pub const FLASH_START: u32 = 0x0800_0000;
+
+/// Size of the main memory of the largest parts of the line, in bytes
+///
+/// Parts with less memory only map the beginning of it, [`FlashSize`] holds their actual size.
+#[cfg(feature = "stm32f746")]
+pub const FLASH_SIZE: usize = 1024 * 1024;
+#[cfg(feature = "stm32f767")]
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Number of sectors in single bank mode
+#[cfg(feature = "stm32f746")]
+const SECTORS: u8 = 8;
+#[cfg(feature = "stm32f767")]
+const SECTORS: u8 = 12;
+
+/// First key of the control register unlock sequence
+const KEY1: u32 = 0x4567_0123;
+/// Second key of the control register unlock sequence
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// First key of the option byte unlock sequence
+const OPT_KEY1: u32 = 0x0819_2A3B;
+/// Second key of the option byte unlock sequence
+const OPT_KEY2: u32 = 0x4C5D_6E7F;
+
+/// Extension trait to constrain the FLASH peripheral
+pub trait FlashExt {
+    /// Constrains the FLASH peripheral to play nicely with the other abstractions
+    fn constrain(self) -> Flash;
+}
+
+impl FlashExt for FLASH {
+    fn constrain(self) -> Flash {
+        Flash {
+            flash: self,
+            writable: None,
+        }
+    }
+}
+
+/// Constrained FLASH peripheral
+pub struct Flash {
+    flash: FLASH,
+    writable: Option<RangeInclusive<u8>>,
+}
+
+/// Flash erase / program error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The sector doesn't exist
+    InvalidSector,
+    /// The sector hasn't been allowed with `set_writable_sectors`
+    SectorNotWritable,
+    /// The address range doesn't lie in the main memory
+    OutOfRange,
+    /// The target isn't erased
+    NotErased,
+    /// The control register is locked
+    Locked,
+    /// The sector is write protected by the option bytes
+    WriteProtection,
+    /// The programming sequence was wrong
+    Sequence,
+    /// The access was misaligned for the parallelism
+    Alignment,
+    /// The erased range doesn't start and end on sector boundaries
+    UnalignedErase,
+    /// The operation failed
+    Operation,
+}
+
+/// Brown-out reset threshold, stored in the option bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BorLevel {
+    /// BOR off, only POR/PDR remain active (reset threshold around 1.7 V)
+    Off,
+    /// BOR level 1 (reset threshold around 2.1 V)
+    Level1,
+    /// BOR level 2 (reset threshold around 2.4 V)
+    Level2,
+    /// BOR level 3 (reset threshold around 2.7 V)
+    Level3,
+}
+
+impl BorLevel {
+    fn bits(self) -> u8 {
+        match self {
+            BorLevel::Level3 => 0b00,
+            BorLevel::Level2 => 0b01,
+            BorLevel::Level1 => 0b10,
+            BorLevel::Off => 0b11,
+        }
+    }
+}
+
+impl Flash {
+    /// Allows erasing and programming `sectors`, forbidding all others
+    ///
+    /// Nothing is writable by default. Keep the sectors holding the running program out of
+    /// this range.
+    pub fn set_writable_sectors(&mut self, sectors: RangeInclusive<u8>) {
+        self.writable = Some(sectors);
+    }
+
+    /// Unlocks the control register, allowing erase and program operations
+    pub fn unlock(&mut self) {
+        // Writing the keys while unlocked is a sequence error that locks CR until reset
+        if self.flash.cr.read().lock().bit_is_set() {
+            self.flash.keyr.write(|w| w.key().bits(KEY1));
+            self.flash.keyr.write(|w| w.key().bits(KEY2));
+        }
+    }
+
+    /// Locks the control register again
+    pub fn lock(&mut self) {
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    /// Erases `sector`, blocking until the flash is done
+    ///
+    /// The control register has to be unlocked.
+    pub fn erase_sector(&mut self, sector: u8) -> Result<(), Error> {
+        if sector >= SECTORS {
+            return Err(Error::InvalidSector);
+        }
+        if !self.is_writable(sector) {
+            return Err(Error::SectorNotWritable);
+        }
+        if self.flash.cr.read().lock().bit_is_set() {
+            return Err(Error::Locked);
+        }
+
+        self.wait_ready();
+        self.clear_errors();
+
+        // x8 parallelism works over the whole supply voltage range
+        self.flash
+            .cr
+            .modify(|_, w| unsafe { w.psize().psize8().ser().set_bit().snb().bits(sector) });
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        self.wait_ready();
+        self.flash.cr.modify(|_, w| w.ser().clear_bit());
+
+        self.check_errors()
+    }
+
+    /// Programs `data` at `address`, blocking until the flash is done
+    ///
+    /// The whole target range must lie in writable sectors and be erased. It is programmed
+    /// byte by byte, so `address` needs no alignment. With the data cache enabled, stale data
+    /// may be read back until the cache is invalidated. The control register has to be
+    /// unlocked.
+    pub fn program(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let end = address
+            .checked_add(data.len() as u32 - 1)
+            .ok_or(Error::OutOfRange)?;
+        let first = sector(address).ok_or(Error::OutOfRange)?;
+        let last = sector(end).ok_or(Error::OutOfRange)?;
+        if !self.is_writable(first) || !self.is_writable(last) {
+            return Err(Error::SectorNotWritable);
+        }
+        if self.flash.cr.read().lock().bit_is_set() {
+            return Err(Error::Locked);
+        }
+
+        // NOTE(unsafe) reads of main memory, which is always mapped
+        let erased = (address..=end).all(|a| unsafe { ptr::read_volatile(a as *const u8) } == 0xff);
+        if !erased {
+            return Err(Error::NotErased);
+        }
+
+        self.wait_ready();
+        self.clear_errors();
+        self.flash
+            .cr
+            .modify(|_, w| w.psize().psize8().pg().set_bit());
+
+        let mut result = Ok(());
+        for (offset, byte) in data.iter().enumerate() {
+            // NOTE(unsafe) the target lies in an erased and writable sector
+            unsafe { ptr::write_volatile((address + offset as u32) as *mut u8, *byte) };
+            self.wait_ready();
+
+            result = self.check_errors();
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+
+        result
+    }
+
+    /// Copies the main memory starting at `address` into `buffer`
+    ///
+    /// The whole range must lie in the main memory of the part. Bytes need no alignment.
+    pub fn read(&self, address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        buffer.copy_from_slice(self.region(address, buffer.len())?);
+        Ok(())
+    }
+
+    /// Returns the `len` bytes of main memory starting at `address` as a slice
+    ///
+    /// The whole range must lie in the main memory of the part. The slice borrows the flash,
+    /// so the range can't be erased or programmed while it is in use.
+    pub fn region(&self, address: u32, len: usize) -> Result<&[u8], Error> {
+        check_range(address, len, FlashSize::get().bytes())?;
+
+        // NOTE(unsafe) the range lies in main memory, which is always mapped and only changes
+        // through `&mut self`
+        Ok(unsafe { core::slice::from_raw_parts(address as *const u8, len) })
+    }
+
+    /// Returns the brown-out reset level programmed in the option bytes
+    pub fn bor_level(&self) -> BorLevel {
+        match self.flash.optcr.read().bor_lev().bits() {
+            0b00 => BorLevel::Level3,
+            0b01 => BorLevel::Level2,
+            0b10 => BorLevel::Level1,
+            _ => BorLevel::Off,
+        }
+    }
+
+    /// Programs the brown-out reset level into the option bytes
+    ///
+    /// This unlocks the option bytes, reprograms them and locks them again, blocking until the
+    /// flash is done. The option bytes are only reloaded on reset, so the new threshold takes
+    /// effect after the next system reset; this function doesn't reset the device itself.
+    /// Nothing is written if `level` is already programmed, to spare the option bytes.
+    ///
+    /// Errors raised while programming are returned and their flags cleared, `Error::Locked`
+    /// if OPTCR stays locked after a wrong unlock sequence since the last reset.
+    pub fn set_bor_level(&mut self, level: BorLevel) -> Result<(), Error> {
+        if self.bor_level() == level {
+            return Ok(());
+        }
+
+        self.unlock_option_bytes();
+        if self.flash.optcr.read().optlock().bit_is_set() {
+            return Err(Error::Locked);
+        }
+        self.wait_ready();
+        // Flags left by earlier operations would be taken for errors of this one
+        self.clear_errors();
+
+        self.flash
+            .optcr
+            .modify(|_, w| unsafe { w.bor_lev().bits(level.bits()) });
+        self.flash.optcr.modify(|_, w| w.optstrt().set_bit());
+        self.wait_ready();
+        let result = self.check_errors();
+
+        self.flash.optcr.modify(|_, w| w.optlock().set_bit());
+
+        result
+    }
+
+    /// Releases the FLASH peripheral
+    pub fn free(self) -> FLASH {
+        self.flash
+    }
+
+    fn unlock_option_bytes(&mut self) {
+        // Writing the keys while unlocked is a sequence error that locks OPTCR until reset
+        if self.flash.optcr.read().optlock().bit_is_set() {
+            self.flash.optkeyr.write(|w| w.optkeyr().bits(OPT_KEY1));
+            self.flash.optkeyr.write(|w| w.optkeyr().bits(OPT_KEY2));
+        }
+    }
+
+    fn wait_ready(&self) {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+    }
+
+    fn is_writable(&self, sector: u8) -> bool {
+        self.writable
+            .as_ref()
+            .is_some_and(|sectors| sectors.contains(&sector))
+    }
+
+    fn clear_errors(&mut self) {
+        // The flags are cleared by writing 1
+        self.flash.sr.write(|w| {
+            w.eop()
+                .set_bit()
+                .operr()
+                .set_bit()
+                .wrperr()
+                .set_bit()
+                .pgaerr()
+                .set_bit()
+                .pgperr()
+                .set_bit()
+                .erserr()
+                .set_bit()
+        });
+    }
+
+    fn check_errors(&mut self) -> Result<(), Error> {
+        let sr = self.flash.sr.read();
+
+        let result = if sr.wrperr().bit_is_set() {
+            Err(Error::WriteProtection)
+        } else if sr.pgaerr().bit_is_set() {
+            Err(Error::Alignment)
+        } else if sr.pgperr().bit_is_set() || sr.erserr().bit_is_set() {
+            Err(Error::Sequence)
+        } else if sr.operr().bit_is_set() {
+            Err(Error::Operation)
+        } else {
+            Ok(())
+        };
+
+        if result.is_err() {
+            self.clear_errors();
+        }
+
+        result
+    }
+}
+
+/// Checks that the `len` bytes starting at `address` lie in a main memory of `size` bytes
+fn check_range(address: u32, len: usize, size: usize) -> Result<(), Error> {
+    let offset = address.checked_sub(FLASH_START).ok_or(Error::OutOfRange)? as usize;
+    let end = offset.checked_add(len).ok_or(Error::OutOfRange)?;
+
+    if end <= size.min(FLASH_SIZE) {
+        Ok(())
+    } else {
+        Err(Error::OutOfRange)
+    }
+}
+
+/// Returns the offset from `FLASH_START` of the first byte of `sector`
+///
+/// `SECTORS` gives the end of the main memory.
+#[cfg(feature = "embedded-storage")]
+fn sector_start(sector: u8) -> u32 {
+    match sector {
+        0..=4 => u32::from(sector) * 0x8000,
+        _ => 0x4_0000 * u32::from(sector - 4),
+    }
+}
+
+/// Returns the sectors covering `from..to`, offsets from `FLASH_START` in a main memory of
+/// `size` bytes
+#[cfg(feature = "embedded-storage")]
+fn erase_sectors(from: u32, to: u32, size: usize) -> Result<Range<u8>, Error> {
+    if from > to || to as usize > size.min(FLASH_SIZE) {
+        return Err(Error::OutOfRange);
+    }
+
+    let boundary = |offset| {
+        (0..=SECTORS)
+            .find(|&sector| sector_start(sector) == offset)
+            .ok_or(Error::UnalignedErase)
+    };
+
+    Ok(boundary(from)?..boundary(to)?)
+}
+
+/// Returns the sector holding `address`
+fn sector(address: u32) -> Option<u8> {
+    let offset = address.checked_sub(FLASH_START)?;
+
+    // 4 sectors of 32 KiB, 1 of 128 KiB, then sectors of 256 KiB
+    let sector = match offset {
+        0..=0x1_ffff => offset / 0x8000,
+        0x2_0000..=0x3_ffff => 4,
+        _ => 5 + (offset - 0x4_0000) / 0x4_0000,
+    } as u8;
+
+    if sector < SECTORS {
+        Some(sector)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::InvalidSector | Error::OutOfRange => NorFlashErrorKind::OutOfBounds,
+            Error::Alignment | Error::UnalignedErase => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl ErrorType for Flash {
+    type Error = Error;
+}
+
+#[cfg(feature = "embedded-storage")]
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        let address = FLASH_START.checked_add(offset).ok_or(Error::OutOfRange)?;
+        Flash::read(self, address, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        FlashSize::get().bytes().min(FLASH_SIZE)
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl NorFlash for Flash {
+    // Bytes are programmed one by one
+    const WRITE_SIZE: usize = 1;
+    // The smallest sector, see the module documentation for the larger ones
+    const ERASE_SIZE: usize = 0x8000;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        for sector in erase_sectors(from, to, self.capacity())? {
+            self.erase_sector(sector)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        let address = FLASH_START.checked_add(offset).ok_or(Error::OutOfRange)?;
+        self.program(address, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 512 * 1024;
+
+    #[cfg(feature = "embedded-storage")]
+    #[test]
+    fn sectors_start_after_each_other() {
+        assert_eq!(sector_start(0), 0);
+        assert_eq!(sector_start(3), 0x1_8000);
+        assert_eq!(sector_start(4), 0x2_0000);
+        assert_eq!(sector_start(5), 0x4_0000);
+        assert_eq!(sector_start(SECTORS), FLASH_SIZE as u32);
+
+        for number in 0..SECTORS {
+            assert_eq!(sector(FLASH_START + sector_start(number)), Some(number));
+        }
+    }
+
+    #[cfg(feature = "embedded-storage")]
+    #[test]
+    fn erase_covers_whole_sectors() {
+        assert_eq!(erase_sectors(0, 0x8000, SIZE), Ok(0..1));
+        assert_eq!(erase_sectors(0x1_8000, 0x8_0000, SIZE), Ok(3..6));
+        assert_eq!(erase_sectors(0x4_0000, 0x4_0000, SIZE), Ok(5..5));
+    }
+
+    #[cfg(feature = "embedded-storage")]
+    #[test]
+    fn erase_rejects_partial_sectors() {
+        // The smallest erase size, but inside the 128 KiB sector 4
+        assert_eq!(
+            erase_sectors(0x2_0000, 0x2_8000, SIZE),
+            Err(Error::UnalignedErase)
+        );
+        assert_eq!(
+            erase_sectors(0x100, 0x8000, SIZE),
+            Err(Error::UnalignedErase)
+        );
+    }
+
+    #[cfg(feature = "embedded-storage")]
+    #[test]
+    fn erase_rejects_ranges_outside_main_memory() {
+        assert_eq!(
+            erase_sectors(0, SIZE as u32 + 0x4_0000, SIZE),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(erase_sectors(0x8000, 0, SIZE), Err(Error::OutOfRange));
+    }
+
+    #[test]
+    fn range_inside_main_memory() {
+        assert_eq!(check_range(FLASH_START, 0, SIZE), Ok(()));
+        assert_eq!(check_range(FLASH_START, SIZE, SIZE), Ok(()));
+        assert_eq!(check_range(FLASH_START + SIZE as u32 - 4, 4, SIZE), Ok(()));
+    }
+
+    #[test]
+    fn range_outside_main_memory() {
+        assert_eq!(
+            check_range(FLASH_START - 1, 1, SIZE),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(
+            check_range(FLASH_START + SIZE as u32 - 4, 5, SIZE),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(
+            check_range(FLASH_START, usize::MAX, SIZE),
+            Err(Error::OutOfRange)
+        );
+        // A size register reporting more than the line has is not trusted
+        assert_eq!(
+            check_range(FLASH_START, FLASH_SIZE + 1, usize::MAX),
+            Err(Error::OutOfRange)
+        );
+    }
+}
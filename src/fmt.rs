@@ -0,0 +1,106 @@
+//! Formatting helpers
+
+use core::fmt::{self, Write};
+
+/// Writer adapter that translates `\n` into `\r\n`
+///
+/// Wraps any `fmt::Write` implementation (e.g. a serial `Tx`) for terminals that expect a
+/// carriage return before each line feed. Other bytes pass through unchanged, so a `\r\n` that
+/// is already present becomes `\r\r\n`.
+pub struct CrlfWriter<W> {
+    inner: W,
+}
+
+impl<W> CrlfWriter<W> {
+    /// Wraps `inner`
+    pub fn new(inner: W) -> Self {
+        CrlfWriter { inner }
+    }
+
+    /// Releases the wrapped writer
+    pub fn free(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> Write for CrlfWriter<W>
+where
+    W: Write,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+
+        if let Some(line) = lines.next() {
+            self.inner.write_str(line)?;
+        }
+        for line in lines {
+            self.inner.write_str("\r\n")?;
+            self.inner.write_str(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects the written bytes
+    struct Buffer {
+        bytes: [u8; 32],
+        len: usize,
+    }
+
+    impl Buffer {
+        fn new() -> Self {
+            Buffer {
+                bytes: [0; 32],
+                len: 0,
+            }
+        }
+
+        fn as_bytes(&self) -> &[u8] {
+            &self.bytes[..self.len]
+        }
+    }
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes
+                .get_mut(self.len..end)
+                .ok_or(fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn newlines_become_crlf() {
+        let mut writer = CrlfWriter::new(Buffer::new());
+        write!(writer, "a\nb\n\n{}\n", 42).unwrap();
+
+        assert_eq!(writer.free().as_bytes(), b"a\r\nb\r\n\r\n42\r\n");
+    }
+
+    #[test]
+    fn text_without_newlines_passes_through() {
+        let mut writer = CrlfWriter::new(Buffer::new());
+        writer.write_str("no\rnewline").unwrap();
+
+        assert_eq!(writer.free().as_bytes(), b"no\rnewline");
+    }
+
+    #[test]
+    fn errors_of_the_inner_writer_are_returned() {
+        let mut writer = CrlfWriter::new(Buffer::new());
+
+        assert_eq!(writer.write_str("\n"), Ok(()));
+        assert_eq!(
+            writer.write_str("0123456789012345678901234567890"),
+            Err(fmt::Error)
+        );
+    }
+}
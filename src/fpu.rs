@@ -0,0 +1,44 @@
+//! Floating Point Unit
+//!
+//! The FPU is disabled after reset, and executing a floating point instruction while it is
+//! disabled raises a UsageFault, which ends up as a HardFault. `cortex-m-rt` enables the FPU
+//! before `main` when building for `thumbv7em-none-eabihf`, but custom startup code,
+//! bootloaders and `thumbv7em-none-eabi` builds with FPU target features enabled have to do it
+//! themselves.
+
+use core::ptr;
+
+use cortex_m::asm;
+use cortex_m::peripheral::SCB;
+
+/// Full access to the coprocessors CP10 and CP11, which make up the FPU
+const CPACR_FPU_FULL_ACCESS: u32 = 0b1111 << 20;
+
+/// Media and VFP Feature Register 0
+const MVFR0: *const u32 = 0xE000_EF40 as *const u32;
+
+/// Enables the FPU for privileged and unprivileged code
+///
+/// This covers both single and double precision operations, there is nothing else to enable.
+/// The calling function must not use floating point values itself, since the compiler may
+/// place FPU instructions before the call; mark it `#[inline(never)]` if in doubt.
+pub fn enable(scb: &mut SCB) {
+    unsafe { scb.cpacr.modify(|r| r | CPACR_FPU_FULL_ACCESS) };
+    // Make sure the new access rights apply to the very next instruction
+    asm::dsb();
+    asm::isb();
+}
+
+/// Returns `true` if the FPU is enabled
+pub fn is_enabled(scb: &SCB) -> bool {
+    scb.cpacr.read() & CPACR_FPU_FULL_ACCESS == CPACR_FPU_FULL_ACCESS
+}
+
+/// Returns `true` if the FPU supports double precision operations
+///
+/// This is the case for the STM32F76x/77x; the other F7 devices only have a single precision FPU,
+/// so `f64` arithmetic is done in software there.
+pub fn has_double_precision() -> bool {
+    // NOTE(unsafe) atomic read of a read-only register with no side effects
+    unsafe { (ptr::read_volatile(MVFR0) >> 8) & 0xf != 0 }
+}
@@ -47,6 +47,9 @@ pub struct PullDown;
 /// Pulled up input (type state)
 pub struct PullUp;
 
+/// Analog mode (type state)
+pub struct Analog;
+
 /// Open drain input or output (type state)
 pub struct OpenDrain;
 
@@ -58,6 +61,79 @@ pub struct Output<MODE> {
 /// Push pull output (type state)
 pub struct PushPull;
 
+/// GPIO output speed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Low = 0b00,
+    Medium = 0b01,
+    High = 0b10,
+    VeryHigh = 0b11,
+}
+
+/// Internal pull-up / pull-down resistor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pull {
+    None = 0b00,
+    Up = 0b01,
+    Down = 0b10,
+}
+
+/// Output settings of a pin applied before it connects to its alternate function
+///
+/// See `into_alternate_afX_with`. Defaults to the reset state: low speed, no pull, push pull.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AltConfig {
+    pub speed: Speed,
+    pub pull: Pull,
+    /// Open drain instead of push pull, e.g. for I2C
+    pub open_drain: bool,
+}
+
+impl Default for AltConfig {
+    fn default() -> Self {
+        AltConfig {
+            speed: Speed::Low,
+            pull: Pull::None,
+            open_drain: false,
+        }
+    }
+}
+
+/// A group of output pins of one port that can be set by writing its BSRR register
+///
+/// Lets a DMA stream drive the pins, see [`port_dma`](crate::port_dma).
+pub trait BsrrPort {
+    /// Returns the address of the BSRR register of the port
+    #[doc(hidden)]
+    fn bsrr_address() -> u32;
+
+    /// Returns the BSRR word that sets the pins of the group to the port layout `value`
+    ///
+    /// Pins outside of the group are left out of the word, writing it leaves them untouched.
+    fn bsrr_word(&self, value: u16) -> u32;
+}
+
+/// Returns the BSRR word setting the pins in `mask` to `value`, both in the port layout
+fn bsrr_word(mask: u16, value: u16) -> u32 {
+    let set = u32::from(value & mask);
+    let reset = u32::from(!value & mask);
+    set | (reset << 16)
+}
+
+/// Spreads the packed `value` over the port layout, bit `k` going to pin `pins[k]`
+fn unpack(pins: impl Iterator<Item = u8>, value: u16) -> u16 {
+    pins.enumerate()
+        .filter(|&(k, _)| value & (1 << k) != 0)
+        .fold(0, |port, (_, i)| port | (1 << i))
+}
+
+/// Packs the pins `pins` of the port layout `port`, pin `pins[k]` going to bit `k`
+fn pack(pins: impl Iterator<Item = u8>, port: u16) -> u16 {
+    pins.enumerate()
+        .filter(|&(_, i)| port & (1 << i) != 0)
+        .fold(0, |value, (k, _)| value | (1 << k))
+}
+
 macro_rules! gpio {
     ($GPIOX:ident, $gpiox:ident, $iopxenr:ident, $PXx:ident, [
         $($PXi:ident: ($pxi:ident, $i:expr, $MODE:ty),)+
@@ -68,11 +144,11 @@ macro_rules! gpio {
 
             use void::Void;
 
-            use crate::hal::digital::v2::{InputPin, OutputPin};
+            use crate::hal::digital::v2::{InputPin, OutputPin, PinState};
             use crate::device::{RCC, $GPIOX};
 
             use super::{
-                Alternate, Floating, GpioExt, Input, OpenDrain, Output,
+                bsrr_word, pack, unpack, AltConfig, Alternate, Analog, BsrrPort, Floating, GpioExt, Input, OpenDrain, Output, Pull, Speed,
                 PullDown, PullUp, PushPull, AF0, AF1, AF2, AF3, AF4, AF5, AF6, AF7, AF8, AF9, AF10,
                 AF11, AF12, AF13, AF14, AF15
             };
@@ -137,25 +213,68 @@ macro_rules! gpio {
                 }
             }
 
-            fn _set_alternate_mode (index:usize, mode: u32)
+            fn _set_alternate_mode (index:usize, mode: u32, config: Option<AltConfig>)
             {
                 let offset = 2 * index;
                 let offset2 = 4 * index;
+                // The output settings come first as well, so the pin doesn't drive the line
+                // from a stale or reset configuration once it's connected
+                if let Some(config) = config {
+                    unsafe {
+                        (*$GPIOX::ptr()).otyper.modify(|r, w| {
+                            w.bits((r.bits() & !(0b1 << index)) | (u32::from(config.open_drain) << index))
+                        });
+                    }
+                    _set_speed(index, config.speed);
+                    _set_pull(index, config.pull);
+                }
                 unsafe {
-                    &(*$GPIOX::ptr()).moder.modify(|r, w| {
-                        w.bits((r.bits() & !(0b11 << offset)) | (0b10 << offset))
-                    });
+                    // Select the alternate function before switching the mode, so the pin never
+                    // connects to the wrong peripheral
                     if offset2 < 32 {
-                        &(*$GPIOX::ptr()).afrl.modify(|r, w| {
+                        (*$GPIOX::ptr()).afrl.modify(|r, w| {
                             w.bits((r.bits() & !(0b1111 << offset2)) | (mode << offset2))
                         });
                     } else
                     {
                         let offset2 = offset2 - 32;
-                        &(*$GPIOX::ptr()).afrh.modify(|r, w| {
+                        (*$GPIOX::ptr()).afrh.modify(|r, w| {
                             w.bits((r.bits() & !(0b1111 << offset2)) | (mode << offset2))
                         });
                     }
+                    (*$GPIOX::ptr()).moder.modify(|r, w| {
+                        w.bits((r.bits() & !(0b11 << offset)) | (0b10 << offset))
+                    });
+                }
+            }
+
+            fn _set_mode(index: usize, mode: u32, pull: Pull) {
+                let offset = 2 * index;
+                // The pull is set first, so the pin doesn't float or get pulled the wrong way
+                // once it is in the new mode
+                _set_pull(index, pull);
+                unsafe {
+                    (*$GPIOX::ptr()).moder.modify(|r, w| {
+                        w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
+                    });
+                }
+            }
+
+            fn _set_speed(index: usize, speed: Speed) {
+                let offset = 2 * index;
+                unsafe {
+                    (*$GPIOX::ptr()).ospeedr.modify(|r, w| {
+                        w.bits((r.bits() & !(0b11 << offset)) | ((speed as u32) << offset))
+                    });
+                }
+            }
+
+            fn _set_pull(index: usize, pull: Pull) {
+                let offset = 2 * index;
+                unsafe {
+                    (*$GPIOX::ptr()).pupdr.modify(|r, w| {
+                        w.bits((r.bits() & !(0b11 << offset)) | ((pull as u32) << offset))
+                    });
                 }
             }
 
@@ -170,7 +289,21 @@ macro_rules! gpio {
                     pub fn into_alternate_af0(
                         self,
                     ) -> $PXi<Alternate<AF0>> {
-                        _set_alternate_mode($i, 0);
+                        _set_alternate_mode($i, 0, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF0 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// The output type, speed and pull of `config` are applied before the pin is
+                    /// switched to the alternate function, so it never drives the line with
+                    /// other settings in between.
+                    pub fn into_alternate_af0_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF0>> {
+                        _set_alternate_mode($i, 0, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -178,7 +311,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af1(
                         self,
                     ) -> $PXi<Alternate<AF1>> {
-                        _set_alternate_mode($i, 1);
+                        _set_alternate_mode($i, 1, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF1 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af1_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF1>> {
+                        _set_alternate_mode($i, 1, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -186,7 +331,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af2(
                         self,
                     ) -> $PXi<Alternate<AF2>> {
-                        _set_alternate_mode($i, 2);
+                        _set_alternate_mode($i, 2, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF2 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af2_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF2>> {
+                        _set_alternate_mode($i, 2, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -194,7 +351,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af3(
                         self,
                     ) -> $PXi<Alternate<AF3>> {
-                        _set_alternate_mode($i, 3);
+                        _set_alternate_mode($i, 3, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF3 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af3_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF3>> {
+                        _set_alternate_mode($i, 3, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -202,7 +371,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af4(
                         self,
                     ) -> $PXi<Alternate<AF4>> {
-                        _set_alternate_mode($i, 4);
+                        _set_alternate_mode($i, 4, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF4 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af4_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF4>> {
+                        _set_alternate_mode($i, 4, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -210,7 +391,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af5(
                         self,
                     ) -> $PXi<Alternate<AF5>> {
-                        _set_alternate_mode($i, 5);
+                        _set_alternate_mode($i, 5, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF5 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af5_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF5>> {
+                        _set_alternate_mode($i, 5, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -218,7 +411,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af6(
                         self,
                     ) -> $PXi<Alternate<AF6>> {
-                        _set_alternate_mode($i, 6);
+                        _set_alternate_mode($i, 6, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF6 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af6_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF6>> {
+                        _set_alternate_mode($i, 6, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -226,7 +431,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af7(
                         self,
                     ) -> $PXi<Alternate<AF7>> {
-                        _set_alternate_mode($i, 7);
+                        _set_alternate_mode($i, 7, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF7 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af7_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF7>> {
+                        _set_alternate_mode($i, 7, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -234,7 +451,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af8(
                         self,
                     ) -> $PXi<Alternate<AF8>> {
-                        _set_alternate_mode($i, 8);
+                        _set_alternate_mode($i, 8, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF8 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af8_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF8>> {
+                        _set_alternate_mode($i, 8, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -242,7 +471,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af9(
                         self,
                     ) -> $PXi<Alternate<AF9>> {
-                        _set_alternate_mode($i, 9);
+                        _set_alternate_mode($i, 9, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF9 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af9_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF9>> {
+                        _set_alternate_mode($i, 9, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -250,7 +491,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af10(
                         self,
                     ) -> $PXi<Alternate<AF10>> {
-                        _set_alternate_mode($i, 10);
+                        _set_alternate_mode($i, 10, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF10 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af10_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF10>> {
+                        _set_alternate_mode($i, 10, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -258,7 +511,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af11(
                         self,
                     ) -> $PXi<Alternate<AF11>> {
-                        _set_alternate_mode($i, 11);
+                        _set_alternate_mode($i, 11, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF11 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af11_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF11>> {
+                        _set_alternate_mode($i, 11, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -266,7 +531,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af12(
                         self,
                     ) -> $PXi<Alternate<AF12>> {
-                        _set_alternate_mode($i, 12);
+                        _set_alternate_mode($i, 12, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF12 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af12_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF12>> {
+                        _set_alternate_mode($i, 12, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -274,7 +551,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af13(
                         self,
                     ) -> $PXi<Alternate<AF13>> {
-                        _set_alternate_mode($i, 13);
+                        _set_alternate_mode($i, 13, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF13 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af13_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF13>> {
+                        _set_alternate_mode($i, 13, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -282,7 +571,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af14(
                         self,
                     ) -> $PXi<Alternate<AF14>> {
-                        _set_alternate_mode($i, 14);
+                        _set_alternate_mode($i, 14, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF14 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af14_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF14>> {
+                        _set_alternate_mode($i, 14, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -290,7 +591,19 @@ macro_rules! gpio {
                     pub fn into_alternate_af15(
                         self,
                     ) -> $PXi<Alternate<AF15>> {
-                        _set_alternate_mode($i, 15);
+                        _set_alternate_mode($i, 15, None);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate in AF15 mode with the output settings of
+                    /// `config`
+                    ///
+                    /// See [`into_alternate_af0_with`](Self::into_alternate_af0_with).
+                    pub fn into_alternate_af15_with(
+                        self,
+                        config: AltConfig,
+                    ) -> $PXi<Alternate<AF15>> {
+                        _set_alternate_mode($i, 15, Some(config));
                         $PXi { _mode: PhantomData }
                     }
 
@@ -298,29 +611,15 @@ macro_rules! gpio {
                     pub fn into_floating_input(
                         self,
                     ) -> $PXi<Input<Floating>> {
-                        let offset = 2 * $i;
-                        unsafe {
-                            &(*$GPIOX::ptr()).moder.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b00 << offset))
-                         });
-                            &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b00 << offset))
-                         })};
+                        _set_mode($i, 0b00, Pull::None);
                         $PXi { _mode: PhantomData }
                     }
 
                     /// Configures the pin to operate as a pulled down input pin
                     pub fn into_pull_down_input(
                         self,
-                        ) -> $PXi<Input<PullDown>> {
-                        let offset = 2 * $i;
-                        unsafe {
-                            &(*$GPIOX::ptr()).moder.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b00 << offset))
-                         });
-                            &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b10 << offset))
-                         })};
+                    ) -> $PXi<Input<PullDown>> {
+                        _set_mode($i, 0b00, Pull::Down);
                         $PXi { _mode: PhantomData }
                     }
 
@@ -328,34 +627,52 @@ macro_rules! gpio {
                     pub fn into_pull_up_input(
                         self,
                     ) -> $PXi<Input<PullUp>> {
-                        let offset = 2 * $i;
-                        unsafe {
-                            &(*$GPIOX::ptr()).moder.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b00 << offset))
-                         });
-                            &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b01 << offset))
-                         })};
+                        _set_mode($i, 0b00, Pull::Up);
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to operate as an analog pin, e.g. as an ADC input
+                    ///
+                    /// The digital input is disconnected, so it draws no current from
+                    /// intermediate voltages.
+                    pub fn into_analog(
+                        self,
+                    ) -> $PXi<Analog> {
+                        _set_mode($i, 0b11, Pull::None);
 
                         $PXi { _mode: PhantomData }
                     }
 
                     /// Configures the pin to operate as an open drain output pin
+                    ///
+                    /// The pin starts out released (high-Z), so configuring it doesn't put a low
+                    /// pulse on a shared bus.
                     pub fn into_open_drain_output(
                         self,
                     ) -> $PXi<Output<OpenDrain>> {
-                        let offset = 2 * $i;
+                        self.into_open_drain_output_in_state(PinState::High)
+                    }
+
+                    /// Configures the pin to operate as an open drain output pin, starting in
+                    /// `initial_state`
+                    ///
+                    /// The output level and type are set before the pin is switched to output
+                    /// mode, so it never drives any other level in between.
+                    pub fn into_open_drain_output_in_state(
+                        self,
+                        initial_state: PinState,
+                    ) -> $PXi<Output<OpenDrain>> {
+                        let bsrr = match initial_state {
+                            PinState::High => 1 << $i,
+                            PinState::Low => 1 << ($i + 16),
+                        };
                         unsafe {
-                            &(*$GPIOX::ptr()).moder.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b01 << offset))
-                         });
-                            &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b00 << offset))
-                         });
-                            &(*$GPIOX::ptr()).otyper.modify(|r, w| {
+                            (*$GPIOX::ptr()).bsrr.write(|w| w.bits(bsrr));
+                            (*$GPIOX::ptr()).otyper.modify(|r, w| {
                                 w.bits(r.bits() | (0b1 << $i))
-                         })};
-
+                            });
+                        }
+                        _set_mode($i, 0b01, Pull::None);
 
                         $PXi { _mode: PhantomData }
                     }
@@ -364,19 +681,12 @@ macro_rules! gpio {
                     pub fn into_push_pull_output(
                         self,
                     ) -> $PXi<Output<PushPull>> {
-                        let offset = 2 * $i;
-
                         unsafe {
-                            &(*$GPIOX::ptr()).moder.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b01 << offset))
-                         });
-                            &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (0b00 << offset))
-                            });
-                            &(*$GPIOX::ptr()).otyper.modify(|r, w| {
+                            (*$GPIOX::ptr()).otyper.modify(|r, w| {
                                 w.bits(r.bits() & !(0b1 << $i))
-                         })};
-
+                            });
+                        }
+                        _set_mode($i, 0b01, Pull::None);
 
                         $PXi { _mode: PhantomData }
                     }
@@ -385,36 +695,53 @@ macro_rules! gpio {
                 impl $PXi<Output<OpenDrain>> {
                     /// Enables / disables the internal pull up
                     pub fn internal_pull_up(&mut self, on: bool) {
-                        let offset = 2 * $i;
-                        let value = if on { 0b01 } else { 0b00 };
-                        unsafe {
-                            &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (value << offset))
-                         })};
+                        _set_pull($i, if on { Pull::Up } else { Pull::None });
                     }
                 }
 
                 impl<MODE> $PXi<Alternate<MODE>> {
                     /// Enables / disables the internal pull up
                     pub fn internal_pull_up(self, on: bool) -> Self {
-                        let offset = 2 * $i;
-                        let value = if on { 0b01 } else { 0b00 };
-                        unsafe {
-                            &(*$GPIOX::ptr()).pupdr.modify(|r, w| {
-                                w.bits((r.bits() & !(0b11 << offset)) | (value << offset))
-                         })};
+                        _set_pull($i, if on { Pull::Up } else { Pull::None });
 
                         self
                     }
                 }
 
                 impl<MODE> $PXi<Alternate<MODE>> {
+                    /// Sets the output speed
+                    ///
+                    /// Can be chained with `set_pull` and `set_open_drain` to configure the pin
+                    /// in one expression, e.g. `pa8.into_alternate_af4().set_speed(Speed::High)`.
+                    /// The pin is already connected by then, use `into_alternate_afX_with` to
+                    /// apply the settings before.
+                    pub fn set_speed(self, speed: Speed) -> Self {
+                        _set_speed($i, speed);
+
+                        self
+                    }
+
+                    /// Sets the internal pull-up / pull-down resistor
+                    pub fn set_pull(self, pull: Pull) -> Self {
+                        _set_pull($i, pull);
+
+                        self
+                    }
+
+                    /// Sets the output speed and the pull-up / pull-down resistor in one step
+                    pub fn set_speed_and_pull(self, speed: Speed, pull: Pull) -> Self {
+                        _set_speed($i, speed);
+                        _set_pull($i, pull);
+
+                        self
+                    }
+
                     /// Turns pin alternate configuration pin into open drain
                     pub fn set_open_drain(self) -> Self {
                         let offset = $i;
                         unsafe {
                             &(*$GPIOX::ptr()).otyper.modify(|r, w| {
-                                w.bits(r.bits() & (1 << offset))
+                                w.bits(r.bits() | (1 << offset))
                          })};
 
                         self
@@ -484,6 +811,78 @@ macro_rules! gpio {
                         self.i
                     }
                 }
+
+            /// Group of pins of this port that are read or written in one go
+            ///
+            /// The pins don't need to be contiguous. There are two ways to lay out a value:
+            /// `read` / `write` use the port layout (bit `i` is pin `i` of the port), while
+            /// `read_packed` / `write_packed` pack the group (bit `k` is the `k`-th pin given to
+            /// `new`), which suits data buses scattered over a port.
+            pub struct PortMask<MODE, const N: usize> {
+                pins: [$PXx<MODE>; N],
+                mask: u16,
+            }
+
+            impl<MODE, const N: usize> PortMask<MODE, N> {
+                /// Groups `pins`
+                pub fn new(pins: [$PXx<MODE>; N]) -> Self {
+                    let mask = pins.iter().fold(0, |mask, pin| mask | (1 << pin.i));
+                    PortMask { pins, mask }
+                }
+
+                /// Returns the pins of the group as bit mask in the port layout
+                pub fn mask(&self) -> u16 {
+                    self.mask
+                }
+
+                /// Releases the pins
+                pub fn free(self) -> [$PXx<MODE>; N] {
+                    self.pins
+                }
+            }
+
+            impl<MODE, const N: usize> PortMask<Output<MODE>, N> {
+                /// Sets the pins of the group to the port layout `value`, atomically
+                ///
+                /// Bits of pins outside of the group are ignored and those pins are left
+                /// untouched.
+                pub fn write(&mut self, value: u16) {
+                    let word = self.bsrr_word(value);
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(word)) };
+                }
+
+                /// Sets the pins of the group to the packed `value`, atomically
+                pub fn write_packed(&mut self, value: u16) {
+                    let port = unpack(self.pins.iter().map(|pin| pin.i), value);
+                    self.write(port);
+                }
+            }
+
+            impl<MODE, const N: usize> BsrrPort for PortMask<Output<MODE>, N> {
+                fn bsrr_address() -> u32 {
+                    // NOTE(unsafe) only the address of the register is taken
+                    unsafe { core::ptr::addr_of!((*$GPIOX::ptr()).bsrr) as u32 }
+                }
+
+                fn bsrr_word(&self, value: u16) -> u32 {
+                    bsrr_word(self.mask, value)
+                }
+            }
+
+            impl<MODE, const N: usize> PortMask<Input<MODE>, N> {
+                /// Reads the pins of the group in the port layout, other bits read as 0
+                pub fn read(&self) -> u16 {
+                    // NOTE(unsafe) atomic read with no side effects
+                    let idr = unsafe { (*$GPIOX::ptr()).idr.read().bits() };
+                    idr as u16 & self.mask
+                }
+
+                /// Reads the pins of the group as packed value
+                pub fn read_packed(&self) -> u16 {
+                    pack(self.pins.iter().map(|pin| pin.i), self.read())
+                }
+            }
         }
     }
 }
@@ -545,7 +944,7 @@ gpio!(GPIOC, gpioc, gpiocen, PC, [
     PC15: (pc15, 15, Input<Floating>),
 ]);
 
-gpio!(GPIOD, gpiod, gpioden, pd, [
+gpio!(GPIOD, gpiod, gpioden, PD, [
     PD0: (pd0, 0, Input<Floating>),
     PD1: (pd1, 1, Input<Floating>),
     PD2: (pd2, 2, Input<Floating>),
@@ -688,3 +1087,41 @@ gpio!(GPIOK, gpiok, gpioken, PK, [
     PK6: (pk6, 6, Input<Floating>),
     PK7: (pk7, 7, Input<Floating>),
 ]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bsrr_word_only_touches_masked_pins() {
+        // Pins 1, 4 and 9 of the port
+        let mask = 0b10_0001_0010;
+        assert_eq!(bsrr_word(mask, 0xffff), 0x0000_0212);
+        assert_eq!(bsrr_word(mask, 0x0000), 0x0212_0000);
+        assert_eq!(bsrr_word(mask, 0b10_0000_0010), 0x0010_0202);
+    }
+
+    #[test]
+    fn bsrr_word_of_empty_mask_is_empty() {
+        assert_eq!(bsrr_word(0, 0xffff), 0);
+    }
+
+    #[test]
+    fn packed_values_follow_pin_order() {
+        let pins = [9, 1, 4];
+        assert_eq!(unpack(pins.iter().copied(), 0b001), 1 << 9);
+        assert_eq!(unpack(pins.iter().copied(), 0b110), (1 << 1) | (1 << 4));
+        assert_eq!(pack(pins.iter().copied(), (1 << 9) | (1 << 4)), 0b101);
+        // Pins outside of the group are dropped
+        assert_eq!(pack(pins.iter().copied(), 0xfffd), 0b101);
+    }
+
+    #[test]
+    fn pack_reverts_unpack() {
+        let pins = [0, 3, 7, 8, 15];
+        for value in 0..(1 << pins.len()) {
+            let port = unpack(pins.iter().copied(), value);
+            assert_eq!(pack(pins.iter().copied(), port), value);
+        }
+    }
+}
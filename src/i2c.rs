@@ -0,0 +1,1176 @@
+//! Inter-Integrated Circuit (I2C) bus, master and slave mode
+
+use crate::device::i2c1::RegisterBlock;
+use crate::device::{DMA1, I2C1, I2C2, I2C3, I2C4};
+use crate::dma::{self, DmaStream, Stream, Transfer};
+use crate::gpio::gpioa::PA8;
+use crate::gpio::gpiob::{PB10, PB11, PB6, PB7, PB8, PB9};
+use crate::gpio::gpioc::PC9;
+use crate::gpio::gpiod::{PD12, PD13};
+use crate::gpio::gpiof::{PF0, PF1, PF14, PF15};
+use crate::gpio::gpioh::{PH11, PH12, PH4, PH5, PH7, PH8};
+use crate::gpio::{Alternate, AF4};
+use crate::hal::blocking::i2c::{Read, Write, WriteRead};
+use crate::rcc::{Clocks, Reset, APB1};
+use crate::time::{Hertz, U32Ext};
+
+/// I2C error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Bus error, a misplaced START or STOP condition
+    Bus,
+    /// Arbitration lost to another master
+    Arbitration,
+    /// The address or a data byte wasn't acknowledged
+    Nack,
+    /// The DMA stream of a transfer failed, or can't transfer the buffer
+    Dma(dma::Error),
+}
+
+/// SCL low to high time ratio in Fast mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DutyCycle {
+    /// t_low / t_high = 2
+    Ratio2to1,
+    /// t_low / t_high = 16 / 9
+    Ratio16to9,
+}
+
+/// I2C bus mode
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Standard mode, up to 100 kHz
+    Standard { frequency: Hertz },
+    /// Fast mode, up to 400 kHz
+    Fast {
+        frequency: Hertz,
+        duty_cycle: DutyCycle,
+    },
+}
+
+impl Mode {
+    /// Standard mode at `frequency`
+    pub fn standard<F>(frequency: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        Mode::Standard {
+            frequency: frequency.into(),
+        }
+    }
+
+    /// Fast mode at `frequency` with a 2:1 duty cycle
+    pub fn fast<F>(frequency: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        Mode::Fast {
+            frequency: frequency.into(),
+            duty_cycle: DutyCycle::Ratio2to1,
+        }
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::standard(100_000.hz())
+    }
+}
+
+/// Own address of an I2C slave
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Address {
+    /// 7-bit address
+    SevenBit(u8),
+    /// 10-bit address
+    TenBit(u16),
+}
+
+/// Addresses an I2C slave responds to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlaveConfig {
+    /// Primary own address (OAR1)
+    pub address: Address,
+    /// Second 7-bit own address (OAR2), and how many of its low bits are ignored (0 - 7)
+    ///
+    /// With 7 ignored bits the slave answers to every address except the reserved ones.
+    pub address2: Option<(u8, u8)>,
+    /// Also answers to the general call address 0x00, a broadcast to all slaves
+    ///
+    /// A general call is always a [`Request::Write`], reported with address 0.
+    pub general_call: bool,
+}
+
+impl SlaveConfig {
+    /// Responds to `address` only
+    pub fn new(address: Address) -> Self {
+        SlaveConfig {
+            address,
+            address2: None,
+            general_call: false,
+        }
+    }
+}
+
+/// What the master asked the slave for, as seen from the master
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+    /// The master writes, the slave receives with [`I2cSlave::read`]
+    Write,
+    /// The master reads, the slave transmits with [`I2cSlave::write`]
+    Read,
+}
+
+/// I2C slave interrupt event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlaveEvent {
+    /// One of the own addresses was matched (ADDR)
+    AddressMatch,
+    /// A byte was received (RXNE)
+    Rxne,
+    /// The master wants the next byte (TXIS)
+    Txis,
+    /// The master sent STOP (STOPF)
+    Stop,
+    /// The master didn't acknowledge a byte, it doesn't read any more (NACKF)
+    Nack,
+    /// Bus error or arbitration loss (BERR / ARLO)
+    Error,
+}
+
+/// Pins usable as the SCL line of an I2C bus
+pub trait PinScl<I2C> {}
+
+/// Pins usable as the SDA line of an I2C bus
+pub trait PinSda<I2C> {}
+
+/// SCL / SDA pin pair of an I2C bus
+pub trait Pins<I2C> {}
+
+impl<I2C, SCL, SDA> Pins<I2C> for (SCL, SDA)
+where
+    SCL: PinScl<I2C>,
+    SDA: PinSda<I2C>,
+{
+}
+
+impl PinScl<I2C1> for PB6<Alternate<AF4>> {}
+impl PinScl<I2C1> for PB8<Alternate<AF4>> {}
+impl PinSda<I2C1> for PB7<Alternate<AF4>> {}
+impl PinSda<I2C1> for PB9<Alternate<AF4>> {}
+
+impl PinScl<I2C2> for PB10<Alternate<AF4>> {}
+impl PinScl<I2C2> for PF1<Alternate<AF4>> {}
+impl PinScl<I2C2> for PH4<Alternate<AF4>> {}
+impl PinSda<I2C2> for PB11<Alternate<AF4>> {}
+impl PinSda<I2C2> for PF0<Alternate<AF4>> {}
+impl PinSda<I2C2> for PH5<Alternate<AF4>> {}
+
+impl PinScl<I2C3> for PA8<Alternate<AF4>> {}
+impl PinScl<I2C3> for PH7<Alternate<AF4>> {}
+impl PinSda<I2C3> for PC9<Alternate<AF4>> {}
+impl PinSda<I2C3> for PH8<Alternate<AF4>> {}
+
+impl PinScl<I2C4> for PD12<Alternate<AF4>> {}
+impl PinScl<I2C4> for PF14<Alternate<AF4>> {}
+impl PinScl<I2C4> for PH11<Alternate<AF4>> {}
+impl PinSda<I2C4> for PD13<Alternate<AF4>> {}
+impl PinSda<I2C4> for PF15<Alternate<AF4>> {}
+impl PinSda<I2C4> for PH12<Alternate<AF4>> {}
+
+/// Most bytes a single transfer can move before NBYTES has to be reloaded
+const MAX_NBYTES: usize = 255;
+
+/// 7-bit addresses left to devices, the others are reserved by the specification
+const SCAN_ADDRESSES: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+/// NBYTES, RELOAD and AUTOEND of the next chunk of a transfer
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Chunk {
+    nbytes: u8,
+    reload: bool,
+    autoend: bool,
+}
+
+/// Returns the CR2 byte count settings for a transfer with `remaining` bytes left
+///
+/// A STOP is only sent after the last chunk, and only with `autoend`. Without it the transfer
+/// ends with TC set and SCL held low, ready for a repeated START.
+fn chunk(remaining: usize, autoend: bool) -> Chunk {
+    let last = remaining <= MAX_NBYTES;
+
+    Chunk {
+        nbytes: remaining.min(MAX_NBYTES) as u8,
+        reload: !last,
+        autoend: autoend && last,
+    }
+}
+
+/// Checks and clears the error flags of a master, recovering from a NACK
+fn check_errors(i2c: &RegisterBlock) -> Result<(), Error> {
+    let isr = i2c.isr.read();
+
+    if isr.berr().bit_is_set() {
+        i2c.icr.write(|w| w.berrcf().set_bit());
+        Err(Error::Bus)
+    } else if isr.arlo().bit_is_set() {
+        i2c.icr.write(|w| w.arlocf().set_bit());
+        Err(Error::Arbitration)
+    } else if isr.nackf().bit_is_set() {
+        // The hardware sends a STOP by itself after a NACK. Only clear the flags once it's
+        // there, otherwise a late STOPF ends the next transfer early.
+        // A bus error or lost arbitration means there is no STOP, the next check reports them.
+        loop {
+            let isr = i2c.isr.read();
+            if isr.stopf().bit_is_set() || isr.berr().bit_is_set() || isr.arlo().bit_is_set() {
+                break;
+            }
+        }
+        i2c.icr.write(|w| w.nackcf().set_bit().stopcf().set_bit());
+        // Flush a byte that may still wait in TXDR, so it doesn't go out with the next transfer
+        i2c.isr.write(|w| w.txe().set_bit());
+        Err(Error::Nack)
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes the next chunk of a transfer into CR2, once TCR is set
+fn reload(i2c: &RegisterBlock, chunk: Chunk) {
+    i2c.cr2.modify(|_, w| {
+        w.nbytes()
+            .bits(chunk.nbytes)
+            .reload()
+            .bit(chunk.reload)
+            .autoend()
+            .bit(chunk.autoend)
+    });
+}
+
+/// TIMINGR fields
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Timing {
+    presc: u8,
+    scll: u8,
+    sclh: u8,
+    sdadel: u8,
+    scldel: u8,
+}
+
+/// Derives the TIMINGR fields for `mode` from the I2C kernel clock frequency `i2cclk`
+///
+/// The SCL period is split between the low and high phase according to the mode, and the data
+/// setup (SCLDEL) and hold (SDADEL) times cover the worst-case rise and fall times of the mode.
+/// The analog filter and clock synchronisation add a few kernel clock cycles per period, so the
+/// bus ends up slightly slower than requested, never faster.
+fn timing(mode: Mode, i2cclk: u32) -> Timing {
+    let (frequency, low, high, setup_ns, hold_ns): (u32, u32, u32, u32, u32) = match mode {
+        Mode::Standard { frequency } => {
+            assert!(frequency.0 <= 100_000);
+            // t_su;dat + t_r = 250 ns + 1000 ns, t_f - t_af = 300 ns - 50 ns
+            (frequency.0, 1, 1, 1_250, 250)
+        }
+        Mode::Fast {
+            frequency,
+            duty_cycle,
+        } => {
+            assert!(frequency.0 <= 400_000);
+            let (low, high) = match duty_cycle {
+                DutyCycle::Ratio2to1 => (2, 1),
+                DutyCycle::Ratio16to9 => (16, 9),
+            };
+            // t_su;dat + t_r = 100 ns + 300 ns, t_f - t_af = 300 ns - 50 ns
+            (frequency.0, low, high, 400, 250)
+        }
+    };
+    assert!(frequency > 0);
+
+    // Rounded up so the bus is never faster than requested
+    let period = i2cclk.div_ceil(frequency);
+    let setup = (u64::from(setup_ns) * u64::from(i2cclk) / 1_000_000_000) as u32;
+
+    // The prescaler has to be large enough for the longer, low SCL phase to fit in 8 bits and
+    // for the setup time to fit in the 4-bit SCLDEL
+    let presc = (period * low)
+        .div_ceil((low + high) * 256)
+        .max(setup.div_ceil(16))
+        .max(1)
+        - 1;
+    assert!(presc <= 15);
+
+    // (PRESC + 1) * 10^9 overflows 32 bits from PRESC = 4 on
+    let tpresc_ns = (u64::from(presc + 1) * 1_000_000_000 / u64::from(i2cclk)) as u32;
+    let ticks = period.div_ceil(presc + 1);
+    let low_ticks = ticks * low / (low + high);
+    let high_ticks = ticks - low_ticks;
+
+    Timing {
+        presc: presc as u8,
+        scll: (low_ticks.max(1) - 1).min(255) as u8,
+        sclh: (high_ticks.max(1) - 1).min(255) as u8,
+        sdadel: hold_ns.div_ceil(tpresc_ns).min(15) as u8,
+        scldel: (setup_ns.div_ceil(tpresc_ns).max(1) - 1).min(15) as u8,
+    }
+}
+
+/// DMA stream and channel of the transmit request of an I2C
+pub trait TxStream<I2C>: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+/// DMA stream and channel of the receive request of an I2C
+pub trait RxStream<I2C>: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+macro_rules! streams {
+    ($($I2C:ident: ($trait:ident, [$($DMA:ident: ($stream:expr, $channel:expr)),+]),)+) => {
+        $(
+            $(
+                impl $trait<$I2C> for Stream<$DMA, $stream> {
+                    const CHANNEL: u8 = $channel;
+                }
+            )+
+        )+
+    }
+}
+
+streams! {
+    I2C1: (TxStream, [DMA1: (6, 1), DMA1: (7, 1)]),
+    I2C1: (RxStream, [DMA1: (0, 1), DMA1: (5, 1)]),
+
+    I2C2: (TxStream, [DMA1: (7, 7)]),
+    I2C2: (RxStream, [DMA1: (2, 7), DMA1: (3, 7)]),
+
+    I2C3: (TxStream, [DMA1: (4, 3)]),
+    I2C3: (RxStream, [DMA1: (1, 1), DMA1: (2, 3)]),
+
+    I2C4: (TxStream, [DMA1: (5, 2)]),
+    I2C4: (RxStream, [DMA1: (2, 2)]),
+}
+
+/// I2C master abstraction
+pub struct I2c<I2C, PINS> {
+    i2c: I2C,
+    pins: PINS,
+}
+
+/// I2C master bound to a transmit and a receive DMA stream
+pub struct I2cDma<I2C, PINS, TX, RX> {
+    i2c: I2c<I2C, PINS>,
+    tx: TX,
+    rx: RX,
+}
+
+/// DMA transfer of an I2C master, continuing it past 255 bytes
+///
+/// NBYTES only counts to 255, after that the peripheral stretches SCL until software reloads
+/// it. The stream moves the data, while [`poll`](Self::poll) or [`wait`](Self::wait) provide
+/// the reloads and watch the bus for errors.
+pub struct I2cTransfer<I2C, PINS, TX, RX, BUFFER> {
+    transfer: Transfer<I2cDma<I2C, PINS, TX, RX>, BUFFER>,
+    /// Bytes beyond the chunk NBYTES currently counts
+    remaining: usize,
+    done: bool,
+}
+
+impl<I2C, PINS, TX, RX, BUFFER> I2cTransfer<I2C, PINS, TX, RX, BUFFER> {
+    fn new(transfer: Transfer<I2cDma<I2C, PINS, TX, RX>, BUFFER>, len: usize) -> Self {
+        I2cTransfer {
+            transfer,
+            remaining: len - usize::from(chunk(len, true).nbytes),
+            done: false,
+        }
+    }
+}
+
+/// I2C slave abstraction
+///
+/// Byte control is enabled, so every received byte is acknowledged by software and a transfer
+/// of any length can be accepted or cut short. SCL is stretched while the slave waits for
+/// software, so a slow loop only slows the bus down.
+///
+/// The slave can't wake the device from Stop mode. Wakeup on address match (CR1.WUPEN) isn't
+/// implemented by the I2C of this family, and none of the EXTI lines is connected to it. A
+/// slave that has to stay reachable keeps the device in Sleep mode, which the
+/// [`AddressMatch`](SlaveEvent::AddressMatch) interrupt ends.
+pub struct I2cSlave<I2C, PINS> {
+    i2c: I2C,
+    pins: PINS,
+}
+
+macro_rules! hal {
+    ($($I2CX:ident: ($i2cX:ident, $i2cXen:ident, $i2cXclk:ident),)+) => {
+        $(
+            impl<PINS> I2c<$I2CX, PINS> {
+                /// Configures an I2C peripheral as bus master
+                ///
+                /// The timing is derived from the kernel clock of the peripheral, PCLK1 unless
+                /// another one was selected in `CFGR`. SCL and SDA must be configured as open
+                /// drain and need pull-ups, either external ones or the pins' internal ones. Set
+                /// both before the pins connect to the bus, so they never drive it push pull:
+                /// `pb8.into_alternate_af4_with(AltConfig { pull: Pull::Up, open_drain: true, ..AltConfig::default() })`.
+                pub fn $i2cX(mut i2c: $I2CX, pins: PINS, mode: Mode, clocks: Clocks, apb: &mut APB1) -> Self
+                where
+                    PINS: Pins<$I2CX>,
+                {
+                    // reset the peripheral to a clean slate state, then enable it
+                    i2c.reset(apb);
+                    apb.enr().modify(|_, w| w.$i2cXen().set_bit());
+                    // The enable takes two bus cycles to reach the peripheral, reading it back
+                    // makes sure the configuration below isn't lost
+                    let _ = apb.enr().read();
+
+                    let timing = timing(mode, clocks.$i2cXclk().0);
+
+                    // TIMINGR can only be written while the peripheral is disabled
+                    i2c.cr1.modify(|_, w| w.pe().clear_bit());
+                    i2c.timingr.write(|w| {
+                        w.presc()
+                            .bits(timing.presc)
+                            .scll()
+                            .bits(timing.scll)
+                            .sclh()
+                            .bits(timing.sclh)
+                            .sdadel()
+                            .bits(timing.sdadel)
+                            .scldel()
+                            .bits(timing.scldel)
+                    });
+                    i2c.cr1.modify(|_, w| w.pe().set_bit());
+
+                    I2c { i2c, pins }
+                }
+
+                /// Releases the I2C peripheral and associated pins
+                pub fn free(self) -> ($I2CX, PINS) {
+                    self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+                    (self.i2c, self.pins)
+                }
+
+                /// Probes every non-reserved 7-bit address, passing the ones that acknowledge
+                /// to `found`
+                ///
+                /// Each address gets a write of zero bytes, START + address + STOP. Some devices
+                /// don't expect a write without data, but it's the probe least likely to change
+                /// their state. A bus error or lost arbitration ends the scan.
+                pub fn scan<F>(&mut self, mut found: F) -> Result<(), Error>
+                where
+                    F: FnMut(u8),
+                {
+                    for addr in SCAN_ADDRESSES {
+                        if self.probe(addr)? {
+                            found(addr);
+                        }
+                    }
+
+                    Ok(())
+                }
+
+                /// Returns whether a device acknowledges `addr`
+                fn probe(&mut self, addr: u8) -> Result<bool, Error> {
+                    self.start(addr, 0, false, true);
+
+                    // Both outcomes end with the STOP sent by the hardware, only clear the
+                    // flags once it's there so it doesn't end the next probe early
+                    let mut present = true;
+                    loop {
+                        let isr = self.i2c.isr.read();
+
+                        if isr.berr().bit_is_set() || isr.arlo().bit_is_set() {
+                            return self.check_errors().map(|_| false);
+                        }
+                        if isr.nackf().bit_is_set() {
+                            present = false;
+                        }
+                        if isr.stopf().bit_is_set() {
+                            self.i2c.icr.write(|w| w.nackcf().set_bit().stopcf().set_bit());
+                            return Ok(present);
+                        }
+                    }
+                }
+
+                /// Checks and clears the error flags, recovering from a NACK
+                fn check_errors(&self) -> Result<(), Error> {
+                    check_errors(&self.i2c)
+                }
+
+                /// Sends START + address, for a transfer of `len` bytes in total
+                ///
+                /// `autoend` selects whether the hardware sends STOP once all bytes went out.
+                /// Transfers longer than 255 bytes are continued through `reload`.
+                fn start(&mut self, addr: u8, len: usize, read: bool, autoend: bool) {
+                    let chunk = chunk(len, autoend);
+
+                    self.i2c.cr2.write(|w| {
+                        w.sadd()
+                            .bits(u16::from(addr) << 1)
+                            .rd_wrn()
+                            .bit(read)
+                            .nbytes()
+                            .bits(chunk.nbytes)
+                            .reload()
+                            .bit(chunk.reload)
+                            .autoend()
+                            .bit(chunk.autoend)
+                            .start()
+                            .set_bit()
+                    });
+                }
+
+                /// Continues a transfer with `remaining` bytes left once NBYTES ran out
+                fn reload(&mut self, remaining: usize, autoend: bool) -> Result<(), Error> {
+                    loop {
+                        self.check_errors()?;
+                        if self.i2c.isr.read().tcr().bit_is_set() {
+                            break;
+                        }
+                    }
+
+                    reload(&self.i2c, chunk(remaining, autoend));
+
+                    Ok(())
+                }
+
+                fn write_bytes(&mut self, addr: u8, bytes: &[u8], autoend: bool) -> Result<(), Error> {
+                    self.start(addr, bytes.len(), false, autoend);
+
+                    for (i, byte) in bytes.iter().enumerate() {
+                        if i > 0 && i % MAX_NBYTES == 0 {
+                            self.reload(bytes.len() - i, autoend)?;
+                        }
+
+                        loop {
+                            self.check_errors()?;
+                            if self.i2c.isr.read().txis().bit_is_set() {
+                                break;
+                            }
+                        }
+                        self.i2c.txdr.write(|w| w.txdata().bits(*byte));
+                    }
+
+                    if autoend {
+                        self.wait_stop()
+                    } else {
+                        // Wait until the last byte went out, ready for a repeated START
+                        loop {
+                            self.check_errors()?;
+                            if self.i2c.isr.read().tc().bit_is_set() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+
+                fn read_bytes(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error> {
+                    let len = buffer.len();
+                    self.start(addr, len, true, true);
+
+                    for (i, byte) in buffer.iter_mut().enumerate() {
+                        if i > 0 && i % MAX_NBYTES == 0 {
+                            self.reload(len - i, true)?;
+                        }
+
+                        loop {
+                            self.check_errors()?;
+                            if self.i2c.isr.read().rxne().bit_is_set() {
+                                break;
+                            }
+                        }
+                        *byte = self.i2c.rxdr.read().rxdata().bits();
+                    }
+
+                    self.wait_stop()
+                }
+
+                fn wait_stop(&mut self) -> Result<(), Error> {
+                    loop {
+                        self.check_errors()?;
+                        if self.i2c.isr.read().stopf().bit_is_set() {
+                            self.i2c.icr.write(|w| w.stopcf().set_bit());
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+
+            impl<PINS> I2c<$I2CX, PINS> {
+                /// Binds the master to DMA streams for its transmit and receive requests
+                pub fn with_dma<TX, RX>(self, tx: TX, rx: RX) -> I2cDma<$I2CX, PINS, TX, RX>
+                where
+                    TX: TxStream<$I2CX>,
+                    RX: RxStream<$I2CX>,
+                {
+                    self.i2c.cr1.modify(|_, w| w.txdmaen().set_bit().rxdmaen().set_bit());
+
+                    I2cDma { i2c: self, tx, rx }
+                }
+            }
+
+            impl<PINS, TX, RX> I2cDma<$I2CX, PINS, TX, RX>
+            where
+                TX: TxStream<$I2CX>,
+                RX: RxStream<$I2CX>,
+            {
+                /// Starts writing `bytes` to the device at `addr`, ending with STOP
+                #[allow(clippy::type_complexity)]
+                pub fn write_dma(
+                    mut self,
+                    addr: u8,
+                    bytes: &'static [u8],
+                ) -> Result<
+                    I2cTransfer<$I2CX, PINS, TX, RX, &'static [u8]>,
+                    (Error, &'static [u8], Self),
+                > {
+                    let stream = self.tx.handle();
+                    let started = stream.start(dma::Config {
+                        channel: TX::CHANNEL,
+                        direction: dma::Direction::MemoryToPeripheral,
+                        width: dma::Width::Byte,
+                        // NOTE(unsafe) only the address of the register is taken
+                        peripheral: unsafe { core::ptr::addr_of!((*$I2CX::ptr()).txdr) as u32 },
+                        memory0: bytes.as_ptr() as u32,
+                        memory1: None,
+                        len: bytes.len(),
+                    });
+                    if let Err(error) = started {
+                        return Err((Error::Dma(error), bytes, self));
+                    }
+                    self.i2c.start(addr, bytes.len(), false, true);
+
+                    Ok(I2cTransfer::new(Transfer::new(self, bytes, stream), bytes.len()))
+                }
+
+                /// Starts reading into `buffer` from the device at `addr`, ending with STOP
+                #[allow(clippy::type_complexity)]
+                pub fn read_dma(
+                    mut self,
+                    addr: u8,
+                    buffer: &'static mut [u8],
+                ) -> Result<
+                    I2cTransfer<$I2CX, PINS, TX, RX, &'static mut [u8]>,
+                    (Error, &'static mut [u8], Self),
+                > {
+                    let len = buffer.len();
+                    let stream = self.rx.handle();
+                    let started = stream.start(dma::Config {
+                        channel: RX::CHANNEL,
+                        direction: dma::Direction::PeripheralToMemory,
+                        width: dma::Width::Byte,
+                        // NOTE(unsafe) only the address of the register is taken
+                        peripheral: unsafe { core::ptr::addr_of!((*$I2CX::ptr()).rxdr) as u32 },
+                        memory0: buffer.as_ptr() as u32,
+                        memory1: None,
+                        len,
+                    });
+                    if let Err(error) = started {
+                        return Err((Error::Dma(error), buffer, self));
+                    }
+                    self.i2c.start(addr, len, true, true);
+
+                    Ok(I2cTransfer::new(Transfer::new(self, buffer, stream), len))
+                }
+
+                /// Writes `bytes`, then starts reading into `buffer` after a repeated START
+                ///
+                /// The write phase, typically a register address, is sent by the CPU and only
+                /// the read phase uses DMA. See [`WriteRead::write_read`] for the sequence.
+                pub fn write_read_dma(
+                    mut self,
+                    addr: u8,
+                    bytes: &[u8],
+                    buffer: &'static mut [u8],
+                ) -> Result<I2cTransfer<$I2CX, PINS, TX, RX, &'static mut [u8]>, (Error, Self)> {
+                    // Nothing may be sent if the read phase can't follow
+                    if let Err(error) = dma::check_len(buffer.len()) {
+                        return Err((Error::Dma(error), self));
+                    }
+
+                    // The transmit requests of the write phase must not reach the TX stream
+                    self.i2c.i2c.cr1.modify(|_, w| w.txdmaen().clear_bit());
+                    let result = self.i2c.write_bytes(addr, bytes, false);
+                    self.i2c.i2c.cr1.modify(|_, w| w.txdmaen().set_bit());
+
+                    match result {
+                        Ok(()) => self
+                            .read_dma(addr, buffer)
+                            .map_err(|(error, _, i2c)| (error, i2c)),
+                        Err(error) => Err((error, self)),
+                    }
+                }
+
+                /// Releases the master and the streams
+                pub fn free(self) -> (I2c<$I2CX, PINS>, TX, RX) {
+                    self.i2c
+                        .i2c
+                        .cr1
+                        .modify(|_, w| w.txdmaen().clear_bit().rxdmaen().clear_bit());
+
+                    (self.i2c, self.tx, self.rx)
+                }
+            }
+
+            impl<PINS, TX, RX, BUFFER> I2cTransfer<$I2CX, PINS, TX, RX, BUFFER> {
+                /// Reloads NBYTES when needed and returns `Ok` once STOP has been sent
+                ///
+                /// Has to be called at least once per 255 bytes, or the bus stays stretched.
+                /// After an error the stream waits for requests that never come, the transfer
+                /// is then ended by [`wait`](Self::wait).
+                pub fn poll(&mut self) -> nb::Result<(), Error> {
+                    if self.done {
+                        return Ok(());
+                    }
+
+                    if let Some(error) = self.transfer.error() {
+                        return Err(nb::Error::Other(Error::Dma(error)));
+                    }
+                    check_errors(&self.transfer.peripheral().i2c.i2c)?;
+
+                    let i2c = &self.transfer.peripheral().i2c.i2c;
+                    let isr = i2c.isr.read();
+                    if isr.tcr().bit_is_set() {
+                        let chunk = chunk(self.remaining, true);
+                        self.remaining -= usize::from(chunk.nbytes);
+                        reload(i2c, chunk);
+                    }
+                    if isr.stopf().bit_is_set() && self.transfer.is_complete() {
+                        i2c.icr.write(|w| w.stopcf().set_bit());
+                        self.done = true;
+                        return Ok(());
+                    }
+
+                    Err(nb::Error::WouldBlock)
+                }
+
+                /// Blocks until the transfer is done, returning the buffer and the master
+                ///
+                /// On an error the stream is stopped and they are returned with the error.
+                #[allow(clippy::type_complexity)]
+                pub fn wait(
+                    mut self,
+                ) -> Result<
+                    (BUFFER, I2cDma<$I2CX, PINS, TX, RX>),
+                    (Error, BUFFER, I2cDma<$I2CX, PINS, TX, RX>),
+                > {
+                    let result = nb::block!(self.poll());
+
+                    let (buffer, i2c) = self.transfer.abort();
+                    match result {
+                        Ok(()) => Ok((buffer, i2c)),
+                        Err(error) => Err((error, buffer, i2c)),
+                    }
+                }
+            }
+
+            impl<PINS> I2cSlave<$I2CX, PINS> {
+                /// Configures an I2C peripheral as slave responding to the addresses in `config`
+                ///
+                /// The data setup and hold times are derived from the kernel clock for Standard
+                /// mode, which meets the Fast mode timing as well. The pins need to be set up as
+                /// for the master.
+                pub fn $i2cX(
+                    mut i2c: $I2CX,
+                    pins: PINS,
+                    config: SlaveConfig,
+                    clocks: Clocks,
+                    apb: &mut APB1,
+                ) -> Self
+                where
+                    PINS: Pins<$I2CX>,
+                {
+                    // reset the peripheral to a clean slate state, then enable it
+                    i2c.reset(apb);
+                    apb.enr().modify(|_, w| w.$i2cXen().set_bit());
+                    // The enable takes two bus cycles to reach the peripheral, reading it back
+                    // makes sure the configuration below isn't lost
+                    let _ = apb.enr().read();
+
+                    let timing = timing(Mode::default(), clocks.$i2cXclk().0);
+
+                    // TIMINGR and the own addresses can only be written while disabled
+                    i2c.cr1.modify(|_, w| w.pe().clear_bit());
+                    i2c.timingr.write(|w| {
+                        w.presc()
+                            .bits(timing.presc)
+                            .sdadel()
+                            .bits(timing.sdadel)
+                            .scldel()
+                            .bits(timing.scldel)
+                    });
+
+                    match config.address {
+                        Address::SevenBit(address) => i2c.oar1.write(|w| {
+                            w.oa1()
+                                .bits(u16::from(address) << 1)
+                                .oa1mode()
+                                .clear_bit()
+                                .oa1en()
+                                .set_bit()
+                        }),
+                        Address::TenBit(address) => i2c.oar1.write(|w| {
+                            w.oa1()
+                                .bits(address & 0x3ff)
+                                .oa1mode()
+                                .set_bit()
+                                .oa1en()
+                                .set_bit()
+                        }),
+                    }
+                    match config.address2 {
+                        Some((address, masked)) => i2c.oar2.write(|w| {
+                            w.oa2()
+                                .bits(address & 0x7f)
+                                .oa2msk()
+                                .bits(masked.min(7))
+                                .oa2en()
+                                .set_bit()
+                        }),
+                        None => i2c.oar2.reset(),
+                    }
+
+                    i2c.cr1.modify(|_, w| {
+                        w.gcen()
+                            .bit(config.general_call)
+                            .sbc()
+                            .set_bit()
+                            .pe()
+                            .set_bit()
+                    });
+
+                    I2cSlave { i2c, pins }
+                }
+
+                /// Starts listening for an interrupt event
+                pub fn listen(&mut self, event: SlaveEvent) {
+                    self.i2c.cr1.modify(|_, w| match event {
+                        SlaveEvent::AddressMatch => w.addrie().set_bit(),
+                        SlaveEvent::Rxne => w.rxie().set_bit(),
+                        SlaveEvent::Txis => w.txie().set_bit(),
+                        SlaveEvent::Stop => w.stopie().set_bit(),
+                        SlaveEvent::Nack => w.nackie().set_bit(),
+                        SlaveEvent::Error => w.errie().set_bit(),
+                    });
+                }
+
+                /// Stops listening for an interrupt event
+                pub fn unlisten(&mut self, event: SlaveEvent) {
+                    self.i2c.cr1.modify(|_, w| match event {
+                        SlaveEvent::AddressMatch => w.addrie().clear_bit(),
+                        SlaveEvent::Rxne => w.rxie().clear_bit(),
+                        SlaveEvent::Txis => w.txie().clear_bit(),
+                        SlaveEvent::Stop => w.stopie().clear_bit(),
+                        SlaveEvent::Nack => w.nackie().clear_bit(),
+                        SlaveEvent::Error => w.errie().clear_bit(),
+                    });
+                }
+
+                /// Returns `true` if one of the own addresses was matched and the slave waits for
+                /// [`address_match`](Self::address_match)
+                pub fn is_addressed(&self) -> bool {
+                    self.i2c.isr.read().addr().bit_is_set()
+                }
+
+                /// Waits for the master to address the slave
+                ///
+                /// Returns the matched 7-bit address (the header bits for a 10-bit address, 0 for
+                /// a general call) and what the master asks for, which decides whether [`read`](Self::read) or
+                /// [`write`](Self::write) has to follow. SCL is stretched until then.
+                pub fn address_match(&mut self) -> nb::Result<(u8, Request), Error> {
+                    self.check_errors()?;
+
+                    let isr = self.i2c.isr.read();
+                    if isr.addr().bit_is_clear() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+
+                    let request = if isr.dir().bit_is_set() {
+                        // Flush a byte left over from a previous transfer
+                        self.i2c.isr.write(|w| w.txe().set_bit());
+                        Request::Read
+                    } else {
+                        Request::Write
+                    };
+
+                    // One byte per reload, so each byte can be acknowledged on its own
+                    self.i2c.cr2.write(|w| w.nbytes().bits(1).reload().set_bit());
+                    self.i2c.icr.write(|w| w.addrcf().set_bit());
+
+                    Ok((isr.addcode().bits(), request))
+                }
+
+                /// Receives the bytes of a [`Request::Write`] into `buffer`
+                ///
+                /// Returns once the master sends STOP or a repeated START, with the number of
+                /// bytes received. When `buffer` is full, the next byte is not acknowledged.
+                pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+                    let mut count = 0;
+                    let mut full = false;
+
+                    loop {
+                        self.check_errors()?;
+                        let isr = self.i2c.isr.read();
+
+                        if isr.rxne().bit_is_set() {
+                            let byte = self.i2c.rxdr.read().rxdata().bits();
+                            match buffer.get_mut(count) {
+                                Some(slot) => {
+                                    *slot = byte;
+                                    count += 1;
+                                }
+                                None => full = true,
+                            }
+                        } else if isr.tcr().bit_is_set() {
+                            // SCL is held between the byte and its ACK until NBYTES is written
+                            self.i2c
+                                .cr2
+                                .modify(|_, w| w.nack().bit(full).nbytes().bits(1));
+                        } else if isr.stopf().bit_is_set() {
+                            self.i2c.icr.write(|w| w.stopcf().set_bit());
+                            return Ok(count);
+                        } else if isr.addr().bit_is_set() {
+                            // Repeated START, left for the next address_match
+                            return Ok(count);
+                        }
+                    }
+                }
+
+                /// Transmits `bytes` for a [`Request::Read`]
+                ///
+                /// Returns once the master ends the transfer, with the number of bytes it
+                /// took. If the master reads past the end of `bytes`, it gets `0xff`.
+                pub fn write(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+                    let mut count = 0;
+
+                    loop {
+                        self.check_errors()?;
+                        let isr = self.i2c.isr.read();
+
+                        if isr.txis().bit_is_set() {
+                            let byte = bytes.get(count).copied().unwrap_or(0xff);
+                            self.i2c.txdr.write(|w| w.txdata().bits(byte));
+                            count += 1;
+                        } else if isr.tcr().bit_is_set() {
+                            self.i2c.cr2.modify(|_, w| w.nbytes().bits(1));
+                        } else if isr.nackf().bit_is_set() {
+                            // The master NACKs the last byte it wants. TXDR may already hold the
+                            // next one, which never goes out.
+                            if isr.txe().bit_is_clear() {
+                                count = count.saturating_sub(1);
+                                self.i2c.isr.write(|w| w.txe().set_bit());
+                            }
+                            self.i2c.icr.write(|w| w.nackcf().set_bit());
+                        } else if isr.stopf().bit_is_set() {
+                            self.i2c.icr.write(|w| w.stopcf().set_bit());
+                            return Ok(count.min(bytes.len()));
+                        } else if isr.addr().bit_is_set() {
+                            return Ok(count.min(bytes.len()));
+                        }
+                    }
+                }
+
+                /// Releases the I2C peripheral and associated pins
+                pub fn free(self) -> ($I2CX, PINS) {
+                    self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+                    (self.i2c, self.pins)
+                }
+
+                /// Checks and clears the bus error flags
+                fn check_errors(&self) -> Result<(), Error> {
+                    let isr = self.i2c.isr.read();
+
+                    if isr.berr().bit_is_set() {
+                        self.i2c.icr.write(|w| w.berrcf().set_bit());
+                        Err(Error::Bus)
+                    } else if isr.arlo().bit_is_set() {
+                        self.i2c.icr.write(|w| w.arlocf().set_bit());
+                        Err(Error::Arbitration)
+                    } else {
+                        Ok(())
+                    }
+                }
+            }
+
+            impl<PINS> Write for I2c<$I2CX, PINS> {
+                type Error = Error;
+
+                fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+                    self.write_bytes(addr, bytes, true)
+                }
+            }
+
+            impl<PINS> Read for I2c<$I2CX, PINS> {
+                type Error = Error;
+
+                fn read(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error> {
+                    self.read_bytes(addr, buffer)
+                }
+            }
+
+            impl<PINS> WriteRead for I2c<$I2CX, PINS> {
+                type Error = Error;
+
+                /// Writes `bytes`, then reads into `buffer` after a repeated START
+                ///
+                /// There is no STOP between the two phases:
+                ///
+                /// 1. CR2 = SADD, RD_WRN = 0, NBYTES, AUTOEND = 0, START. The write phase is
+                ///    continued through RELOAD / TCR if it is longer than 255 bytes, with AUTOEND
+                ///    kept clear.
+                /// 2. TXDR is written on each TXIS, until TC is set. SCL is then held low with
+                ///    the bus still owned, since AUTOEND was clear.
+                /// 3. CR2 = SADD, RD_WRN = 1, NBYTES, AUTOEND = 1, START. Setting START while TC
+                ///    is set sends the repeated START.
+                /// 4. RXDR is read on each RXNE, the hardware sends STOP after the last byte.
+                fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+                    self.write_bytes(addr, bytes, false)?;
+                    self.read_bytes(addr, buffer)
+                }
+            }
+        )+
+    }
+}
+
+hal! {
+    I2C1: (i2c1, i2c1en, i2c1clk),
+    I2C2: (i2c2, i2c2en, i2c2clk),
+    I2C3: (i2c3, i2c3en, i2c3clk),
+    I2C4: (i2c4, i2c4en, i2c4clk),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_write_of_write_read_ends_without_stop() {
+        assert_eq!(
+            chunk(2, false),
+            Chunk {
+                nbytes: 2,
+                reload: false,
+                autoend: false,
+            }
+        );
+    }
+
+    #[test]
+    fn long_write_of_write_read_never_sends_stop() {
+        // 300 bytes take a reload after the first 255
+        let first = chunk(300, false);
+        let second = chunk(300 - usize::from(first.nbytes), false);
+
+        assert_eq!(
+            first,
+            Chunk {
+                nbytes: 255,
+                reload: true,
+                autoend: false,
+            }
+        );
+        assert_eq!(
+            second,
+            Chunk {
+                nbytes: 45,
+                reload: false,
+                autoend: false,
+            }
+        );
+    }
+
+    #[test]
+    fn stop_is_only_sent_after_the_last_chunk() {
+        assert!(!chunk(256, true).autoend);
+        assert!(chunk(255, true).autoend);
+        assert!(chunk(1, true).autoend);
+    }
+
+    /// Returns the SCL frequency of `timing`, without the filter and synchronisation delays
+    fn scl_frequency(timing: Timing, i2cclk: u32) -> u32 {
+        let ticks = (u32::from(timing.scll) + 1) + (u32::from(timing.sclh) + 1);
+        i2cclk / ((u32::from(timing.presc) + 1) * ticks)
+    }
+
+    #[test]
+    fn standard_mode_timing() {
+        // PCLK1 at 216 MHz sysclk
+        assert_eq!(
+            timing(Mode::standard(100_000.hz()), 54_000_000),
+            Timing {
+                presc: 4,
+                scll: 53,
+                sclh: 53,
+                sdadel: 3,
+                scldel: 13,
+            }
+        );
+        // HSI kernel clock
+        assert_eq!(
+            timing(Mode::standard(100_000.hz()), 16_000_000),
+            Timing {
+                presc: 1,
+                scll: 39,
+                sclh: 39,
+                sdadel: 2,
+                scldel: 9,
+            }
+        );
+    }
+
+    #[test]
+    fn fast_mode_timing() {
+        assert_eq!(
+            timing(Mode::fast(400_000.hz()), 54_000_000),
+            Timing {
+                presc: 1,
+                scll: 44,
+                sclh: 22,
+                sdadel: 7,
+                scldel: 10,
+            }
+        );
+        let mode = Mode::Fast {
+            frequency: 400_000.hz(),
+            duty_cycle: DutyCycle::Ratio16to9,
+        };
+        assert_eq!(
+            timing(mode, 16_000_000),
+            Timing {
+                presc: 0,
+                scll: 24,
+                sclh: 14,
+                sdadel: 5,
+                scldel: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn scl_never_exceeds_requested_frequency() {
+        for &i2cclk in [16_000_000, 27_000_000, 54_000_000, 108_000_000].iter() {
+            for &frequency in [20_000, 50_000, 100_000].iter() {
+                let timing = timing(Mode::standard(frequency.hz()), i2cclk);
+                assert!(scl_frequency(timing, i2cclk) <= frequency);
+            }
+            // At 108 MHz, 120 kHz needs a larger prescaler for the low phase than the period
+            for &frequency in [100_000, 120_000, 200_000, 400_000].iter() {
+                let timing = timing(Mode::fast(frequency.hz()), i2cclk);
+                assert!(scl_frequency(timing, i2cclk) <= frequency);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn standard_mode_is_limited_to_100_khz() {
+        timing(Mode::standard(400_000.hz()), 54_000_000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn prescaler_has_to_fit_in_4_bits() {
+        // 216 MHz takes 21600 kernel clock cycles per 10 kHz period, even 16 * 2 * 256 is short
+        timing(Mode::standard(10_000.hz()), 216_000_000);
+    }
+}
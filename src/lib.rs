@@ -81,31 +81,86 @@ pub use stm32f7::stm32f7x9 as device;
 #[cfg(feature = "rt")]
 pub use crate::device::interrupt;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod adc;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod dcmi;
+
 #[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
 pub mod delay;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod dma;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod flash;
+
 // Remove unexpected module
 // #[cfg(feature = "doc")]
 // pub mod examples;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod fmt;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod fpu;
+
 #[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
 pub mod gpio;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod i2c;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod mpu;
+
+#[cfg(all(
+    feature = "panic-serial",
+    not(test),
+    any(feature = "stm32f746", feature = "stm32f767")
+))]
+pub mod panic_serial;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod port_dma;
+
 #[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
 pub mod prelude;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod pwm;
+
 #[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
 pub mod rcc;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod rtc;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod scheduler;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod serial;
+
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod servo;
+
 #[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
 pub mod time;
 
 #[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
 pub mod timer;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod watchdog;
+
 #[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
 pub mod signature;
 
+#[cfg(any(feature = "stm32f746", feature = "stm32f767"))]
+pub mod spi;
+
 pub mod state {
     /// Indicates that a peripheral is enabled
     pub struct Enabled;
@@ -0,0 +1,137 @@
+//! Memory Protection Unit
+//!
+//! The Cortex-M7 data cache doesn't see writes done by DMA, and DMA doesn't see data still held
+//! in the cache. Instead of cleaning / invalidating the cache around every transfer, DMA buffers
+//! can be placed in a RAM region that the MPU marks as non-cacheable.
+
+use cortex_m::asm;
+use cortex_m::peripheral::MPU;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_B: u32 = 1 << 16;
+const RASR_S: u32 = 1 << 18;
+const RASR_TEX_001: u32 = 0b001 << 19;
+const RASR_AP_FULL_ACCESS: u32 = 0b011 << 24;
+const RASR_XN: u32 = 1 << 28;
+
+/// Memory type of a region
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryType {
+    /// Shareable normal memory that is never cached (TEX = 0b001, C = 0, B = 0)
+    NormalNonCacheable,
+    /// Shareable device memory (TEX = 0b000, C = 0, B = 1)
+    ///
+    /// Accesses are neither cached nor merged, which also makes unaligned accesses fault.
+    Device,
+}
+
+/// MPU configuration error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The region number is not implemented by this MPU
+    InvalidRegion,
+    /// The size is not a power of two of at least 32 bytes
+    InvalidSize,
+    /// The base address is not aligned to the region size
+    UnalignedBase,
+}
+
+/// Memory Protection Unit
+pub struct Mpu {
+    mpu: MPU,
+}
+
+impl Mpu {
+    /// Takes control of the MPU
+    pub fn new(mpu: MPU) -> Self {
+        Mpu { mpu }
+    }
+
+    /// Returns the number of regions supported by the MPU
+    pub fn regions(&self) -> u8 {
+        ((self.mpu._type.read() >> 8) & 0xff) as u8
+    }
+
+    /// Configures `region` to cover `size` bytes starting at `base` with the given memory type
+    ///
+    /// `size` must be a power of two of at least 32 bytes and `base` must be aligned to it.
+    /// The region allows full read / write access and is not executable. If regions overlap,
+    /// the one with the higher number takes precedence.
+    ///
+    /// The MPU is disabled while the region is written and then left enabled, with the default
+    /// memory map kept as background region, so memory outside of all regions behaves as before.
+    /// Cache lines holding data of the region are not touched: configure the region before
+    /// the D-cache gets enabled, or clean and invalidate it afterwards.
+    pub fn configure_region(
+        &mut self,
+        region: u8,
+        base: u32,
+        size: u32,
+        memory: MemoryType,
+    ) -> Result<(), Error> {
+        if region >= self.regions() {
+            return Err(Error::InvalidRegion);
+        }
+        if size < 32 || !size.is_power_of_two() {
+            return Err(Error::InvalidSize);
+        }
+        if base & (size - 1) != 0 {
+            return Err(Error::UnalignedBase);
+        }
+
+        // Region size is 2^(SIZE + 1) bytes
+        let size_bits = (size.trailing_zeros() - 1) << 1;
+        let attributes = match memory {
+            MemoryType::NormalNonCacheable => RASR_TEX_001 | RASR_S,
+            MemoryType::Device => RASR_B | RASR_S,
+        };
+
+        // Make sure all outstanding memory accesses are done before changing the map
+        asm::dmb();
+        unsafe {
+            self.mpu.ctrl.write(0);
+            self.mpu.rnr.write(u32::from(region));
+            self.mpu.rbar.write(base);
+            self.mpu
+                .rasr
+                .write(RASR_XN | RASR_AP_FULL_ACCESS | attributes | size_bits | RASR_ENABLE);
+        }
+        self.enable();
+
+        Ok(())
+    }
+
+    /// Marks `size` bytes starting at `base` as normal non-cacheable memory, for DMA buffers
+    ///
+    /// Uses the highest numbered region, so the setting overrides other regions covering
+    /// the same memory. See [`configure_region`](Mpu::configure_region) for the requirements on
+    /// `base` and `size`.
+    pub fn configure_dma_region(&mut self, base: u32, size: u32) -> Result<(), Error> {
+        let region = self.regions().checked_sub(1).ok_or(Error::InvalidRegion)?;
+        self.configure_region(region, base, size, MemoryType::NormalNonCacheable)
+    }
+
+    /// Enables the MPU, with the default memory map as background region
+    pub fn enable(&mut self) {
+        unsafe { self.mpu.ctrl.write(CTRL_PRIVDEFENA | CTRL_ENABLE) };
+        // Make sure the new memory map is used by all following accesses and instructions
+        asm::dsb();
+        asm::isb();
+    }
+
+    /// Disables the MPU
+    pub fn disable(&mut self) {
+        asm::dmb();
+        unsafe { self.mpu.ctrl.write(0) };
+        asm::dsb();
+        asm::isb();
+    }
+
+    /// Releases the MPU peripheral
+    pub fn free(self) -> MPU {
+        self.mpu
+    }
+}
@@ -0,0 +1,88 @@
+//! Panic handler that prints the panic message over a serial port
+//!
+//! Enabled with the `panic-serial` feature. A program can only have one `#[panic_handler]`, so
+//! the feature conflicts with `panic-halt`, `panic-semihosting` and any other panic crate.
+//!
+//! ```ignore
+//! let (tx, _rx) = serial.split();
+//! panic_serial::set_output(tx, AfterPanic::Reset);
+//! ```
+//!
+//! The handler disables interrupts, writes the message and location with blocking writes, waits
+//! until the last byte went out, and then halts or resets. A panic before [`set_output`] is
+//! called, or while writing the message, goes straight to halting or resetting.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{self, AtomicBool, Ordering};
+
+use cortex_m::interrupt;
+use cortex_m::peripheral::SCB;
+
+use crate::hal::serial;
+use crate::serial::Tx;
+
+/// What the panic handler does once the message is written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AfterPanic {
+    /// Spin forever, so a debugger can inspect the state
+    Halt,
+    /// Reset the device through the SCB
+    Reset,
+}
+
+/// Writer of the panic message, monomorphized for the USART passed to `set_output`
+static mut OUTPUT: Option<fn(&PanicInfo)> = None;
+static mut AFTER_PANIC: AfterPanic = AfterPanic::Halt;
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// Makes `tx` the output of the panic handler
+///
+/// The transmitter is kept for good, the handler takes it over without looking at what the
+/// program was sending. A DMA transfer still running on it can interleave with the message.
+pub fn set_output<USART>(tx: Tx<USART>, after_panic: AfterPanic)
+where
+    Tx<USART>: serial::Write<u8> + Write,
+{
+    // The handler conjures up its own `Tx`, this one must not be used anymore
+    let _ = tx;
+
+    // NOTE(unsafe) the statics are only read by the panic handler, with interrupts disabled
+    interrupt::free(|_| unsafe {
+        OUTPUT = Some(write_panic::<USART>);
+        AFTER_PANIC = after_panic;
+    });
+}
+
+fn write_panic<USART>(info: &PanicInfo)
+where
+    Tx<USART>: serial::Write<u8> + Write,
+{
+    // NOTE(unsafe) `set_output` gave up the only other instance
+    let mut tx: Tx<USART> = unsafe { Tx::steal() };
+
+    let _ = writeln!(tx, "\r\n{}\r", info);
+    let _ = nb::block!(serial::Write::flush(&mut tx));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    interrupt::disable();
+
+    // NOTE(unsafe) interrupts are disabled, nothing else accesses the statics
+    let (output, after_panic) = unsafe { (OUTPUT, AFTER_PANIC) };
+
+    // A panic while formatting the message must not recurse
+    if !PANICKED.swap(true, Ordering::Relaxed) {
+        if let Some(output) = output {
+            output(info);
+        }
+    }
+
+    match after_panic {
+        AfterPanic::Reset => SCB::sys_reset(),
+        AfterPanic::Halt => loop {
+            atomic::compiler_fence(Ordering::SeqCst);
+        },
+    }
+}
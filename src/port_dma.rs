@@ -0,0 +1,201 @@
+//! Timer paced DMA writes to a GPIO port
+//!
+//! An advanced timer requests a DMA transfer on every update event, and the stream writes the
+//! next word of a buffer into the BSRR register of a port. The pins change at a fixed rate,
+//! without the jitter of software bit-banging, e.g. for WS2812 LEDs or a parallel bus.
+//!
+//! ```ignore
+//! let port = gpiob::PortMask::new([
+//!     gpiob.pb0.into_push_pull_output().downgrade(),
+//!     gpiob.pb1.into_push_pull_output().downgrade(),
+//! ]);
+//!
+//! static mut STEPS: [u32; 4] = [0; 4];
+//! let steps = unsafe { &mut STEPS };
+//! for (step, value) in steps.iter_mut().zip([0b00, 0b01, 0b11, 0b10]) {
+//!     *step = port.bsrr_word(value);
+//! }
+//!
+//! let streams = dp.DMA2.split(&mut rcc.ahb1);
+//! let writer = PortWriter::tim8(dp.TIM8, port, streams.1, 1.mhz(), clocks, &mut rcc.apb2);
+//! let transfer = writer.write(steps).map_err(|(error, ..)| error).unwrap();
+//! let (_, writer) = transfer.wait().map_err(|(error, ..)| error).unwrap();
+//! ```
+//!
+//! # Buffer format
+//!
+//! One `u32` per time step, written as is into BSRR: bits 0 - 15 set pins, bits 16 - 31 reset
+//! them and pins with neither bit keep their level. [`BsrrPort::bsrr_word`] builds the word of
+//! a port value. The first step is written one period after [`PortWriter::write`], and the
+//! pins keep the last value once the buffer is done.
+//!
+//! # Update rate
+//!
+//! The timer counts on the APB2 timer clock, so rates up to half of `Clocks::timclk2` can be
+//! programmed, and [`PortWriter::rate`] returns the one actually reached. Only DMA2 can
+//! reach the GPIO ports, and every step costs it an access over the AHB bus matrix. At a
+//! few MHz a step comes too fast for the stream, which then falls behind the timer and
+//! stretches the following steps without reporting an error. Other DMA2 streams and
+//! CPU accesses to AHB1 lower that limit further.
+
+use crate::device::{DMA2, TIM1, TIM8};
+use crate::dma::{self, DmaStream, Stream, Transfer};
+use crate::gpio::BsrrPort;
+use crate::rcc::{Clocks, Reset, APB2};
+use crate::time::Hertz;
+
+/// DMA stream and channel of the update request of a timer
+pub trait UpdateStream<TIM>: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+macro_rules! update_streams {
+    ($($TIM:ident: [$($DMA:ident: ($stream:expr, $channel:expr)),+],)+) => {
+        $(
+            $(
+                impl UpdateStream<$TIM> for Stream<$DMA, $stream> {
+                    const CHANNEL: u8 = $channel;
+                }
+            )+
+        )+
+    }
+}
+
+update_streams! {
+    TIM1: [DMA2: (5, 6)],
+    TIM8: [DMA2: (1, 7)],
+}
+
+/// Returns the prescaler and auto-reload values of the update rate closest to `rate`
+///
+/// The rate is clamped to the range the timer can reach, a period is at least 2 ticks.
+fn prescalers(timclk: u32, rate: u32) -> (u16, u16) {
+    let ticks = timclk.checked_div(rate).unwrap_or(u32::MAX).max(2);
+    let psc = (ticks - 1) / (1 << 16);
+    let arr = ticks / (psc + 1) - 1;
+
+    (psc as u16, arr as u16)
+}
+
+/// Writes buffers into the BSRR register of `PORT`, one word per update event of `TIM`
+pub struct PortWriter<TIM, PORT, STREAM> {
+    tim: TIM,
+    port: PORT,
+    stream: STREAM,
+    rate: Hertz,
+}
+
+macro_rules! hal {
+    ($($TIM:ident: ($tim:ident, $timXen:ident),)+) => {
+        $(
+            impl<PORT, STREAM> PortWriter<$TIM, PORT, STREAM>
+            where
+                PORT: BsrrPort,
+                STREAM: UpdateStream<$TIM>,
+            {
+                /// Sets up the timer to request a DMA transfer at `rate`
+                ///
+                /// `rate` is clamped to the rates the timer can reach.
+                pub fn $tim(
+                    mut tim: $TIM,
+                    port: PORT,
+                    stream: STREAM,
+                    rate: Hertz,
+                    clocks: Clocks,
+                    apb2: &mut APB2,
+                ) -> Self {
+                    // reset and enable peripheral to a clean slate state
+                    tim.reset(apb2);
+                    apb2.enr().modify(|_, w| w.$timXen().set_bit());
+
+                    let timclk = clocks.timclk2().0;
+                    let (psc, arr) = prescalers(timclk, rate.0);
+                    tim.psc.write(|w| w.psc().bits(psc));
+                    tim.arr.write(|w| w.arr().bits(arr));
+                    // Load the prescaler, before the DMA request is enabled so the update
+                    // event doesn't trigger it
+                    tim.egr.write(|w| w.ug().set_bit());
+                    tim.sr.modify(|_, w| w.uif().clear_bit());
+
+                    PortWriter {
+                        tim,
+                        port,
+                        stream,
+                        rate: Hertz(timclk / ((u32::from(psc) + 1) * (u32::from(arr) + 1))),
+                    }
+                }
+
+                /// Returns the update rate the timer reached
+                pub fn rate(&self) -> Hertz {
+                    self.rate
+                }
+
+                /// Starts writing `buffer` into BSRR, one word per period
+                ///
+                /// See the [module documentation](self) for the format of the buffer.
+                #[allow(clippy::type_complexity)]
+                pub fn write(
+                    self,
+                    buffer: &'static [u32],
+                ) -> Result<Transfer<Self, &'static [u32]>, (dma::Error, &'static [u32], Self)> {
+                    // Stop the timer of a previous transfer, so the first step takes a full
+                    // period as well
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim.dier.modify(|_, w| w.ude().clear_bit());
+                    self.tim.cnt.write(|w| w.cnt().bits(0));
+
+                    let stream = self.stream.handle();
+                    let started = stream.start(dma::Config {
+                        channel: STREAM::CHANNEL,
+                        direction: dma::Direction::MemoryToPeripheral,
+                        width: dma::Width::Word,
+                        peripheral: PORT::bsrr_address(),
+                        memory0: buffer.as_ptr() as u32,
+                        memory1: None,
+                        len: buffer.len(),
+                    });
+                    if let Err(error) = started {
+                        return Err((error, buffer, self));
+                    }
+
+                    self.tim.dier.modify(|_, w| w.ude().set_bit());
+                    self.tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    Ok(Transfer::new(self, buffer, stream))
+                }
+
+                /// Stops the timer and releases it, the port and the stream
+                pub fn free(self) -> ($TIM, PORT, STREAM) {
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim.dier.modify(|_, w| w.ude().clear_bit());
+
+                    (self.tim, self.port, self.stream)
+                }
+            }
+        )+
+    }
+}
+
+hal! {
+    TIM1: (tim1, tim1en),
+    TIM8: (tim8, tim8en),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescalers_reach_rate() {
+        // 216 MHz timer clock, 800 kHz WS2812 bit rate
+        assert_eq!(prescalers(216_000_000, 800_000), (0, 269));
+        assert_eq!(prescalers(216_000_000, 1_000), (3, 53_999));
+    }
+
+    #[test]
+    fn prescalers_clamp_rate() {
+        assert_eq!(prescalers(216_000_000, 216_000_000), (0, 1));
+        assert_eq!(prescalers(216_000_000, 0), (0xffff, 0xfffe));
+    }
+}
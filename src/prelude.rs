@@ -1,5 +1,8 @@
+pub use crate::dma::DmaExt as _stm32f7xx_hal_dma_DmaExt;
+pub use crate::flash::FlashExt as _stm32f7xx_hal_flash_FlashExt;
 pub use crate::gpio::GpioExt as _stm327xx_hal_gpio_GpioExt;
 pub use crate::hal::digital::v2::{InputPin, OutputPin};
 pub use crate::hal::prelude::*;
+pub use crate::pwm::PwmExt as _stm32f7xx_hal_pwm_PwmExt;
 pub use crate::rcc::RccExt as _stm32f7xx_hal_rcc_RccExt;
 pub use crate::time::U32Ext as _stm327xx_hal_time_U32Ext;
@@ -0,0 +1,419 @@
+//! Pulse Width Modulation (PWM) output on the general purpose timers
+//!
+//! ```ignore
+//! let ch1 = gpioa.pa6.into_alternate_af2();
+//! let ch2 = gpioa.pa7.into_alternate_af2();
+//!
+//! let (pwm, (mut ch1, mut ch2)) = dp.TIM3.pwm((ch1, ch2), 10.khz(), clocks, &mut rcc.apb1);
+//! ch1.set_duty(ch1.get_max_duty() / 2);
+//! ch1.enable();
+//! ```
+//!
+//! The channels share the period of their timer, held by the [`Pwm`]. Each pin has to be
+//! driven by another channel of the same timer, or the call doesn't compile.
+//!
+//! # Polarity and idle state
+//!
+//! A channel drives its pin high during the duty cycle, [`PwmChannel::set_polarity`] inverts
+//! that for active-low loads. The level of a disabled channel can't be configured on these
+//! timers, so loads that must not see a glitch, e.g. gate drivers, need an external pull to
+//! their inactive level, kept while the timer is reset and set up. The configurable idle levels
+//! (`CR2.OISx`) and the break input only exist on the advanced timers TIM1 and TIM8, which
+//! this module doesn't drive.
+
+use core::marker::PhantomData;
+
+use crate::device::{TIM2, TIM3, TIM4, TIM5};
+use crate::gpio::gpioa::{PA0, PA1, PA15, PA2, PA3, PA5, PA6, PA7};
+use crate::gpio::gpiob::{PB0, PB1, PB10, PB11, PB3, PB4, PB5, PB6, PB7, PB8, PB9};
+use crate::gpio::gpioc::{PC6, PC7, PC8, PC9};
+use crate::gpio::gpiod::{PD12, PD13, PD14, PD15};
+use crate::gpio::gpioh::{PH10, PH11, PH12};
+use crate::gpio::gpioi::PI0;
+use crate::gpio::{Alternate, AF1, AF2};
+use crate::hal::PwmPin;
+use crate::rcc::{Clocks, Reset, APB1};
+use crate::time::Hertz;
+use cast::u32;
+
+/// Channel 1 (type state)
+pub struct C1;
+/// Channel 2 (type state)
+pub struct C2;
+/// Channel 3 (type state)
+pub struct C3;
+/// Channel 4 (type state)
+pub struct C4;
+
+/// Pins usable as the channel 1 output of a timer
+pub trait PinC1<TIM> {}
+/// Pins usable as the channel 2 output of a timer
+pub trait PinC2<TIM> {}
+/// Pins usable as the channel 3 output of a timer
+pub trait PinC3<TIM> {}
+/// Pins usable as the channel 4 output of a timer
+pub trait PinC4<TIM> {}
+
+mod sealed {
+    /// Keeps channels from being created outside of this module
+    pub struct Token(pub(super) ());
+}
+
+/// A pin or a tuple of pins, each driven by a different channel of the timer
+///
+/// `CHANNELS` lists the channels of the pins, e.g. `(C1, C3)`, and only serves to tell the
+/// implementations apart.
+pub trait Pins<TIM, CHANNELS> {
+    #[doc(hidden)]
+    const C1: bool = false;
+    #[doc(hidden)]
+    const C2: bool = false;
+    #[doc(hidden)]
+    const C3: bool = false;
+    #[doc(hidden)]
+    const C4: bool = false;
+
+    /// One channel handle per pin
+    type Channels;
+
+    #[doc(hidden)]
+    fn channels(token: sealed::Token) -> Self::Channels;
+}
+
+macro_rules! pins_impl {
+    ($(($($PINX:ident: $TRAIT:ident, $CX:ident);+),)+) => {
+        $(
+            #[allow(unused_parens)]
+            impl<TIM, $($PINX,)+> Pins<TIM, ($($CX),+)> for ($($PINX),+)
+            where
+                $($PINX: $TRAIT<TIM>,)+
+            {
+                $(const $CX: bool = true;)+
+
+                type Channels = ($(PwmChannel<TIM, $CX>),+);
+
+                fn channels(_token: sealed::Token) -> Self::Channels {
+                    ($(PwmChannel::<TIM, $CX> { _tim: PhantomData, _channel: PhantomData }),+)
+                }
+            }
+        )+
+    }
+}
+
+pins_impl! {
+    (P1: PinC1, C1; P2: PinC2, C2; P3: PinC3, C3; P4: PinC4, C4),
+    (P2: PinC2, C2; P3: PinC3, C3; P4: PinC4, C4),
+    (P1: PinC1, C1; P3: PinC3, C3; P4: PinC4, C4),
+    (P1: PinC1, C1; P2: PinC2, C2; P4: PinC4, C4),
+    (P1: PinC1, C1; P2: PinC2, C2; P3: PinC3, C3),
+    (P3: PinC3, C3; P4: PinC4, C4),
+    (P2: PinC2, C2; P4: PinC4, C4),
+    (P2: PinC2, C2; P3: PinC3, C3),
+    (P1: PinC1, C1; P4: PinC4, C4),
+    (P1: PinC1, C1; P3: PinC3, C3),
+    (P1: PinC1, C1; P2: PinC2, C2),
+    (P1: PinC1, C1),
+    (P2: PinC2, C2),
+    (P3: PinC3, C3),
+    (P4: PinC4, C4),
+}
+
+macro_rules! channel_pins {
+    ($($TIM:ident: ($TRAIT:ident, [$($PIN:ident<$AF:ident>),+]),)+) => {
+        $($(
+            impl $TRAIT<$TIM> for $PIN<Alternate<$AF>> {}
+        )+)+
+    }
+}
+
+channel_pins! {
+    TIM2: (PinC1, [PA0<AF1>, PA5<AF1>, PA15<AF1>]),
+    TIM2: (PinC2, [PA1<AF1>, PB3<AF1>]),
+    TIM2: (PinC3, [PA2<AF1>, PB10<AF1>]),
+    TIM2: (PinC4, [PA3<AF1>, PB11<AF1>]),
+
+    TIM3: (PinC1, [PA6<AF2>, PB4<AF2>, PC6<AF2>]),
+    TIM3: (PinC2, [PA7<AF2>, PB5<AF2>, PC7<AF2>]),
+    TIM3: (PinC3, [PB0<AF2>, PC8<AF2>]),
+    TIM3: (PinC4, [PB1<AF2>, PC9<AF2>]),
+
+    TIM4: (PinC1, [PB6<AF2>, PD12<AF2>]),
+    TIM4: (PinC2, [PB7<AF2>, PD13<AF2>]),
+    TIM4: (PinC3, [PB8<AF2>, PD14<AF2>]),
+    TIM4: (PinC4, [PB9<AF2>, PD15<AF2>]),
+
+    TIM5: (PinC1, [PA0<AF2>, PH10<AF2>]),
+    TIM5: (PinC2, [PA1<AF2>, PH11<AF2>]),
+    TIM5: (PinC3, [PA2<AF2>, PH12<AF2>]),
+    TIM5: (PinC4, [PA3<AF2>, PI0<AF2>]),
+}
+
+/// Level of a channel output during the duty cycle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Polarity {
+    /// High during the duty cycle, low for the rest of the period
+    ActiveHigh,
+    /// Low during the duty cycle, high for the rest of the period
+    ActiveLow,
+}
+
+/// PWM output channel of a timer
+pub struct PwmChannel<TIM, CHANNEL> {
+    _tim: PhantomData<TIM>,
+    _channel: PhantomData<CHANNEL>,
+}
+
+/// Timer outputting PWM on `PINS`, holding the period its channels share
+pub struct Pwm<TIM, PINS> {
+    tim: TIM,
+    pins: PINS,
+    clocks: Clocks,
+}
+
+/// Returns the prescaler and auto-reload values of the PWM frequency closest to `freq`
+///
+/// The auto-reload stays below 0xffff, so the max duty (ARR + 1) fits in 16 bits. The frequency
+/// is clamped to the range the timer can reach, a period is at least 2 ticks.
+fn prescalers(timclk: u32, freq: u32) -> (u16, u16) {
+    let ticks = timclk
+        .checked_div(freq)
+        .unwrap_or(u32::MAX)
+        .clamp(2, 0x1_0000 * 0xffff);
+    let psc = (ticks - 1) / 0xffff;
+    let arr = ticks / (psc + 1) - 1;
+
+    (psc as u16, arr as u16)
+}
+
+/// Extension trait to output PWM with a timer
+pub trait PwmExt: Sized {
+    /// Configures the timer to output PWM at `freq` on `pins`
+    ///
+    /// Returns the timer and one channel per pin, see [`tim2`] for the details.
+    fn pwm<CHANNELS, PINS, F>(
+        self,
+        pins: PINS,
+        freq: F,
+        clocks: Clocks,
+        apb: &mut APB1,
+    ) -> (Pwm<Self, PINS>, PINS::Channels)
+    where
+        PINS: Pins<Self, CHANNELS>,
+        F: Into<Hertz>;
+}
+
+macro_rules! hal {
+    ($($TIM:ident: ($tim:ident, $timXen:ident),)+) => {
+        $(
+            /// Configures a timer to output PWM at `freq` on `pins`, returning their channels
+            ///
+            /// The channels start disabled with a duty cycle of 0. The counter is 16 bits wide
+            /// at most here, so `get_max_duty` is the number of timer clock cycles per period
+            /// and shrinks as `freq` grows.
+            pub fn $tim<CHANNELS, PINS, F>(
+                mut tim: $TIM,
+                pins: PINS,
+                freq: F,
+                clocks: Clocks,
+                apb: &mut APB1,
+            ) -> (Pwm<$TIM, PINS>, PINS::Channels)
+            where
+                PINS: Pins<$TIM, CHANNELS>,
+                F: Into<Hertz>,
+            {
+                // reset and enable peripheral to a clean slate state
+                tim.reset(apb);
+                apb.enr().modify(|_, w| w.$timXen().set_bit());
+
+                // PWM mode 1 with preloaded compare values, so duty changes apply at the end of
+                // a period
+                if PINS::C1 {
+                    tim.ccmr1_output()
+                        .modify(|_, w| w.oc1pe().set_bit().oc1m().pwm_mode1());
+                }
+                if PINS::C2 {
+                    tim.ccmr1_output()
+                        .modify(|_, w| w.oc2pe().set_bit().oc2m().pwm_mode1());
+                }
+                if PINS::C3 {
+                    tim.ccmr2_output()
+                        .modify(|_, w| w.oc3pe().set_bit().oc3m().pwm_mode1());
+                }
+                if PINS::C4 {
+                    tim.ccmr2_output()
+                        .modify(|_, w| w.oc4pe().set_bit().oc4m().pwm_mode1());
+                }
+                tim.cr1.write(|w| w.arpe().set_bit());
+
+                let mut pwm = Pwm { tim, pins, clocks };
+                pwm.set_frequency(freq);
+                pwm.tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                (pwm, PINS::channels(sealed::Token(())))
+            }
+
+            impl PwmExt for $TIM {
+                fn pwm<CHANNELS, PINS, F>(
+                    self,
+                    pins: PINS,
+                    freq: F,
+                    clocks: Clocks,
+                    apb: &mut APB1,
+                ) -> (Pwm<$TIM, PINS>, PINS::Channels)
+                where
+                    PINS: Pins<$TIM, CHANNELS>,
+                    F: Into<Hertz>,
+                {
+                    $tim(self, pins, freq, clocks, apb)
+                }
+            }
+
+            impl<PINS> Pwm<$TIM, PINS> {
+                /// Changes the PWM frequency of all channels
+                ///
+                /// The duty cycles are kept in timer clock cycles, so they have to be set again
+                /// against the new `get_max_duty`. `freq` is clamped to the frequencies the
+                /// timer can reach, from half the timer clock down to the slowest prescaler.
+                pub fn set_frequency<F>(&mut self, freq: F)
+                where
+                    F: Into<Hertz>,
+                {
+                    let (psc, arr) = prescalers(self.clocks.timclk1().0, freq.into().0);
+
+                    self.tim.psc.write(|w| w.psc().bits(psc));
+                    self.tim.arr.write(|w| unsafe { w.bits(u32(arr)) });
+
+                    // Load the prescaler and auto-reload right away
+                    self.tim.egr.write(|w| w.ug().set_bit());
+                }
+
+                /// Returns the duty cycle of a full period, shared by all channels
+                pub fn get_max_duty(&self) -> u16 {
+                    self.tim.arr.read().bits() as u16 + 1
+                }
+
+                /// Stops the timer and releases it together with the pins
+                ///
+                /// Takes back the channels returned with the `Pwm`, so none can drive the timer
+                /// after it's released.
+                pub fn free<CHANNELS>(self, _channels: PINS::Channels) -> ($TIM, PINS)
+                where
+                    PINS: Pins<$TIM, CHANNELS>,
+                {
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim.ccer.reset();
+
+                    (self.tim, self.pins)
+                }
+            }
+        )+
+    }
+}
+
+hal! {
+    TIM2: (tim2, tim2en),
+    TIM3: (tim3, tim3en),
+    TIM4: (tim4, tim4en),
+    TIM5: (tim5, tim5en),
+}
+
+macro_rules! pwm_channel {
+    ($($TIM:ident: ($CX:ident, $ccrX:ident, $ccXe:ident, $ccXp:ident),)+) => {
+        $(
+            impl PwmChannel<$TIM, $CX> {
+                /// Sets the level of the output during the duty cycle
+                ///
+                /// Applies right away, also in the middle of a period.
+                pub fn set_polarity(&mut self, polarity: Polarity) {
+                    // NOTE(unsafe) atomic read-modify-write of a bit only this channel owns
+                    unsafe {
+                        (*$TIM::ptr())
+                            .ccer
+                            .modify(|_, w| w.$ccXp().bit(polarity == Polarity::ActiveLow))
+                    }
+                }
+            }
+
+            impl PwmPin for PwmChannel<$TIM, $CX> {
+                type Duty = u16;
+
+                fn disable(&mut self) {
+                    // NOTE(unsafe) atomic read-modify-write of a bit only this channel owns
+                    unsafe { (*$TIM::ptr()).ccer.modify(|_, w| w.$ccXe().clear_bit()) }
+                }
+
+                fn enable(&mut self) {
+                    // NOTE(unsafe) atomic read-modify-write of a bit only this channel owns
+                    unsafe { (*$TIM::ptr()).ccer.modify(|_, w| w.$ccXe().set_bit()) }
+                }
+
+                fn get_duty(&self) -> u16 {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$TIM::ptr()).$ccrX.read().bits() as u16 }
+                }
+
+                fn get_max_duty(&self) -> u16 {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$TIM::ptr()).arr.read().bits() as u16 + 1 }
+                }
+
+                fn set_duty(&mut self, duty: u16) {
+                    // NOTE(unsafe) atomic write to a register only this channel owns
+                    unsafe { (*$TIM::ptr()).$ccrX.write(|w| w.bits(u32(duty))) }
+                }
+            }
+        )+
+    }
+}
+
+pwm_channel! {
+    TIM2: (C1, ccr1, cc1e, cc1p),
+    TIM2: (C2, ccr2, cc2e, cc2p),
+    TIM2: (C3, ccr3, cc3e, cc3p),
+    TIM2: (C4, ccr4, cc4e, cc4p),
+
+    TIM3: (C1, ccr1, cc1e, cc1p),
+    TIM3: (C2, ccr2, cc2e, cc2p),
+    TIM3: (C3, ccr3, cc3e, cc3p),
+    TIM3: (C4, ccr4, cc4e, cc4p),
+
+    TIM4: (C1, ccr1, cc1e, cc1p),
+    TIM4: (C2, ccr2, cc2e, cc2p),
+    TIM4: (C3, ccr3, cc3e, cc3p),
+    TIM4: (C4, ccr4, cc4e, cc4p),
+
+    TIM5: (C1, ccr1, cc1e, cc1p),
+    TIM5: (C2, ccr2, cc2e, cc2p),
+    TIM5: (C3, ccr3, cc3e, cc3p),
+    TIM5: (C4, ccr4, cc4e, cc4p),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescalers_reach_frequency() {
+        // 108 MHz APB1 timer clock at 216 MHz sysclk
+        assert_eq!(prescalers(108_000_000, 10_000), (0, 10_799));
+        assert_eq!(prescalers(108_000_000, 1_000), (1, 53_999));
+        assert_eq!(prescalers(108_000_000, 50), (32, 65_453));
+    }
+
+    #[test]
+    fn max_duty_fits_in_16_bits() {
+        for &freq in [1, 10, 1_647, 1_648, 100_000].iter() {
+            let (_, arr) = prescalers(108_000_000, freq);
+            assert!(arr < 0xffff, "{} Hz", freq);
+        }
+    }
+
+    #[test]
+    fn prescalers_clamp_frequency() {
+        // Above the timer clock, where the period would be less than a tick
+        assert_eq!(prescalers(108_000_000, 200_000_000), (0, 1));
+        assert_eq!(prescalers(108_000_000, 108_000_000), (0, 1));
+        // No frequency at all takes the slowest one
+        assert_eq!(prescalers(108_000_000, 0), (0xffff, 0xfffe));
+    }
+}
@@ -1,4 +1,15 @@
-use crate::device::{rcc, FLASH, RCC};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::time::Duration;
+
+use cortex_m::interrupt;
+use cortex_m::peripheral::DWT;
+
+use crate::device::{rcc, ADC1, DCMI, DMA1, DMA2, FLASH, RCC};
+use crate::device::{I2C1, I2C2, I2C3, I2C4, SPI1, SPI2, SPI3, SPI4, SPI5, SPI6};
+use crate::device::{TIM1, TIM2, TIM3, TIM4, TIM5, TIM8};
+use crate::device::{USART1, USART2, USART3, USART6};
 
 use crate::time::Hertz;
 
@@ -12,14 +23,11 @@ impl RccExt for RCC {
     fn constrain(self) -> Rcc {
         Rcc {
             ahb1: AHB1(()),
+            ahb2: AHB2(()),
             apb1: APB1 { _0: () },
             apb2: APB2 { _0: () },
-            cfgr: CFGR {
-                hclk: None,
-                pclk1: None,
-                pclk2: None,
-                sysclk: None,
-            },
+            bdcr: BDCR { _0: () },
+            cfgr: CFGR::new(),
         }
     }
 }
@@ -28,11 +36,15 @@ impl RccExt for RCC {
 pub struct Rcc {
     /// Advanced High-Performance Bus 1 (AHB1) registers
     pub ahb1: AHB1,
+    /// Advanced High-Performance Bus 2 (AHB2) registers
+    pub ahb2: AHB2,
 
     /// Advanced Peripheral Bus 1 (APB1) registers
     pub apb1: APB1,
     /// Advanced Peripheral Bus 2 (APB2) registers
     pub apb2: APB2,
+    /// RTC domain control register
+    pub bdcr: BDCR,
     pub cfgr: CFGR,
 }
 
@@ -51,6 +63,21 @@ impl AHB1 {
     }
 }
 
+/// Advanced High-Performance Bus 2 (AHB2) registers
+pub struct AHB2(());
+
+impl AHB2 {
+    pub(crate) fn enr(&mut self) -> &rcc::AHB2ENR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).ahb2enr }
+    }
+
+    pub(crate) fn rstr(&mut self) -> &rcc::AHB2RSTR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).ahb2rstr }
+    }
+}
+
 /// Advanced Peripheral Bus 1 (APB1) registers
 pub struct APB1 {
     _0: (),
@@ -85,16 +112,342 @@ impl APB2 {
     }
 }
 
+/// RTC domain control register
+pub struct BDCR {
+    _0: (),
+}
+
+impl BDCR {
+    pub(crate) fn bdcr(&mut self) -> &rcc::BDCR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).bdcr }
+    }
+}
+
+/// Peripherals that can be reset through the RCC
+///
+/// The drivers reset their peripheral before enabling its clock, so a soft reset doesn't leave
+/// it in the configuration of the previous run.
+pub trait Reset {
+    /// Bus proxy holding the reset register of the peripheral
+    type Bus;
+
+    /// Pulses the reset line of the peripheral, returning all of its registers to their reset
+    /// values
+    fn reset(&mut self, bus: &mut Self::Bus);
+}
+
+macro_rules! reset {
+    ($($PER:ident: ($bus:ident, $perXrst:ident),)+) => {
+        $(
+            impl Reset for $PER {
+                type Bus = $bus;
+
+                fn reset(&mut self, bus: &mut $bus) {
+                    bus.rstr().modify(|_, w| w.$perXrst().set_bit());
+                    // Hold the reset for a couple of bus cycles before releasing it
+                    let _ = bus.rstr().read();
+                    let _ = bus.rstr().read();
+                    bus.rstr().modify(|_, w| w.$perXrst().clear_bit());
+                }
+            }
+        )+
+    }
+}
+
+reset! {
+    ADC1: (APB2, adcrst),
+
+    DCMI: (AHB2, dcmirst),
+
+    DMA1: (AHB1, dma1rst),
+    DMA2: (AHB1, dma2rst),
+
+    I2C1: (APB1, i2c1rst),
+    I2C2: (APB1, i2c2rst),
+    I2C3: (APB1, i2c3rst),
+    I2C4: (APB1, i2c4rst),
+
+    SPI1: (APB2, spi1rst),
+    SPI2: (APB1, spi2rst),
+    SPI3: (APB1, spi3rst),
+    SPI4: (APB2, spi4rst),
+    SPI5: (APB2, spi5rst),
+    SPI6: (APB2, spi6rst),
+
+    TIM1: (APB2, tim1rst),
+    TIM2: (APB1, tim2rst),
+    TIM3: (APB1, tim3rst),
+    TIM4: (APB1, tim4rst),
+    TIM5: (APB1, tim5rst),
+    TIM8: (APB2, tim8rst),
+
+    USART1: (APB2, usart1rst),
+    USART2: (APB1, uart2rst),
+    USART3: (APB1, uart3rst),
+    USART6: (APB2, usart6rst),
+}
+
+/// Peripherals whose bus clock can be enabled through the RCC
+pub trait Enable {
+    /// Bus proxy holding the enable register of the peripheral
+    type Bus;
+
+    #[doc(hidden)]
+    fn enable(bus: &mut Self::Bus);
+
+    /// Clears the enable bit without the bus proxy, in a critical section
+    #[doc(hidden)]
+    fn disable();
+}
+
+/// Keeps the bus clock of peripheral `P` enabled, and disables it again when dropped
+///
+/// Drivers hold one for their peripheral, so the clock is enabled for as long as the driver
+/// exists and turned off once it is released.
+pub struct ClockGate<P>
+where
+    P: Enable,
+{
+    _peripheral: PhantomData<P>,
+}
+
+impl<P> ClockGate<P>
+where
+    P: Enable,
+{
+    /// Enables the bus clock of `peripheral`
+    pub fn enable(_peripheral: &P, bus: &mut P::Bus) -> Self {
+        P::enable(bus);
+
+        ClockGate {
+            _peripheral: PhantomData,
+        }
+    }
+
+    /// Gives up the gate, leaving the clock enabled for good
+    pub fn keep_enabled(self) {
+        mem::forget(self);
+    }
+}
+
+impl<P> Drop for ClockGate<P>
+where
+    P: Enable,
+{
+    fn drop(&mut self) {
+        P::disable();
+    }
+}
+
+macro_rules! enable {
+    ($($PER:ident: ($bus:ident, $enr:ident, $perXen:ident),)+) => {
+        $(
+            impl Enable for $PER {
+                type Bus = $bus;
+
+                fn enable(bus: &mut $bus) {
+                    bus.enr().modify(|_, w| w.$perXen().set_bit());
+                    // The enable takes two bus cycles to reach the peripheral, reading it back
+                    // makes sure the configuration that follows isn't lost
+                    let _ = bus.enr().read();
+                }
+
+                fn disable() {
+                    // NOTE(unsafe) the critical section makes the read-modify-write atomic
+                    // towards interrupts, and only the bit of this peripheral is changed
+                    interrupt::free(|_| unsafe {
+                        (*RCC::ptr()).$enr.modify(|_, w| w.$perXen().clear_bit())
+                    });
+                }
+            }
+        )+
+    }
+}
+
+enable! {
+    SPI1: (APB2, apb2enr, spi1en),
+    SPI2: (APB1, apb1enr, spi2en),
+    SPI3: (APB1, apb1enr, spi3en),
+    SPI4: (APB2, apb2enr, spi4en),
+    SPI5: (APB2, apb2enr, spi5en),
+    SPI6: (APB2, apb2enr, spi6en),
+
+    USART1: (APB2, apb2enr, usart1en),
+    USART2: (APB1, apb1enr, usart2en),
+    USART3: (APB1, apb1enr, usart3en),
+    USART6: (APB2, apb2enr, usart6en),
+}
+
 const HSI: u32 = 16_000_000; // Hz
 
+const HSE_MIN: u32 = 4_000_000; // Hz
+const HSE_MAX: u32 = 26_000_000; // Hz
+
+const SYSCLK_MAX: u32 = 216_000_000; // Hz
+const PLL_SYSCLK_MIN: u32 = 24_000_000; // Hz
+
+const PCLK1_MAX: u32 = 54_000_000; // Hz
+const PCLK2_MAX: u32 = 108_000_000; // Hz
+
+const PLL48CLK: u32 = 48_000_000; // Hz
+
+const LSE: u32 = 32_768; // Hz
+
+/// Kernel clock of a USART, the clock its baud rate is divided down from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsartClock {
+    /// The clock of the APB the USART is on, the default
+    Pclk = 0b00,
+    /// The system clock
+    Sysclk = 0b01,
+    /// HSI, which keeps running when the system clock is changed
+    Hsi = 0b10,
+    /// LSE, for low baud rates. The oscillator has to be started, e.g. by the RTC.
+    Lse = 0b11,
+}
+
+/// Kernel clock of an I2C, the clock its timing is derived from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum I2cClock {
+    /// PCLK1, the default
+    Pclk1,
+    /// The system clock
+    Sysclk,
+    /// HSI, which keeps the bus timing independent of the system clock
+    Hsi,
+}
+
+/// Kernel clock selection of the peripherals, applied to DCKCFGR2
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct KernelClocks {
+    usart1: UsartClock,
+    usart2: UsartClock,
+    usart3: UsartClock,
+    usart6: UsartClock,
+    i2c1: I2cClock,
+    i2c2: I2cClock,
+    i2c3: I2cClock,
+    i2c4: I2cClock,
+}
+
+impl Default for KernelClocks {
+    fn default() -> Self {
+        KernelClocks {
+            usart1: UsartClock::Pclk,
+            usart2: UsartClock::Pclk,
+            usart3: UsartClock::Pclk,
+            usart6: UsartClock::Pclk,
+            i2c1: I2cClock::Pclk1,
+            i2c2: I2cClock::Pclk1,
+            i2c3: I2cClock::Pclk1,
+            i2c4: I2cClock::Pclk1,
+        }
+    }
+}
+
+impl KernelClocks {
+    fn write(&self, rcc: &rcc::RegisterBlock) {
+        fn i2c_bits(source: I2cClock) -> u8 {
+            match source {
+                I2cClock::Pclk1 => 0b00,
+                I2cClock::Sysclk => 0b01,
+                I2cClock::Hsi => 0b10,
+            }
+        }
+
+        // NOTE(unsafe) the I2C selections are limited to their three valid values
+        rcc.dckcfgr2.modify(|_, w| unsafe {
+            w.usart1sel()
+                .bits(self.usart1 as u8)
+                .usart2sel()
+                .bits(self.usart2 as u8)
+                .usart3sel()
+                .bits(self.usart3 as u8)
+                .usart6sel()
+                .bits(self.usart6 as u8)
+                .i2c1sel()
+                .bits(i2c_bits(self.i2c1))
+                .i2c2sel()
+                .bits(i2c_bits(self.i2c2))
+                .i2c3sel()
+                .bits(i2c_bits(self.i2c3))
+                .i2c4sel()
+                .bits(i2c_bits(self.i2c4))
+        });
+    }
+}
+
+/// Returns the frequency of a USART kernel clock
+fn usart_clock(source: UsartClock, pclk: Hertz, sysclk: Hertz) -> Hertz {
+    match source {
+        UsartClock::Pclk => pclk,
+        UsartClock::Sysclk => sysclk,
+        UsartClock::Hsi => Hertz(HSI),
+        UsartClock::Lse => Hertz(LSE),
+    }
+}
+
+/// Returns the frequency of an I2C kernel clock
+fn i2c_clock(source: I2cClock, pclk1: Hertz, sysclk: Hertz) -> Hertz {
+    match source {
+        I2cClock::Pclk1 => pclk1,
+        I2cClock::Sysclk => sysclk,
+        I2cClock::Hsi => Hertz(HSI),
+    }
+}
+
 pub struct CFGR {
     hclk: Option<u32>,
     pclk1: Option<u32>,
     pclk2: Option<u32>,
     sysclk: Option<u32>,
+    hse: Option<u32>,
+    css: bool,
+    pll48clk: bool,
+    kernel_clocks: KernelClocks,
+}
+
+macro_rules! kernel_clock_setters {
+    ($($setter:ident: ($field:ident, $Source:ident, $name:expr),)+) => {
+        $(
+            #[doc = concat!("Selects the kernel clock of ", $name, ", its APB clock by default")]
+            ///
+            /// The driver derives its timing from the selected clock. HSI stays on when HSE is
+            /// used, so it can clock the peripheral independently of the system clock.
+            pub fn $setter(mut self, source: $Source) -> Self {
+                self.kernel_clocks.$field = source;
+                self
+            }
+        )+
+    }
+}
+
+macro_rules! kernel_clock_getters {
+    ($($getter:ident: ($field:ident, $clock:ident, $pclk:ident, $name:expr),)+) => {
+        $(
+            #[doc = concat!("Returns the frequency of the kernel clock of ", $name)]
+            pub fn $getter(&self) -> Hertz {
+                $clock(self.kernel_clocks.$field, self.$pclk, self.sysclk)
+            }
+        )+
+    }
 }
 
 impl CFGR {
+    fn new() -> Self {
+        CFGR {
+            hclk: None,
+            pclk1: None,
+            pclk2: None,
+            sysclk: None,
+            hse: None,
+            css: false,
+            pll48clk: false,
+            kernel_clocks: KernelClocks::default(),
+        }
+    }
+
     pub fn hclk<F>(mut self, freq: F) -> Self
     where
         F: Into<Hertz>,
@@ -127,180 +480,547 @@ impl CFGR {
         self
     }
 
+    /// Uses an external crystal / oscillator of frequency `freq` on HSE instead of HSI
+    ///
+    /// HSE then drives the PLL, or the system clock directly if `sysclk` is left unset or set to
+    /// `freq`. The PLL is fed with 1 MHz, so `freq` must be a whole number of MHz between 4 and
+    /// 26 MHz.
+    pub fn use_hse<F>(mut self, freq: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        self.hse = Some(freq.into().0);
+        self
+    }
+
+    /// Enables the Clock Security System (CSS)
+    ///
+    /// The CSS monitors the HSE oscillator and is only active while HSE is running. When HSE
+    /// fails, the hardware switches the system clock to HSI, turns off HSE and the PLL, and
+    /// raises the CSSF flag together with a non-maskable interrupt. The `NMI` handler must call
+    /// [`clear_css_interrupt`] (otherwise the NMI fires again right away) and the clocks must
+    /// be reconfigured, since the frozen [`Clocks`] no longer match the hardware.
+    pub fn enable_css(mut self) -> Self {
+        self.css = true;
+        self
+    }
+
+    /// Requires PLL48CLK, the clock of USB OTG FS and SDMMC, to be exactly 48 MHz
+    ///
+    /// PLL48CLK is divided down from the PLL, so this only works when the PLL drives the system
+    /// clock, at a frequency whose VCO frequency is a multiple of 48 MHz (e.g. 216 or 168 MHz).
+    /// Freezing fails otherwise, instead of handing out an off-spec clock.
+    pub fn require_pll48clk(mut self) -> Self {
+        self.pll48clk = true;
+        self
+    }
+
+    kernel_clock_setters! {
+        usart1_clock: (usart1, UsartClock, "USART1"),
+        usart2_clock: (usart2, UsartClock, "USART2"),
+        usart3_clock: (usart3, UsartClock, "USART3"),
+        usart6_clock: (usart6, UsartClock, "USART6"),
+        i2c1_clock: (i2c1, I2cClock, "I2C1"),
+        i2c2_clock: (i2c2, I2cClock, "I2C2"),
+        i2c3_clock: (i2c3, I2cClock, "I2C3"),
+        i2c4_clock: (i2c4, I2cClock, "I2C4"),
+    }
+
+    /// Applies the clock configuration
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration can't be reached, see [`try_freeze`](CFGR::try_freeze).
     pub fn freeze(self) -> Clocks {
+        self.try_freeze().unwrap()
+    }
+
+    /// Runs the system clock from HSI with the PLL off, for low power operation
+    ///
+    /// The system clock runs at 16 MHz. `hclk` can lower the AHB clock further to 8, 4, 2 or
+    /// 1 MHz, or 250, 125, 62.5 or 31.25 kHz; the fastest of these at or below the request is
+    /// used. The APB clocks run at the AHB clock and HSE is turned off. Of the other settings,
+    /// only the kernel clock selections apply.
+    ///
+    /// This can be applied while running from the PLL at full speed. The source is switched to
+    /// HSI before the PLL is turned off and the flash wait states are only reduced once the
+    /// system clock has actually dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hclk` can't be reached, see
+    /// [`try_freeze_low_power`](CFGR::try_freeze_low_power).
+    pub fn freeze_low_power(self) -> Clocks {
+        self.try_freeze_low_power().unwrap()
+    }
+
+    /// Runs the system clock from HSI with the PLL off, or returns an error if `hclk` can't be
+    /// reached
+    ///
+    /// `hclk` has to be between 31.25 kHz and 16 MHz. It's validated before any register is
+    /// touched, so on error the clocks are left as they were. See
+    /// [`freeze_low_power`](CFGR::freeze_low_power) for the rest.
+    pub fn try_freeze_low_power(self) -> Result<Clocks, ClockConfigError> {
+        let tree = self.low_power_tree()?;
+
         let flash = unsafe { &(*FLASH::ptr()) };
         let rcc = unsafe { &*RCC::ptr() };
 
-        let sysclk = self.sysclk.unwrap_or(HSI);
-        let hclk = self.hclk.unwrap_or(HSI);
+        // Make sure HSI runs, e.g. after the CSS or a previous configuration turned it off
+        rcc.cr.modify(|_, w| w.hsion().set_bit());
+        while rcc.cr.read().hsirdy().bit_is_clear() {}
+        self.kernel_clocks.write(rcc);
+
+        // The PLL and HSE can only be stopped once they no longer drive the system clock
+        rcc.cfgr.modify(|_, w| w.sw().hsi());
+        while !rcc.cfgr.read().sws().is_hsi() {}
+
+        rcc.cr
+            .modify(|_, w| w.pllon().clear_bit().hseon().clear_bit());
+        while rcc.cr.read().pllrdy().bit_is_set() {}
+
+        rcc.cfgr.modify(|_, w| unsafe {
+            w.ppre2()
+                .bits(tree.ppre2_bits)
+                .ppre1()
+                .bits(tree.ppre1_bits)
+                .hpre()
+                .bits(tree.hpre_bits)
+        });
 
-        assert!(sysclk >= HSI);
-        assert!(hclk <= sysclk);
+        // 16 MHz needs no wait states. The new latency only applies once it reads back.
+        flash.acr.modify(|_, w| w.latency().bits(tree.latency));
+        while flash.acr.read().latency().bits() != tree.latency {}
 
-        if sysclk == HSI && hclk == sysclk {
-            // use HSI as source and run everything at the same speed
-            rcc.cfgr.modify(|_, w| unsafe {
-                w.ppre2().bits(0).ppre1().bits(0).hpre().bits(0).sw().hsi()
+        Ok(tree.clocks)
+    }
+
+    /// Applies the clock configuration, or returns an error if it can't be reached
+    ///
+    /// `hclk`, `pclk1` and `pclk2` run at the fastest frequency their prescaler can divide
+    /// down at or below the request, by default the fastest one the bus supports (54 MHz for
+    /// APB1, 108 MHz for APB2). The returned [`Clocks`] hold the frequencies actually reached.
+    /// The configuration is validated before any register is touched, so on error the clocks
+    /// are left as they were.
+    pub fn try_freeze(self) -> Result<Clocks, ClockConfigError> {
+        let tree = self.clock_tree()?;
+
+        let flash = unsafe { &(*FLASH::ptr()) };
+        let rcc = unsafe { &*RCC::ptr() };
+
+        if self.css {
+            rcc.cr.modify(|_, w| w.csson().set_bit());
+        }
+        self.kernel_clocks.write(rcc);
+
+        if self.hse.is_some() {
+            // Enable HSE and wait for the oscillator to stabilise
+            rcc.cr.modify(|_, w| w.hseon().set_bit());
+            while rcc.cr.read().hserdy().bit_is_clear() {}
+        }
+
+        // More wait states have to be in place before HCLK speeds up, fewer only apply once it
+        // has slowed down
+        let raise_latency = tree.latency > flash.acr.read().latency().bits();
+        if raise_latency {
+            flash.acr.modify(|_, w| w.latency().bits(tree.latency));
+            while flash.acr.read().latency().bits() != tree.latency {}
+        }
+
+        if let Some(pll) = tree.pll {
+            // use PLL as source
+            rcc.pllcfgr.write(|w| unsafe {
+                w.pllsrc()
+                    .bit(self.hse.is_some())
+                    .pllm()
+                    .bits(pll.m)
+                    .plln()
+                    .bits(pll.n)
+                    .pllp()
+                    .bits(pll.p)
+                    .pllq()
+                    .bits(pll.q)
             });
 
-            Clocks {
+            // Enable PLL
+            rcc.cr.modify(|_, w| w.pllon().set_bit());
+
+            // Wait for PLL to stabilise
+            while rcc.cr.read().pllrdy().bit_is_clear() {}
+        }
+
+        // Set scaling factors and switch the system clock to its source
+        rcc.cfgr.modify(|_, w| unsafe {
+            let w = w
+                .ppre2()
+                .bits(tree.ppre2_bits)
+                .ppre1()
+                .bits(tree.ppre1_bits)
+                .hpre()
+                .bits(tree.hpre_bits);
+            match tree.clocks.sysclk_source {
+                SysClkSource::Hsi => w.sw().hsi(),
+                SysClkSource::Hse => w.sw().hse(),
+                SysClkSource::Pll => w.sw().pll(),
+            }
+        });
+
+        if !raise_latency {
+            flash.acr.modify(|_, w| w.latency().bits(tree.latency));
+        }
+
+        Ok(tree.clocks)
+    }
+
+    /// Works out the AHB prescaler of `freeze_low_power`, the APB clocks follow HCLK
+    fn low_power_tree(&self) -> Result<ClockTree, ClockConfigError> {
+        let (hpre_bits, hclk) = hpre(HSI, self.hclk.unwrap_or(HSI))?;
+
+        Ok(ClockTree {
+            clocks: Clocks {
                 hclk: Hertz(hclk),
                 pclk1: Hertz(hclk),
                 pclk2: Hertz(hclk),
-                sysclk: Hertz(sysclk),
+                sysclk: Hertz(HSI),
+                ppre1: 1,
+                ppre2: 1,
+                sysclk_source: SysClkSource::Hsi,
+                pll48clk: None,
+                kernel_clocks: self.kernel_clocks,
+            },
+            hpre_bits,
+            ppre1_bits: 0,
+            ppre2_bits: 0,
+            pll: None,
+            latency: flash_latency(hclk),
+        })
+    }
+
+    /// Validates the configuration and works out the prescalers reaching it
+    fn clock_tree(&self) -> Result<ClockTree, ClockConfigError> {
+        // The oscillator that drives the system clock, directly or through the PLL
+        let osc = self.hse.unwrap_or(HSI);
+
+        let sysclk = self.sysclk.unwrap_or(osc);
+
+        if let Some(hse) = self.hse {
+            if hse % 1_000_000 != 0 || !(HSE_MIN..=HSE_MAX).contains(&hse) {
+                return Err(ClockConfigError::HseUnsupported { freq: hse });
+            }
+        }
+        if sysclk < osc {
+            return Err(ClockConfigError::SysclkTooLow {
+                requested: sysclk,
+                min: osc,
+            });
+        }
+        if sysclk != osc {
+            // The PLL is needed
+            if sysclk > SYSCLK_MAX {
+                return Err(ClockConfigError::SysclkTooHigh {
+                    requested: sysclk,
+                    max: SYSCLK_MAX,
+                });
             }
-        } else if sysclk == HSI && hclk < sysclk {
-            let hpre_bits = match sysclk / hclk {
-                0 => unreachable!(),
-                1 => 0b0111,
-                2 => 0b1000,
-                3..=5 => 0b1001,
-                6..=11 => 0b1010,
-                12..=39 => 0b1011,
-                40..=95 => 0b1100,
-                96..=191 => 0b1101,
-                192..=383 => 0b1110,
-                _ => 0b1111,
+            if sysclk < PLL_SYSCLK_MIN {
+                return Err(ClockConfigError::SysclkTooLow {
+                    requested: sysclk,
+                    min: PLL_SYSCLK_MIN,
+                });
+            }
+        }
+        if self.pll48clk && (sysclk == osc || !pll_vco(sysclk).is_multiple_of(PLL48CLK)) {
+            return Err(ClockConfigError::Pll48clkUnattainable { sysclk });
+        }
+
+        let (hpre_bits, hclk) = hpre(sysclk, self.hclk.unwrap_or(sysclk))?;
+        let (ppre1_bits, ppre1) = ppre(1, hclk, self.pclk1, PCLK1_MAX)?;
+        let (ppre2_bits, ppre2) = ppre(2, hclk, self.pclk2, PCLK2_MAX)?;
+
+        let (pll, pll48clk, sysclk_source) = if sysclk == osc {
+            let source = if self.hse.is_some() {
+                SysClkSource::Hse
+            } else {
+                SysClkSource::Hsi
             };
 
-            // Use HSI as source and run everything at the same speed
-            rcc.cfgr.modify(|_, w| unsafe {
-                w.ppre2()
-                    .bits(0)
-                    .ppre1()
-                    .bits(0)
-                    .hpre()
-                    .bits(hpre_bits)
-                    .sw()
-                    .hsi()
-            });
+            (None, None, source)
+        } else {
+            let (plln, pllp) = pll_factors(sysclk);
+
+            // 48 MHz output divisor, min 2, max 15. PLL48CLK must not exceed 48 MHz, so it's the
+            // closest frequency at or below that.
+            let vco = pll_vco(sysclk);
+            let pllq = vco.div_ceil(PLL48CLK);
+
+            let pll = PllConfig {
+                // Input divisor, must result in less than 2MHz. Both oscillators are divided
+                // down to 1MHz, so the scalers don't depend on the source.
+                m: (osc / 1_000_000) as u8,
+                n: plln as u16,
+                p: pllp,
+                q: pllq as u8,
+            };
+
+            (Some(pll), Some(Hertz(vco / pllq)), SysClkSource::Pll)
+        };
 
-            Clocks {
+        Ok(ClockTree {
+            clocks: Clocks {
                 hclk: Hertz(hclk),
-                pclk1: Hertz(hclk),
-                pclk2: Hertz(hclk),
+                pclk1: Hertz(hclk / u32::from(ppre1)),
+                pclk2: Hertz(hclk / u32::from(ppre2)),
                 sysclk: Hertz(sysclk),
-            }
-        } else {
-            assert!(sysclk <= 216_000_000 && sysclk >= 24_000_000);
+                ppre1,
+                ppre2,
+                sysclk_source,
+                pll48clk,
+                kernel_clocks: self.kernel_clocks,
+            },
+            hpre_bits,
+            ppre1_bits,
+            ppre2_bits,
+            pll,
+            latency: flash_latency(hclk),
+        })
+    }
+}
 
-            // We're not diving down the hclk so it'll be the same as sysclk
-            let hclk = sysclk;
+/// PLLM, PLLN, PLLP and PLLQ register values
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PllConfig {
+    m: u8,
+    n: u16,
+    p: u8,
+    q: u8,
+}
 
-            let (pllm, plln, pllp) = if sysclk >= 96_000_000 {
-                // Input divisor from HSI clock, must result in less than 2MHz
-                let pllm = 16;
+/// Register values of a validated configuration, with the clocks they produce
+struct ClockTree {
+    clocks: Clocks,
+    hpre_bits: u8,
+    ppre1_bits: u8,
+    ppre2_bits: u8,
+    /// The PLL settings, if it drives the system clock
+    pll: Option<PllConfig>,
+    latency: u8,
+}
 
-                // Main scaler, must result in >= 192MHz and <= 432MHz, min 50, max 432
-                let plln = (sysclk / 1_000_000) * 2;
+/// Returns the HPRE bits and the frequency of the fastest AHB clock at or below `hclk`
+fn hpre(sysclk: u32, hclk: u32) -> Result<(u8, u32), ClockConfigError> {
+    // There is no division by 32
+    const DIVISORS: [(u8, u32); 9] = [
+        (0b0000, 1),
+        (0b1000, 2),
+        (0b1001, 4),
+        (0b1010, 8),
+        (0b1011, 16),
+        (0b1100, 64),
+        (0b1101, 128),
+        (0b1110, 256),
+        (0b1111, 512),
+    ];
 
-                // Sysclk output divisor, must result in >= 24MHz and <= 216MHz
-                // needs to be the equivalent of 2, 4, 6 or 8
-                let pllp = 0;
+    if hclk > sysclk {
+        return Err(ClockConfigError::HclkExceedsSysclk { hclk, sysclk });
+    }
 
-                (pllm, plln, pllp)
-            } else if sysclk <= 54_000_000 {
-                // Input divisor from HSI clock, must result in less than 2MHz
-                let pllm = 16;
+    DIVISORS
+        .iter()
+        .find(|&&(_, divisor)| sysclk / divisor <= hclk)
+        .map(|&(bits, divisor)| (bits, sysclk / divisor))
+        .ok_or(ClockConfigError::HclkTooLow {
+            requested: hclk,
+            min: sysclk / 512,
+        })
+}
 
-                // Main scaler, must result in >= 192MHz and <= 432MHz, min 50, max 432
-                let plln = (sysclk / 1_000_000) * 8;
+/// Returns the PPRE bits and divisor of the fastest clock of APB `apb` at or below `pclk`, or
+/// at or below `max` if no frequency was requested
+fn ppre(apb: u8, hclk: u32, pclk: Option<u32>, max: u32) -> Result<(u8, u8), ClockConfigError> {
+    const DIVISORS: [(u8, u8); 5] = [(0b000, 1), (0b100, 2), (0b101, 4), (0b110, 8), (0b111, 16)];
 
-                // Sysclk output divisor, must result in >= 24MHz and <= 216MHz
-                // needs to be the equivalent of 2, 4, 6 or 8
-                let pllp = 0b11;
+    let min = hclk / 16;
+    let max = max.min(hclk);
+    let pclk = pclk.unwrap_or(max);
 
-                (pllm, plln, pllp)
-            } else {
-                // Input divisor from HSI clock, must result in less than 2MHz
-                let pllm = 16;
+    if pclk > max || pclk < min {
+        return Err(ClockConfigError::PclkUnreachable {
+            apb,
+            requested: pclk,
+            min,
+            max,
+        });
+    }
 
-                // Main scaler, must result in >= 192MHz and <= 432MHz, min 50, max 432
-                let plln = (sysclk / 1_000_000) * 4;
+    // The request lies between HCLK / 16 and HCLK, so one of the divisors fits
+    Ok(*DIVISORS
+        .iter()
+        .find(|&&(_, divisor)| hclk / u32::from(divisor) <= pclk)
+        .unwrap())
+}
 
-                // Sysclk output divisor, must result in >= 24MHz and <= 216MHz
-                // needs to be the equivalent of 2, 4, 6 or 8
-                let pllp = 0b1;
+/// Returns the flash wait states needed at `hclk`, at 2.7 - 3.6 V
+fn flash_latency(hclk: u32) -> u8 {
+    // One wait state per started 30 MHz
+    (hclk.saturating_sub(1) / 30_000_000) as u8
+}
 
-                (pllm, plln, pllp)
-            };
+/// Returns the main (PLLN) and system clock (PLLP) multiplier / divisor bits for `sysclk`,
+/// with the PLL input at 1 MHz
+fn pll_factors(sysclk: u32) -> (u32, u8) {
+    if sysclk >= 96_000_000 {
+        // Main scaler, must result in >= 192MHz and <= 432MHz, min 50, max 432
+        let plln = (sysclk / 1_000_000) * 2;
 
-            let ppre2_bits = if sysclk > 108_000_000 { 0b100 } else { 0 };
-            let ppre1_bits = if sysclk > 108_000_000 {
-                0b101
-            } else if sysclk > 54_000_000 {
-                0b100
-            } else {
-                0
-            };
+        // Sysclk output divisor, must result in >= 24MHz and <= 216MHz
+        // needs to be the equivalent of 2, 4, 6 or 8
+        let pllp = 0;
 
-            // Calculate real divisor
-            let ppre1 = 1 << (ppre1_bits - 0b011);
-            let ppre2 = 1 << (ppre2_bits - 0b011);
-
-            // Calculate new bus clocks
-            let pclk1 = hclk / ppre1;
-            let pclk2 = hclk / ppre2;
-
-            // Adjust flash wait states
-            flash.acr.write(|w| {
-                w.latency().bits(if sysclk <= 30_000_000 {
-                    0b0000
-                } else if sysclk <= 60_000_000 {
-                    0b0001
-                } else if sysclk <= 90_000_000 {
-                    0b0010
-                } else if sysclk <= 120_000_000 {
-                    0b0011
-                } else if sysclk <= 150_000_000 {
-                    0b0100
-                } else if sysclk <= 180_000_000 {
-                    0b0101
-                } else if sysclk <= 210_000_000 {
-                    0b0110
-                } else {
-                    0b0111
-                })
-            });
+        (plln, pllp)
+    } else if sysclk <= 54_000_000 {
+        // Main scaler, must result in >= 192MHz and <= 432MHz, min 50, max 432
+        let plln = (sysclk / 1_000_000) * 8;
 
-            // use PLL as source
-            rcc.pllcfgr.write(|w| unsafe {
-                w.pllm()
-                    .bits(pllm)
-                    .plln()
-                    .bits(plln as u16)
-                    .pllp()
-                    .bits(pllp)
-            });
+        // Sysclk output divisor, must result in >= 24MHz and <= 216MHz
+        // needs to be the equivalent of 2, 4, 6 or 8
+        let pllp = 0b11;
 
-            // Enable PLL
-            rcc.cr.write(|w| w.pllon().set_bit());
+        (plln, pllp)
+    } else {
+        // Main scaler, must result in >= 192MHz and <= 432MHz, min 50, max 432
+        let plln = (sysclk / 1_000_000) * 4;
 
-            // Wait for PLL to stabilise
-            while rcc.cr.read().pllrdy().bit_is_clear() {}
+        // Sysclk output divisor, must result in >= 24MHz and <= 216MHz
+        // needs to be the equivalent of 2, 4, 6 or 8
+        let pllp = 0b1;
 
-            // Set scaling factors and switch clock to PLL
-            rcc.cfgr.modify(|_, w| unsafe {
-                w.ppre2()
-                    .bits(ppre2_bits)
-                    .ppre1()
-                    .bits(ppre1_bits)
-                    .hpre()
-                    .bits(0)
-                    .sw()
-                    .pll()
-            });
+        (plln, pllp)
+    }
+}
 
-            Clocks {
-                hclk: Hertz(hclk),
-                pclk1: Hertz(pclk1),
-                pclk2: Hertz(pclk2),
-                sysclk: Hertz(sysclk),
+/// Returns the VCO frequency the PLL runs at for `sysclk`
+fn pll_vco(sysclk: u32) -> u32 {
+    pll_factors(sysclk).0 * 1_000_000
+}
+
+/// Returns `true` if the Clock Security System detected an HSE failure
+///
+/// Can be called from the `NMI` handler to tell a CSS event apart from other NMI sources.
+pub fn css_failure_detected() -> bool {
+    // NOTE(unsafe) atomic read with no side effects
+    unsafe { (*RCC::ptr()).cir.read().cssf().bit_is_set() }
+}
+
+/// Clears the Clock Security System interrupt flag (CSSF)
+pub fn clear_css_interrupt() {
+    // NOTE(unsafe) the clear bits are write-one-to-clear and the other flags read as zero
+    unsafe { (*RCC::ptr()).cir.modify(|_, w| w.cssc().set_bit()) }
+}
+
+/// System clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SysClkSource {
+    /// High speed internal oscillator
+    Hsi,
+    /// High speed external oscillator
+    Hse,
+    /// Main PLL
+    Pll,
+}
+
+/// Error returned by [`CFGR::try_freeze`] and [`CFGR::try_freeze_low_power`]
+///
+/// All frequencies are in Hz.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ClockConfigError {
+    /// The requested system clock is faster than the device supports
+    SysclkTooHigh { requested: u32, max: u32 },
+    /// The requested system clock is slower than the oscillator, or than the PLL can output
+    SysclkTooLow { requested: u32, min: u32 },
+    /// The requested AHB clock is faster than the system clock
+    HclkExceedsSysclk { hclk: u32, sysclk: u32 },
+    /// The requested AHB clock is slower than the system clock divided by 512
+    HclkTooLow { requested: u32, min: u32 },
+    /// The requested clock of APB `apb` is faster than the bus supports or than the AHB
+    /// clock, or slower than the AHB clock divided by 16
+    PclkUnreachable {
+        apb: u8,
+        requested: u32,
+        min: u32,
+        max: u32,
+    },
+    /// The HSE frequency isn't a whole number of MHz in the 4 - 26 MHz range
+    HseUnsupported { freq: u32 },
+    /// PLL48CLK is required, but can't be exactly 48 MHz with this system clock
+    Pll48clkUnattainable { sysclk: u32 },
+}
+
+impl fmt::Display for ClockConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClockConfigError::SysclkTooHigh { requested, max } => {
+                write!(
+                    f,
+                    "SYSCLK of {} Hz is above the maximum of {} Hz",
+                    requested, max
+                )
+            }
+            ClockConfigError::SysclkTooLow { requested, min } => {
+                write!(
+                    f,
+                    "SYSCLK of {} Hz is below the minimum of {} Hz",
+                    requested, min
+                )
+            }
+            ClockConfigError::HclkExceedsSysclk { hclk, sysclk } => {
+                write!(f, "HCLK of {} Hz is above SYSCLK of {} Hz", hclk, sysclk)
             }
+            ClockConfigError::HclkTooLow { requested, min } => {
+                write!(
+                    f,
+                    "HCLK of {} Hz is below the minimum of {} Hz",
+                    requested, min
+                )
+            }
+            ClockConfigError::PclkUnreachable {
+                apb,
+                requested,
+                min,
+                max,
+            } => write!(
+                f,
+                "PCLK{} of {} Hz is outside the {} - {} Hz range",
+                apb, requested, min, max
+            ),
+            ClockConfigError::HseUnsupported { freq } => write!(
+                f,
+                "HSE of {} Hz is not a whole number of MHz in the 4 - 26 MHz range",
+                freq
+            ),
+            ClockConfigError::Pll48clkUnattainable { sysclk } => write!(
+                f,
+                "PLL48CLK can't be exactly 48 MHz with SYSCLK of {} Hz",
+                sysclk
+            ),
         }
     }
 }
 
+/// Error returned by [`Clocks::verify`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockHealthError {
+    /// The system clock is no longer driven by the source selected in `freeze`, e.g. because
+    /// the Clock Security System switched to HSI
+    SourceChanged {
+        expected: SysClkSource,
+        actual: SysClkSource,
+    },
+    /// The PLL drives the system clock but isn't locked
+    PllNotLocked,
+}
+
 /// Frozen clock frequencies
 ///
 /// The existence of this value indicates that the clock configuration can no longer be changed
@@ -310,6 +1030,11 @@ pub struct Clocks {
     pclk1: Hertz,
     pclk2: Hertz,
     sysclk: Hertz,
+    ppre1: u8,
+    ppre2: u8,
+    sysclk_source: SysClkSource,
+    pll48clk: Option<Hertz>,
+    kernel_clocks: KernelClocks,
 }
 
 impl Clocks {
@@ -328,8 +1053,507 @@ impl Clocks {
         self.pclk2
     }
 
+    /// Returns the kernel clock frequency of the timers on the APB1
+    ///
+    /// The timers run at twice the APB1 frequency whenever the APB1 prescaler isn't 1.
+    pub fn timclk1(&self) -> Hertz {
+        if self.ppre1 == 1 {
+            self.pclk1
+        } else {
+            Hertz(self.pclk1.0 * 2)
+        }
+    }
+
+    /// Returns the kernel clock frequency of the timers on the APB2
+    ///
+    /// The timers run at twice the APB2 frequency whenever the APB2 prescaler isn't 1.
+    pub fn timclk2(&self) -> Hertz {
+        if self.ppre2 == 1 {
+            self.pclk2
+        } else {
+            Hertz(self.pclk2.0 * 2)
+        }
+    }
+
     /// Returns the system (core) frequency
     pub fn sysclk(&self) -> Hertz {
         self.sysclk
     }
+
+    /// Returns the frequency of PLL48CLK, or `None` if the PLL is off
+    pub fn pll48clk(&self) -> Option<Hertz> {
+        self.pll48clk
+    }
+
+    kernel_clock_getters! {
+        usart1clk: (usart1, usart_clock, pclk2, "USART1"),
+        usart2clk: (usart2, usart_clock, pclk1, "USART2"),
+        usart3clk: (usart3, usart_clock, pclk1, "USART3"),
+        usart6clk: (usart6, usart_clock, pclk2, "USART6"),
+        i2c1clk: (i2c1, i2c_clock, pclk1, "I2C1"),
+        i2c2clk: (i2c2, i2c_clock, pclk1, "I2C2"),
+        i2c3clk: (i2c3, i2c_clock, pclk1, "I2C3"),
+        i2c4clk: (i2c4, i2c_clock, pclk1, "I2C4"),
+    }
+
+    /// Returns the source of the system clock selected in `freeze`
+    pub fn sysclk_source(&self) -> SysClkSource {
+        self.sysclk_source
+    }
+
+    /// Returns `true` if the main PLL is locked
+    pub fn is_pll_ready(&self) -> bool {
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { (*RCC::ptr()).cr.read().pllrdy().bit_is_set() }
+    }
+
+    /// Returns `true` if the HSE oscillator is running and stable
+    pub fn is_hse_ready(&self) -> bool {
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { (*RCC::ptr()).cr.read().hserdy().bit_is_set() }
+    }
+
+    /// Returns `true` if the LSE oscillator is running and stable
+    pub fn is_lse_ready(&self) -> bool {
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { (*RCC::ptr()).bdcr.read().lserdy().bit_is_set() }
+    }
+
+    /// Switches the system clock to HSI and turns off the PLL and HSE
+    ///
+    /// Use this before entering Stop mode, or to idle at low power without reconfiguring the
+    /// clocks. The bus prescalers stay as they are, so until [`power_up`](Clocks::power_up) is
+    /// called the frequencies reported by `Clocks` are wrong whenever they came from the PLL or
+    /// HSE, and peripherals run correspondingly slower.
+    pub fn power_down(&self) {
+        // NOTE(unsafe) the clock configuration is frozen, only the source is toggled
+        let rcc = unsafe { &*RCC::ptr() };
+
+        // Make sure HSI runs, e.g. after the CSS or the application turned it off. Switching to
+        // it before it's ready would stall the core.
+        rcc.cr.modify(|_, w| w.hsion().set_bit());
+        while rcc.cr.read().hsirdy().bit_is_clear() {}
+
+        // The PLL and HSE can only be stopped once they no longer drive the system clock
+        rcc.cfgr.modify(|_, w| w.sw().hsi());
+        while !rcc.cfgr.read().sws().is_hsi() {}
+
+        rcc.cr
+            .modify(|_, w| w.pllon().clear_bit().hseon().clear_bit());
+    }
+
+    /// Restarts the oscillators and switches back to the system clock source of `freeze`
+    ///
+    /// Call this after [`power_down`](Clocks::power_down) or after waking up from Stop mode,
+    /// which leaves the core running on HSI. Returns the time spent waiting for HSE to start
+    /// and the PLL to lock, so the wake-up latency can be budgeted. The time is measured with
+    /// the DWT cycle counter, which must be enabled for this (otherwise zero is returned).
+    pub fn power_up(&self) -> Duration {
+        // NOTE(unsafe) restores the configuration written by `freeze`
+        let rcc = unsafe { &*RCC::ptr() };
+
+        let start = DWT::cycle_count();
+
+        let uses_hse = match self.sysclk_source {
+            SysClkSource::Hsi => false,
+            SysClkSource::Hse => true,
+            SysClkSource::Pll => rcc.pllcfgr.read().pllsrc().bit_is_set(),
+        };
+        if uses_hse {
+            rcc.cr.modify(|_, w| w.hseon().set_bit());
+            while rcc.cr.read().hserdy().bit_is_clear() {}
+        }
+        if self.sysclk_source == SysClkSource::Pll {
+            rcc.cr.modify(|_, w| w.pllon().set_bit());
+            while rcc.cr.read().pllrdy().bit_is_clear() {}
+        }
+
+        let cycles = DWT::cycle_count().wrapping_sub(start);
+
+        match self.sysclk_source {
+            SysClkSource::Hsi => {}
+            SysClkSource::Hse => {
+                rcc.cfgr.modify(|_, w| w.sw().hse());
+                while !rcc.cfgr.read().sws().is_hse() {}
+            }
+            SysClkSource::Pll => {
+                rcc.cfgr.modify(|_, w| w.sw().pll());
+                while !rcc.cfgr.read().sws().is_pll() {}
+            }
+        }
+
+        hsi_wait_time(cycles, self.sysclk, self.hclk)
+    }
+
+    /// Checks that the hardware still runs on the system clock source selected in `freeze`
+    ///
+    /// This only reads two status registers, so it is cheap enough to call periodically from
+    /// a supervisory task.
+    pub fn verify(&self) -> Result<(), ClockHealthError> {
+        // NOTE(unsafe) atomic read with no side effects
+        let rcc = unsafe { &*RCC::ptr() };
+
+        let actual = match rcc.cfgr.read().sws().bits() {
+            0b00 => SysClkSource::Hsi,
+            0b01 => SysClkSource::Hse,
+            _ => SysClkSource::Pll,
+        };
+        if actual != self.sysclk_source {
+            return Err(ClockHealthError::SourceChanged {
+                expected: self.sysclk_source,
+                actual,
+            });
+        }
+        if actual == SysClkSource::Pll && rcc.cr.read().pllrdy().bit_is_clear() {
+            return Err(ClockHealthError::PllNotLocked);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the time taken by `cycles` core cycles on HSI, with the AHB prescaler that divides
+/// `sysclk` down to `hclk`
+fn hsi_wait_time(cycles: u32, sysclk: Hertz, hclk: Hertz) -> Duration {
+    // HPRE is a power of two up to 512, which divides HSI evenly
+    let hclk = HSI / (sysclk.0 / hclk.0);
+    Duration::from_nanos(u64::from(cycles) * 1_000_000_000 / u64::from(hclk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clocks(cfgr: CFGR) -> Clocks {
+        cfgr.clock_tree().map(|tree| tree.clocks).unwrap()
+    }
+
+    fn error(cfgr: CFGR) -> ClockConfigError {
+        cfgr.clock_tree().err().unwrap()
+    }
+
+    #[test]
+    fn default_runs_on_hsi() {
+        let tree = CFGR::new().clock_tree().unwrap();
+        assert_eq!(tree.clocks.sysclk_source(), SysClkSource::Hsi);
+        assert_eq!(tree.clocks.hclk().0, HSI);
+        assert_eq!(tree.clocks.pclk1().0, HSI);
+        assert_eq!(tree.clocks.pclk2().0, HSI);
+        assert_eq!(tree.pll, None);
+        assert_eq!(tree.latency, 0);
+    }
+
+    #[test]
+    fn full_speed_divides_the_buses_down_to_their_maximum() {
+        let tree = CFGR::new().sysclk(Hertz(216_000_000)).clock_tree().unwrap();
+        assert_eq!(tree.clocks.hclk().0, 216_000_000);
+        assert_eq!(tree.clocks.pclk1().0, 54_000_000);
+        assert_eq!(tree.clocks.pclk2().0, 108_000_000);
+        assert_eq!((tree.ppre1_bits, tree.ppre2_bits), (0b101, 0b100));
+        assert_eq!(tree.latency, 7);
+    }
+
+    #[test]
+    fn hclk_is_honoured_with_the_pll() {
+        let tree = CFGR::new()
+            .sysclk(Hertz(216_000_000))
+            .hclk(Hertz(108_000_000))
+            .clock_tree()
+            .unwrap();
+        assert_eq!(tree.hpre_bits, 0b1000);
+        assert_eq!(tree.clocks.hclk().0, 108_000_000);
+        assert_eq!(tree.clocks.pclk1().0, 54_000_000);
+        assert_eq!(tree.clocks.pclk2().0, 108_000_000);
+        // The wait states follow HCLK
+        assert_eq!(tree.latency, 3);
+    }
+
+    #[test]
+    fn hclk_rounds_down_to_a_divisor() {
+        // 16 MHz / 32 doesn't exist, the next slower division is 64
+        let clocks = clocks(CFGR::new().hclk(Hertz(500_000)));
+        assert_eq!(clocks.hclk().0, 250_000);
+    }
+
+    #[test]
+    fn pclk_is_honoured() {
+        let clocks = clocks(
+            CFGR::new()
+                .sysclk(Hertz(216_000_000))
+                .pclk1(Hertz(27_000_000))
+                .pclk2(Hertz(54_000_000)),
+        );
+        assert_eq!(clocks.pclk1().0, 27_000_000);
+        assert_eq!(clocks.pclk2().0, 54_000_000);
+        assert_eq!(clocks.timclk1().0, 54_000_000);
+    }
+
+    #[test]
+    fn pclk_rounds_down_to_a_divisor() {
+        let clocks = clocks(
+            CFGR::new()
+                .sysclk(Hertz(216_000_000))
+                .pclk2(Hertz(100_000_000)),
+        );
+        assert_eq!(clocks.pclk2().0, 54_000_000);
+    }
+
+    #[test]
+    fn sysclk_too_high() {
+        assert_eq!(
+            error(CFGR::new().sysclk(Hertz(250_000_000))),
+            ClockConfigError::SysclkTooHigh {
+                requested: 250_000_000,
+                max: SYSCLK_MAX
+            }
+        );
+    }
+
+    #[test]
+    fn sysclk_too_low() {
+        assert_eq!(
+            error(
+                CFGR::new()
+                    .use_hse(Hertz(25_000_000))
+                    .sysclk(Hertz(16_000_000))
+            ),
+            ClockConfigError::SysclkTooLow {
+                requested: 16_000_000,
+                min: 25_000_000
+            }
+        );
+        assert_eq!(
+            error(CFGR::new().sysclk(Hertz(20_000_000))),
+            ClockConfigError::SysclkTooLow {
+                requested: 20_000_000,
+                min: PLL_SYSCLK_MIN
+            }
+        );
+    }
+
+    #[test]
+    fn hclk_exceeds_sysclk() {
+        assert_eq!(
+            error(
+                CFGR::new()
+                    .sysclk(Hertz(48_000_000))
+                    .hclk(Hertz(96_000_000))
+            ),
+            ClockConfigError::HclkExceedsSysclk {
+                hclk: 96_000_000,
+                sysclk: 48_000_000
+            }
+        );
+    }
+
+    #[test]
+    fn hclk_too_low() {
+        assert_eq!(
+            error(CFGR::new().sysclk(Hertz(216_000_000)).hclk(Hertz(100_000))),
+            ClockConfigError::HclkTooLow {
+                requested: 100_000,
+                min: 421_875
+            }
+        );
+        assert!(matches!(
+            error(CFGR::new().hclk(Hertz(0))),
+            ClockConfigError::HclkTooLow { requested: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn pll48clk_is_48_mhz() {
+        // (sysclk, PLLQ): VCO of 432 and 336 MHz
+        for &(sysclk, q) in [(216_000_000, 9), (168_000_000, 7)].iter() {
+            let tree = CFGR::new()
+                .sysclk(Hertz(sysclk))
+                .require_pll48clk()
+                .clock_tree()
+                .unwrap();
+            assert_eq!(tree.pll.unwrap().q, q, "sysclk {}", sysclk);
+            assert_eq!(
+                tree.clocks.pll48clk().map(|clk| clk.0),
+                Some(48_000_000),
+                "sysclk {}",
+                sysclk
+            );
+        }
+    }
+
+    #[test]
+    fn pll48clk_unattainable() {
+        // 400 MHz VCO, PLL48CLK would be 44.4 MHz
+        assert_eq!(
+            error(CFGR::new().sysclk(Hertz(200_000_000)).require_pll48clk()),
+            ClockConfigError::Pll48clkUnattainable {
+                sysclk: 200_000_000
+            }
+        );
+        // Without the PLL there is no PLL48CLK at all
+        assert_eq!(
+            error(CFGR::new().require_pll48clk()),
+            ClockConfigError::Pll48clkUnattainable { sysclk: HSI }
+        );
+        // Not required, the closest clock below 48 MHz is used
+        let clocks = clocks(CFGR::new().sysclk(Hertz(200_000_000)));
+        assert!(clocks.pll48clk().unwrap().0 < 48_000_000);
+    }
+
+    #[test]
+    fn low_power_divides_hsi() {
+        let tree = CFGR::new().low_power_tree().unwrap();
+        assert_eq!(tree.clocks.hclk().0, HSI);
+        assert_eq!(tree.latency, 0);
+
+        let tree = CFGR::new().hclk(Hertz(500_000)).low_power_tree().unwrap();
+        assert_eq!(tree.hpre_bits, 0b1100);
+        assert_eq!(tree.clocks.hclk().0, 250_000);
+        assert_eq!(tree.clocks.pclk1().0, 250_000);
+        assert_eq!(tree.clocks.pclk2().0, 250_000);
+    }
+
+    #[test]
+    fn low_power_rejects_unreachable_hclk() {
+        let error = |cfgr: CFGR| cfgr.low_power_tree().err().unwrap();
+        assert_eq!(
+            error(CFGR::new().hclk(Hertz(0))),
+            ClockConfigError::HclkTooLow {
+                requested: 0,
+                min: 31_250
+            }
+        );
+        assert_eq!(
+            error(CFGR::new().hclk(Hertz(20_000))),
+            ClockConfigError::HclkTooLow {
+                requested: 20_000,
+                min: 31_250
+            }
+        );
+        // sysclk doesn't apply, HSI is always the source
+        assert_eq!(
+            error(
+                CFGR::new()
+                    .sysclk(Hertz(216_000_000))
+                    .hclk(Hertz(54_000_000))
+            ),
+            ClockConfigError::HclkExceedsSysclk {
+                hclk: 54_000_000,
+                sysclk: HSI
+            }
+        );
+    }
+
+    #[test]
+    fn hse_unsupported() {
+        assert_eq!(
+            error(CFGR::new().use_hse(Hertz(3_000_000))),
+            ClockConfigError::HseUnsupported { freq: 3_000_000 }
+        );
+        assert_eq!(
+            error(CFGR::new().use_hse(Hertz(8_500_000))),
+            ClockConfigError::HseUnsupported { freq: 8_500_000 }
+        );
+    }
+
+    #[test]
+    fn pclk_unreachable() {
+        // Above the APB1 maximum
+        assert_eq!(
+            error(
+                CFGR::new()
+                    .sysclk(Hertz(216_000_000))
+                    .pclk1(Hertz(108_000_000))
+            ),
+            ClockConfigError::PclkUnreachable {
+                apb: 1,
+                requested: 108_000_000,
+                min: 13_500_000,
+                max: PCLK1_MAX
+            }
+        );
+        // Faster than HCLK
+        assert_eq!(
+            error(CFGR::new().pclk2(Hertz(32_000_000))),
+            ClockConfigError::PclkUnreachable {
+                apb: 2,
+                requested: 32_000_000,
+                min: 1_000_000,
+                max: HSI
+            }
+        );
+        // Below HCLK / 16
+        assert_eq!(
+            error(CFGR::new().pclk2(Hertz(500_000))),
+            ClockConfigError::PclkUnreachable {
+                apb: 2,
+                requested: 500_000,
+                min: 1_000_000,
+                max: HSI
+            }
+        );
+    }
+
+    #[test]
+    fn timer_clocks_double_divided_apb_clocks() {
+        // (sysclk, timclk1, timclk2): APB1 is divided above 54 MHz, APB2 above 108 MHz
+        let cases = [
+            (16_000_000, 16_000_000, 16_000_000),
+            (48_000_000, 48_000_000, 48_000_000),
+            (96_000_000, 96_000_000, 96_000_000),
+            (168_000_000, 84_000_000, 168_000_000),
+            (216_000_000, 108_000_000, 216_000_000),
+        ];
+        for &(sysclk, timclk1, timclk2) in cases.iter() {
+            let clocks = clocks(CFGR::new().sysclk(Hertz(sysclk)));
+            assert_eq!(clocks.timclk1().0, timclk1, "sysclk {}", sysclk);
+            assert_eq!(clocks.timclk2().0, timclk2, "sysclk {}", sysclk);
+        }
+
+        // 96 MHz puts APB1 at 48 MHz behind a prescaler of 2
+        let divided = clocks(CFGR::new().sysclk(Hertz(96_000_000)));
+        assert_eq!(divided.pclk1().0, 48_000_000);
+        assert_eq!(divided.pclk2().0, 96_000_000);
+    }
+
+    #[test]
+    fn timer_clocks_follow_undivided_apb_clocks() {
+        let undivided = clocks(
+            CFGR::new()
+                .sysclk(Hertz(216_000_000))
+                .hclk(Hertz(54_000_000)),
+        );
+        assert_eq!(undivided.pclk1().0, 54_000_000);
+        assert_eq!(undivided.timclk1().0, 54_000_000);
+        assert_eq!(undivided.timclk2().0, 54_000_000);
+
+        // A slower APB2 than needed still doubles
+        let slow = clocks(
+            CFGR::new()
+                .sysclk(Hertz(216_000_000))
+                .pclk2(Hertz(27_000_000)),
+        );
+        assert_eq!(slow.timclk2().0, 54_000_000);
+    }
+
+    #[test]
+    fn wait_time_counts_hsi_cycles() {
+        assert_eq!(
+            hsi_wait_time(16_000, Hertz(216_000_000), Hertz(216_000_000)),
+            Duration::from_millis(1)
+        );
+    }
+
+    #[test]
+    fn wait_time_follows_ahb_prescaler() {
+        // HPRE = 2 keeps the core on 8 MHz while it waits
+        assert_eq!(
+            hsi_wait_time(16_000, Hertz(16_000_000), Hertz(8_000_000)),
+            Duration::from_millis(2)
+        );
+        // HPRE = 512
+        assert_eq!(
+            hsi_wait_time(31_250, Hertz(216_000_000), Hertz(421_875)),
+            Duration::from_secs(1)
+        );
+    }
 }
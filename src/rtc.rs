@@ -0,0 +1,278 @@
+//! Real Time Clock (RTC) calendar
+//!
+//! The RTC and its clock configuration live in the backup domain, which keeps running from VBAT
+//! through resets and standby. [`Rtc::new`] only configures the domain if the calendar hasn't
+//! been initialized yet, so the time survives a warm reset.
+
+use crate::device::{PWR, RCC, RTC};
+use crate::rcc::{APB1, BDCR};
+
+/// RTC error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// A date / time field is out of range, or the year isn't in 2000 - 2099
+    InvalidDateTime,
+}
+
+/// Clock source of the RTC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcClock {
+    /// 32.768 kHz external crystal, accurate and kept running on VBAT
+    Lse,
+    /// About 32 kHz internal oscillator, needs no crystal but drifts by several percent
+    Lsi,
+}
+
+/// Calendar date and time, in 24-hour format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime {
+    /// 2000 - 2099
+    pub year: u16,
+    /// 1 - 12
+    pub month: u8,
+    /// 1 - 31
+    pub day: u8,
+    /// 0 - 23
+    pub hour: u8,
+    /// 0 - 59
+    pub minute: u8,
+    /// 0 - 59
+    pub second: u8,
+}
+
+impl DateTime {
+    fn is_valid(&self) -> bool {
+        let leap = self.year.is_multiple_of(4);
+        let days = match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+
+        (2000..=2099).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Returns the day of the week, 1 (Monday) to 7 (Sunday)
+    fn weekday(&self) -> u8 {
+        const OFFSETS: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+
+        let year = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let day = (year + year / 4 - year / 100
+            + year / 400
+            + OFFSETS[usize::from(self.month - 1)]
+            + u16::from(self.day))
+            % 7;
+
+        // 0 is Sunday
+        if day == 0 {
+            7
+        } else {
+            day as u8
+        }
+    }
+}
+
+/// Splits a value below 100 into its BCD tens and units
+fn bcd(value: u8) -> (u8, u8) {
+    (value / 10, value % 10)
+}
+
+/// Real Time Clock
+pub struct Rtc {
+    rtc: RTC,
+}
+
+impl Rtc {
+    /// Sets up the backup domain and the RTC for a 1 Hz calendar running from `clock`
+    ///
+    /// The backup domain write protection is lifted and stays lifted. If the calendar was
+    /// already initialized before, e.g. on a warm reset, the RTC is left running as it is.
+    /// Switching to another clock source resets the backup domain, which clears the calendar.
+    pub fn new(rtc: RTC, clock: RtcClock, apb1: &mut APB1, bdcr: &mut BDCR, pwr: &mut PWR) -> Self {
+        // Allow writes to the backup domain
+        apb1.enr().modify(|_, w| w.pwren().set_bit());
+        pwr.cr1.modify(|_, w| w.dbp().set_bit());
+        while pwr.cr1.read().dbp().bit_is_clear() {}
+
+        let bdcr = bdcr.bdcr();
+        let source = bdcr.read().rtcsel();
+        let selected = match clock {
+            RtcClock::Lse => source.is_lse(),
+            RtcClock::Lsi => source.is_lsi(),
+        };
+
+        let mut this = Rtc { rtc };
+
+        if bdcr.read().rtcen().bit_is_set() && selected && this.rtc.isr.read().inits().bit_is_set()
+        {
+            // A system reset clears the shadow registers, which hold zeros until the next copy
+            this.synchronize();
+            return this;
+        }
+
+        // RTCSEL can only be changed after a backup domain reset
+        if !source.is_no_clock() && !selected {
+            bdcr.modify(|_, w| w.bdrst().set_bit());
+            bdcr.modify(|_, w| w.bdrst().clear_bit());
+        }
+
+        // 1 Hz = f / ((PREDIV_A + 1) * (PREDIV_S + 1))
+        let (prediv_a, prediv_s) = match clock {
+            RtcClock::Lse => {
+                bdcr.modify(|_, w| w.lseon().set_bit());
+                while bdcr.read().lserdy().bit_is_clear() {}
+                bdcr.modify(|_, w| w.rtcsel().lse());
+                (127, 255)
+            }
+            RtcClock::Lsi => {
+                // NOTE(unsafe) LSI is only controlled here and by the watchdog, which can only
+                // turn it on as well
+                let csr = unsafe { &(*RCC::ptr()).csr };
+                csr.modify(|_, w| w.lsion().set_bit());
+                while csr.read().lsirdy().bit_is_clear() {}
+                bdcr.modify(|_, w| w.rtcsel().lsi());
+                (127, 249)
+            }
+        };
+        bdcr.modify(|_, w| w.rtcen().set_bit());
+
+        this.modify(|rtc| {
+            // The prescalers take two separate writes, synchronous one first
+            rtc.prer.write(|w| unsafe { w.prediv_s().bits(prediv_s) });
+            rtc.prer
+                .modify(|_, w| unsafe { w.prediv_a().bits(prediv_a) });
+            rtc.cr.modify(|_, w| w.fmt().clear_bit());
+        });
+
+        this
+    }
+
+    /// Sets the calendar date and time
+    pub fn set_datetime(&mut self, datetime: &DateTime) -> Result<(), Error> {
+        if !datetime.is_valid() {
+            return Err(Error::InvalidDateTime);
+        }
+
+        let (yt, yu) = bcd((datetime.year - 2000) as u8);
+        let (mt, mu) = bcd(datetime.month);
+        let (dt, du) = bcd(datetime.day);
+        let (ht, hu) = bcd(datetime.hour);
+        let (mnt, mnu) = bcd(datetime.minute);
+        let (st, su) = bcd(datetime.second);
+        let wdu = datetime.weekday();
+
+        self.modify(|rtc| {
+            rtc.tr.write(|w| unsafe {
+                w.pm()
+                    .clear_bit()
+                    .ht()
+                    .bits(ht)
+                    .hu()
+                    .bits(hu)
+                    .mnt()
+                    .bits(mnt)
+                    .mnu()
+                    .bits(mnu)
+                    .st()
+                    .bits(st)
+                    .su()
+                    .bits(su)
+            });
+            rtc.dr.write(|w| unsafe {
+                w.yt()
+                    .bits(yt)
+                    .yu()
+                    .bits(yu)
+                    .wdu()
+                    .bits(wdu)
+                    .mt()
+                    .bit(mt != 0)
+                    .mu()
+                    .bits(mu)
+                    .dt()
+                    .bits(dt)
+                    .du()
+                    .bits(du)
+            });
+        });
+
+        Ok(())
+    }
+
+    /// Returns the calendar date and time
+    pub fn get_datetime(&self) -> DateTime {
+        // The shadow registers are copied from the calendar every two RTCCLK cycles. Read
+        // until two consecutive reads agree, so a copy in between can't mix up two seconds.
+        let (tr, dr) = loop {
+            let tr = self.rtc.tr.read();
+            let dr = self.rtc.dr.read();
+
+            if tr.bits() == self.rtc.tr.read().bits() && dr.bits() == self.rtc.dr.read().bits() {
+                break (tr, dr);
+            }
+        };
+
+        DateTime {
+            year: 2000 + u16::from(dr.yt().bits() * 10 + dr.yu().bits()),
+            month: u8::from(dr.mt().bit()) * 10 + dr.mu().bits(),
+            day: dr.dt().bits() * 10 + dr.du().bits(),
+            hour: tr.ht().bits() * 10 + tr.hu().bits(),
+            minute: tr.mnt().bits() * 10 + tr.mnu().bits(),
+            second: tr.st().bits() * 10 + tr.su().bits(),
+        }
+    }
+
+    /// Releases the RTC peripheral, leaving the calendar running
+    pub fn free(self) -> RTC {
+        self.rtc
+    }
+
+    /// Waits until the shadow registers have been copied from the calendar again
+    fn synchronize(&mut self) {
+        // Unlock the write protection
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(0xca) });
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(0x53) });
+
+        self.rtc.isr.modify(|_, w| w.rsf().clear_bit());
+        while self.rtc.isr.read().rsf().bit_is_clear() {}
+
+        // Lock the write protection again
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(0xff) });
+    }
+
+    /// Runs `f` in initialization mode, with the write protection lifted
+    fn modify<F>(&mut self, f: F)
+    where
+        F: FnOnce(&RTC),
+    {
+        // Unlock the write protection
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(0xca) });
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(0x53) });
+
+        // Stop the calendar and wait until it can be modified
+        self.rtc.isr.modify(|_, w| w.init().set_bit());
+        while self.rtc.isr.read().initf().bit_is_clear() {}
+
+        f(&self.rtc);
+
+        // Restart the calendar, the shadow registers are resynchronized after that
+        self.rtc
+            .isr
+            .modify(|_, w| w.init().clear_bit().rsf().clear_bit());
+        while self.rtc.isr.read().rsf().bit_is_clear() {}
+
+        // Lock the write protection again
+        self.rtc.wpr.write(|w| unsafe { w.key().bits(0xff) });
+    }
+}
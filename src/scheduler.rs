@@ -0,0 +1,322 @@
+//! Software timers multiplexed on the system timer (SysTick)
+//!
+//! The SysTick interrupt only bumps a [`TickCounter`]; the expired timers are dispatched from
+//! [`SysTickScheduler::poll`] in thread mode, so callbacks never run in interrupt context.
+//!
+//! ```ignore
+//! static TICKS: TickCounter = TickCounter::new();
+//!
+//! #[exception]
+//! fn SysTick() {
+//!     TICKS.tick();
+//! }
+//!
+//! let mut scheduler: SysTickScheduler<4> = SysTickScheduler::new(cp.SYST, clocks, 1_000.hz(), &TICKS);
+//! let blink = scheduler.add(500).unwrap();
+//!
+//! loop {
+//!     scheduler.poll(|id| if id == blink { led.toggle() });
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+
+use crate::rcc::Clocks;
+use crate::time::Hertz;
+
+/// Tick count shared between the SysTick exception handler and the scheduler
+pub struct TickCounter {
+    ticks: AtomicU32,
+}
+
+impl TickCounter {
+    /// Creates a counter at 0, to be placed in a `static`
+    pub const fn new() -> Self {
+        TickCounter {
+            ticks: AtomicU32::new(0),
+        }
+    }
+
+    /// Counts one tick, to be called from the SysTick exception handler
+    pub fn tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns the number of ticks counted so far, wrapping on overflow
+    pub fn ticks(&self) -> u32 {
+        self.ticks.load(Ordering::Acquire)
+    }
+}
+
+impl Default for TickCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle of a timer registered with a [`SysTickScheduler`]
+///
+/// Ids carry the generation of their slot, so an id kept after its timer was removed doesn't
+/// match a later timer reusing the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    slot: usize,
+    generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct SoftTimer {
+    period: u32,
+    remaining: u32,
+}
+
+/// Counts `elapsed` ticks down on `timer`, returns `true` and reloads it if it expired
+///
+/// A timer overdue by more than a period is reloaded with what's left of its current period,
+/// so it stays aligned to its original schedule.
+fn advance(timer: &mut SoftTimer, elapsed: u32) -> bool {
+    if elapsed < timer.remaining {
+        timer.remaining -= elapsed;
+        false
+    } else {
+        let overdue = (elapsed - timer.remaining) % timer.period;
+        timer.remaining = timer.period - overdue;
+        true
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    generation: u32,
+    timer: Option<SoftTimer>,
+}
+
+/// Timer table of a [`SysTickScheduler`], kept apart from the system timer
+struct Timers<const N: usize> {
+    last: u32,
+    slots: [Slot; N],
+}
+
+impl<const N: usize> Timers<N> {
+    const fn new(now: u32) -> Self {
+        Timers {
+            last: now,
+            slots: [Slot {
+                generation: 0,
+                timer: None,
+            }; N],
+        }
+    }
+
+    fn add(&mut self, period: u32) -> Option<TimerId> {
+        if period == 0 {
+            return None;
+        }
+
+        let slot = self.slots.iter().position(|slot| slot.timer.is_none())?;
+        self.slots[slot].timer = Some(SoftTimer {
+            period,
+            remaining: period,
+        });
+
+        Some(TimerId {
+            slot,
+            generation: self.slots[slot].generation,
+        })
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        let slot = &mut self.slots[id.slot];
+        if slot.generation != id.generation || slot.timer.is_none() {
+            return false;
+        }
+
+        slot.timer = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        true
+    }
+
+    fn poll<F>(&mut self, now: u32, mut f: F)
+    where
+        F: FnMut(TimerId),
+    {
+        let elapsed = now.wrapping_sub(self.last);
+        self.last = now;
+
+        if elapsed == 0 {
+            return;
+        }
+
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if let Some(timer) = &mut slot.timer {
+                if advance(timer, elapsed) {
+                    f(TimerId {
+                        slot: index,
+                        generation: slot.generation,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Up to `N` periodic software timers driven by the SysTick interrupt
+pub struct SysTickScheduler<const N: usize> {
+    syst: SYST,
+    counter: &'static TickCounter,
+    resolution: Hertz,
+    timers: Timers<N>,
+}
+
+impl<const N: usize> SysTickScheduler<N> {
+    /// Configures the system timer (SysTick) to interrupt at `tick_rate`
+    ///
+    /// The SysTick handler must call [`TickCounter::tick`] on `counter`. SysTick runs from
+    /// HCLK / 8 and only has a 24-bit reload value, so the tick rate must be at least
+    /// HCLK / 2^27 (about 1.6 Hz at 216 MHz).
+    pub fn new<F>(
+        mut syst: SYST,
+        clocks: Clocks,
+        tick_rate: F,
+        counter: &'static TickCounter,
+    ) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        let clock = clocks.hclk().0 / 8;
+        let reload = clock / tick_rate.into().0;
+
+        assert!(reload > 0 && reload <= (1 << 24));
+
+        syst.set_clock_source(SystClkSource::External);
+        syst.set_reload(reload - 1);
+        syst.clear_current();
+        syst.enable_interrupt();
+        syst.enable_counter();
+
+        SysTickScheduler {
+            syst,
+            counter,
+            resolution: Hertz(clock / reload),
+            timers: Timers::new(counter.ticks()),
+        }
+    }
+
+    /// Returns the actual tick rate, i.e. the resolution of the timer periods
+    pub fn resolution(&self) -> Hertz {
+        self.resolution
+    }
+
+    /// Registers a timer expiring every `period` ticks
+    ///
+    /// Returns `None` if `period` is 0 or all `N` slots are taken.
+    pub fn add(&mut self, period: u32) -> Option<TimerId> {
+        self.timers.add(period)
+    }
+
+    /// Unregisters a timer, freeing its slot
+    ///
+    /// Returns `false`, and leaves the timers as they are, if `id` was already removed.
+    pub fn remove(&mut self, id: TimerId) -> bool {
+        self.timers.remove(id)
+    }
+
+    /// Calls `f` with every timer that expired since the previous poll
+    ///
+    /// Each expired timer is reported once per poll, even if several of its periods passed in the
+    /// meantime; it stays aligned to its original schedule either way.
+    pub fn poll<F>(&mut self, f: F)
+    where
+        F: FnMut(TimerId),
+    {
+        self.timers.poll(self.counter.ticks(), f);
+    }
+
+    /// Stops the system timer (SysTick) and releases it
+    pub fn free(mut self) -> SYST {
+        self.syst.disable_interrupt();
+        self.syst.disable_counter();
+        self.syst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expired<const N: usize>(timers: &mut Timers<N>, now: u32) -> [bool; N] {
+        let mut expired = [false; N];
+        timers.poll(now, |id| expired[id.slot] = true);
+        expired
+    }
+
+    #[test]
+    fn counts_down_remaining_ticks() {
+        let mut timer = SoftTimer {
+            period: 10,
+            remaining: 10,
+        };
+        assert!(!advance(&mut timer, 3));
+        assert_eq!(timer.remaining, 7);
+        assert!(!advance(&mut timer, 6));
+        assert_eq!(timer.remaining, 1);
+        assert!(advance(&mut timer, 1));
+        assert_eq!(timer.remaining, 10);
+    }
+
+    #[test]
+    fn overdue_timer_stays_on_schedule() {
+        let mut timer = SoftTimer {
+            period: 10,
+            remaining: 4,
+        };
+        // Expired at tick 4, and again at 14 and 24, the next expiry is at 34
+        assert!(advance(&mut timer, 27));
+        assert_eq!(timer.remaining, 7);
+    }
+
+    #[test]
+    fn tick_counter_wraps() {
+        let mut timers = Timers::<1>::new(u32::MAX - 2);
+        timers.add(5).unwrap();
+        assert_eq!(expired(&mut timers, 1), [false]);
+        assert_eq!(expired(&mut timers, 2), [true]);
+    }
+
+    #[test]
+    fn rejects_zero_period_and_full_table() {
+        let mut timers = Timers::<2>::new(0);
+        assert_eq!(timers.add(0), None);
+        assert!(timers.add(1).is_some());
+        assert!(timers.add(1).is_some());
+        assert_eq!(timers.add(1), None);
+    }
+
+    #[test]
+    fn reuses_removed_slot() {
+        let mut timers = Timers::<2>::new(0);
+        let first = timers.add(3).unwrap();
+        let second = timers.add(5).unwrap();
+        assert!(timers.remove(first));
+
+        let third = timers.add(2).unwrap();
+        assert_eq!(third.slot, first.slot);
+        assert_eq!(expired(&mut timers, 2), [true, false]);
+        assert!(timers.remove(second));
+    }
+
+    #[test]
+    fn stale_id_does_not_remove_new_timer() {
+        let mut timers = Timers::<1>::new(0);
+        let stale = timers.add(3).unwrap();
+        assert!(timers.remove(stale));
+        let id = timers.add(3).unwrap();
+
+        assert_ne!(stale, id);
+        assert!(!timers.remove(stale));
+        assert_eq!(expired(&mut timers, 3), [true]);
+    }
+}
@@ -0,0 +1,930 @@
+//! Serial communication using the USARTs
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::ptr;
+
+use cortex_m::interrupt;
+
+use crate::device::{DMA1, DMA2, USART1, USART2, USART3, USART6};
+use crate::dma::{self, DmaStream, DoubleBuffer, Stream, Transfer};
+use crate::gpio::gpioa::{PA10, PA2, PA3, PA9};
+use crate::gpio::gpiob::{PB10, PB11, PB6, PB7};
+use crate::gpio::gpioc::{PC10, PC11, PC6, PC7};
+use crate::gpio::gpiod::{PD5, PD6, PD8, PD9};
+use crate::gpio::gpiog::{PG14, PG9};
+use crate::gpio::{Alternate, AF7, AF8};
+use crate::hal::blocking::serial::write;
+use crate::hal::serial;
+use crate::rcc::{ClockGate, Clocks, Enable, Reset, APB1, APB2};
+use crate::time::Bps;
+use nb;
+use void::Void;
+
+/// Serial error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Framing error
+    Framing,
+    /// Noise error
+    Noise,
+    /// RX buffer overrun
+    Overrun,
+    /// Parity check error
+    Parity,
+}
+
+/// Interrupt event
+pub enum Event {
+    /// New data has been received
+    Rxne,
+    /// New data can be sent
+    Txe,
+    /// The line has gone idle
+    Idle,
+}
+
+/// Parity bit, sent after the 8 data bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parity {
+    /// No parity bit
+    None,
+    /// Even parity
+    Even,
+    /// Odd parity
+    Odd,
+}
+
+/// Number of stop bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopBits {
+    /// 1 stop bit
+    Stop1,
+    /// 0.5 stop bits
+    Stop0p5,
+    /// 2 stop bits
+    Stop2,
+    /// 1.5 stop bits
+    Stop1p5,
+}
+
+/// Serial configuration
+///
+/// The default is 115200 bps, no parity, 1 stop bit, standard polarities and no pin swap.
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub baudrate: Bps,
+    pub parity: Parity,
+    pub stopbits: StopBits,
+    /// Inverts the TX line, idle and stop bits are low
+    pub tx_invert: bool,
+    /// Inverts the RX line, idle and stop bits are expected low
+    pub rx_invert: bool,
+    /// Inverts the data and parity bits, 1 is sent and received as low
+    pub data_invert: bool,
+    /// Swaps the TX and RX pins, fixing crossed wiring in firmware
+    ///
+    /// The pins are still passed in their usual order: the TX pin receives and the RX pin
+    /// transmits.
+    pub swap: bool,
+    /// Samples each bit once in its middle, instead of taking the majority of three samples
+    ///
+    /// The receiver then tolerates a larger clock deviation, but no longer detects noise: a
+    /// glitch at the sampling point flips the bit without raising a noise error. Only meant
+    /// for clean lines, e.g. short high speed links on a board.
+    pub one_bit_sampling: bool,
+    /// Single-wire half-duplex mode: the TX pin transmits and receives, the RX pin is unused
+    ///
+    /// The transmitter releases the line while it's idle. The receiver also picks up the bytes
+    /// sent, [`Serial::transmit_then_receive`] drops them from the answer.
+    pub half_duplex: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baudrate: Bps(115_200),
+            parity: Parity::None,
+            stopbits: StopBits::Stop1,
+            tx_invert: false,
+            rx_invert: false,
+            data_invert: false,
+            swap: false,
+            one_bit_sampling: false,
+            half_duplex: false,
+        }
+    }
+}
+
+/// Pins usable as the TX line of a USART
+pub trait PinTx<USART> {}
+
+/// Pins usable as the RX line of a USART
+pub trait PinRx<USART> {}
+
+/// TX / RX pin pair of a USART
+pub trait Pins<USART> {}
+
+impl<USART, TX, RX> Pins<USART> for (TX, RX)
+where
+    TX: PinTx<USART>,
+    RX: PinRx<USART>,
+{
+}
+
+impl PinTx<USART1> for PA9<Alternate<AF7>> {}
+impl PinTx<USART1> for PB6<Alternate<AF7>> {}
+impl PinRx<USART1> for PA10<Alternate<AF7>> {}
+impl PinRx<USART1> for PB7<Alternate<AF7>> {}
+
+impl PinTx<USART2> for PA2<Alternate<AF7>> {}
+impl PinTx<USART2> for PD5<Alternate<AF7>> {}
+impl PinRx<USART2> for PA3<Alternate<AF7>> {}
+impl PinRx<USART2> for PD6<Alternate<AF7>> {}
+
+impl PinTx<USART3> for PB10<Alternate<AF7>> {}
+impl PinTx<USART3> for PC10<Alternate<AF7>> {}
+impl PinTx<USART3> for PD8<Alternate<AF7>> {}
+impl PinRx<USART3> for PB11<Alternate<AF7>> {}
+impl PinRx<USART3> for PC11<Alternate<AF7>> {}
+impl PinRx<USART3> for PD9<Alternate<AF7>> {}
+
+impl PinTx<USART6> for PC6<Alternate<AF8>> {}
+impl PinTx<USART6> for PG14<Alternate<AF8>> {}
+impl PinRx<USART6> for PC7<Alternate<AF8>> {}
+impl PinRx<USART6> for PG9<Alternate<AF8>> {}
+
+/// DMA streams able to serve the TX requests of a USART
+pub trait TxStream<USART>: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+/// DMA streams able to serve the RX requests of a USART
+pub trait RxStream<USART>: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+macro_rules! streams {
+    ($($USART:ident: ($trait:ident, [$($DMA:ident: ($n:expr, $channel:expr)),+]),)+) => {
+        $($(
+            impl $trait<$USART> for Stream<$DMA, $n> {
+                const CHANNEL: u8 = $channel;
+            }
+        )+)+
+    }
+}
+
+streams! {
+    USART1: (TxStream, [DMA2: (7, 4)]),
+    USART1: (RxStream, [DMA2: (2, 4), DMA2: (5, 4)]),
+
+    USART2: (TxStream, [DMA1: (6, 4)]),
+    USART2: (RxStream, [DMA1: (5, 4)]),
+
+    USART3: (TxStream, [DMA1: (3, 4), DMA1: (4, 7)]),
+    USART3: (RxStream, [DMA1: (1, 4)]),
+
+    USART6: (TxStream, [DMA2: (6, 5), DMA2: (7, 5)]),
+    USART6: (RxStream, [DMA2: (1, 5), DMA2: (2, 5)]),
+}
+
+/// Serial abstraction
+pub struct Serial<USART, PINS>
+where
+    USART: Enable,
+{
+    usart: USART,
+    pins: PINS,
+    clock: ClockGate<USART>,
+}
+
+/// Serial receiver
+pub struct Rx<USART> {
+    _usart: PhantomData<USART>,
+}
+
+/// Serial transmitter
+pub struct Tx<USART> {
+    _usart: PhantomData<USART>,
+}
+
+#[cfg(feature = "panic-serial")]
+impl<USART> Tx<USART> {
+    /// Creates another handle to the transmitter of `USART`
+    ///
+    /// # Safety
+    ///
+    /// The caller has to make sure the handles don't write at the same time.
+    pub(crate) unsafe fn steal() -> Self {
+        Tx {
+            _usart: PhantomData,
+        }
+    }
+}
+
+/// Serial receiver bound to a DMA stream
+pub struct RxDma<USART, STREAM> {
+    rx: Rx<USART>,
+    stream: STREAM,
+}
+
+/// Serial transmitter bound to a DMA stream
+pub struct TxDma<USART, STREAM> {
+    tx: Tx<USART>,
+    stream: STREAM,
+}
+
+/// Returns the BRR divisor of `baudrate` with oversampling by 16, rounded to the nearest one
+fn brr(usartclk: u32, baudrate: u32) -> u32 {
+    (usartclk + baudrate / 2) / baudrate
+}
+
+/// Serial port served by the USART interrupt, with software buffers of `TX` and `RX` bytes
+///
+/// [`handle_interrupt`](Self::handle_interrupt) has to be called from the interrupt handler of
+/// the USART. It moves received bytes into the RX buffer and feeds the USART from the TX
+/// buffer, so the application reads and writes the buffers without waiting for the line.
+/// Share it with the handler through a `cortex_m::interrupt::Mutex`.
+///
+/// Bytes arriving while the RX buffer is full are dropped, and the next read reports
+/// [`Error::Overrun`].
+pub struct BufferedSerial<USART, PINS, const TX: usize, const RX: usize>
+where
+    USART: Enable,
+{
+    serial: Serial<USART, PINS>,
+    tx: RingBuffer<TX>,
+    rx: RingBuffer<RX>,
+    /// Error of a received byte, reported by the next read
+    error: Option<Error>,
+}
+
+/// First in, first out queue of bytes
+struct RingBuffer<const N: usize> {
+    bytes: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        RingBuffer {
+            bytes: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends `byte`, giving it back if the buffer is full
+    fn push(&mut self, byte: u8) -> Result<(), u8> {
+        if self.len == N {
+            return Err(byte);
+        }
+
+        self.bytes[(self.start + self.len) % N] = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the oldest byte
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+macro_rules! hal {
+    ($(
+        $USARTX:ident: ($usartX:ident, $apb:ident, $usartXclk:ident),
+    )+) => {
+        $(
+            impl<PINS> Serial<$USARTX, PINS> {
+                /// Configures a USART peripheral to provide serial communication
+                ///
+                /// The peripheral is clocked from its kernel clock, the APB clock unless another
+                /// one was selected in `CFGR`, and oversamples by 16. The baud rate must be at
+                /// most 1/16 of that clock.
+                pub fn $usartX(
+                    mut usart: $USARTX,
+                    pins: PINS,
+                    config: Config,
+                    clocks: Clocks,
+                    apb: &mut $apb,
+                ) -> Self
+                where
+                    PINS: Pins<$USARTX>,
+                {
+                    // reset the peripheral to a clean slate state, then enable it
+                    usart.reset(apb);
+                    let clock = ClockGate::enable(&usart, apb);
+
+                    let brr = brr(clocks.$usartXclk().0, config.baudrate.0);
+                    assert!((16..=0xffff).contains(&brr));
+                    usart.brr.write(|w| w.brr().bits(brr as u16));
+
+                    // CR2 and CR3 are only writable while UE is clear, which it is after the reset
+                    usart.cr2.write(|w| {
+                        let w = match config.stopbits {
+                            StopBits::Stop1 => w.stop().stop1(),
+                            StopBits::Stop0p5 => w.stop().stop0p5(),
+                            StopBits::Stop2 => w.stop().stop2(),
+                            StopBits::Stop1p5 => w.stop().stop1p5(),
+                        };
+                        w.txinv()
+                            .bit(config.tx_invert)
+                            .rxinv()
+                            .bit(config.rx_invert)
+                            .datainv()
+                            .bit(config.data_invert)
+                            .swap()
+                            .bit(config.swap)
+                    });
+                    usart.cr3.write(|w| {
+                        w.onebit()
+                            .bit(config.one_bit_sampling)
+                            .hdsel()
+                            .bit(config.half_duplex)
+                    });
+
+                    // The parity bit is part of the word, so 8 data bits with parity take 9 bits.
+                    // UE is set last, once the whole configuration is in place.
+                    let parity = config.parity != Parity::None;
+                    usart.cr1.write(|w| {
+                        w.m0()
+                            .bit(parity)
+                            .pce()
+                            .bit(parity)
+                            .ps()
+                            .bit(config.parity == Parity::Odd)
+                            .te()
+                            .set_bit()
+                            .re()
+                            .set_bit()
+                            .ue()
+                            .set_bit()
+                    });
+
+                    Serial { usart, pins, clock }
+                }
+
+                /// Starts listening for an interrupt event
+                pub fn listen(&mut self, event: Event) {
+                    match event {
+                        Event::Rxne => self.usart.cr1.modify(|_, w| w.rxneie().set_bit()),
+                        Event::Txe => self.usart.cr1.modify(|_, w| w.txeie().set_bit()),
+                        Event::Idle => self.usart.cr1.modify(|_, w| w.idleie().set_bit()),
+                    }
+                }
+
+                /// Stops listening for an interrupt event
+                pub fn unlisten(&mut self, event: Event) {
+                    match event {
+                        Event::Rxne => self.usart.cr1.modify(|_, w| w.rxneie().clear_bit()),
+                        Event::Txe => self.usart.cr1.modify(|_, w| w.txeie().clear_bit()),
+                        Event::Idle => self.usart.cr1.modify(|_, w| w.idleie().clear_bit()),
+                    }
+                }
+
+                /// Sends `bytes`, then receives the answer into `buffer`
+                ///
+                /// `turnaround` is called as soon as the stop bit of the last byte is out (TC),
+                /// e.g. to deassert the DE pin of an RS-485 transceiver so the bus is free for
+                /// the answer. The bytes received until then, the echo of a half-duplex or
+                /// RS-485 bus, are dropped along with their errors.
+                pub fn transmit_then_receive<F>(
+                    &mut self,
+                    bytes: &[u8],
+                    buffer: &mut [u8],
+                    turnaround: F,
+                ) -> Result<(), Error>
+                where
+                    F: FnOnce(),
+                {
+                    let mut tx: Tx<$USARTX> = Tx {
+                        _usart: PhantomData,
+                    };
+                    let mut rx: Rx<$USARTX> = Rx {
+                        _usart: PhantomData,
+                    };
+
+                    for byte in bytes {
+                        nb::block!(serial::Write::write(&mut tx, *byte)).ok();
+                    }
+                    tx.wait_idle();
+                    turnaround();
+
+                    // The echo of the last byte is received in the middle of its stop bit, so
+                    // it's already in RDR. Earlier ones overran it.
+                    self.usart.rqr.write(|w| w.rxfrq().set_bit());
+                    self.usart.icr.write(|w| {
+                        w.pecf()
+                            .set_bit()
+                            .fecf()
+                            .set_bit()
+                            .ncf()
+                            .set_bit()
+                            .orecf()
+                            .set_bit()
+                    });
+
+                    for slot in buffer.iter_mut() {
+                        *slot = nb::block!(serial::Read::read(&mut rx))?;
+                    }
+
+                    Ok(())
+                }
+
+                /// Splits the `Serial` abstraction into a transmitter and a receiver half
+                ///
+                /// The halves can't be put back together, so the clock of the USART stays
+                /// enabled for good.
+                pub fn split(self) -> (Tx<$USARTX>, Rx<$USARTX>) {
+                    self.clock.keep_enabled();
+
+                    (
+                        Tx {
+                            _usart: PhantomData,
+                        },
+                        Rx {
+                            _usart: PhantomData,
+                        },
+                    )
+                }
+
+                /// Releases the USART peripheral and associated pins, turning off its clock
+                pub fn free(self) -> ($USARTX, PINS) {
+                    self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+                    (self.usart, self.pins)
+                }
+
+                /// Turns the serial port into one served by its interrupt, see
+                /// [`BufferedSerial`]
+                pub fn buffered<const TX: usize, const RX: usize>(
+                    self,
+                ) -> BufferedSerial<$USARTX, PINS, TX, RX> {
+                    self.usart.cr1.modify(|_, w| w.rxneie().set_bit());
+
+                    BufferedSerial {
+                        serial: self,
+                        tx: RingBuffer::new(),
+                        rx: RingBuffer::new(),
+                        error: None,
+                    }
+                }
+            }
+
+            impl<PINS, const TX: usize, const RX: usize> BufferedSerial<$USARTX, PINS, TX, RX> {
+                /// Queues `byte` for sending
+                ///
+                /// Returns `WouldBlock` while the TX buffer is full.
+                pub fn try_write(&mut self, byte: u8) -> nb::Result<(), Void> {
+                    self.tx.push(byte).map_err(|_| nb::Error::WouldBlock)?;
+                    // The interrupt handler disables it again once the buffer is empty
+                    self.serial.usart.cr1.modify(|_, w| w.txeie().set_bit());
+                    Ok(())
+                }
+
+                /// Takes the oldest received byte
+                ///
+                /// Returns `WouldBlock` while the RX buffer is empty. An error of a byte that
+                /// was received is returned once, in place of that byte.
+                pub fn try_read(&mut self) -> nb::Result<u8, Error> {
+                    if let Some(error) = self.error.take() {
+                        return Err(nb::Error::Other(error));
+                    }
+
+                    self.rx.pop().ok_or(nb::Error::WouldBlock)
+                }
+
+                /// Queues all of `bytes` for sending, blocking while the TX buffer is full
+                ///
+                /// Serves the USART itself while it waits, so this also makes progress with
+                /// the USART interrupt masked, e.g. inside the critical section of the mutex.
+                pub fn write_all(&mut self, bytes: &[u8]) {
+                    for &byte in bytes {
+                        while self.try_write(byte).is_err() {
+                            self.handle_interrupt();
+                        }
+                    }
+                }
+
+                /// Fills `buffer` with received bytes, blocking until enough have arrived
+                ///
+                /// Serves the USART itself while it waits, like
+                /// [`write_all`](Self::write_all).
+                pub fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+                    for byte in buffer.iter_mut() {
+                        *byte = loop {
+                            match self.try_read() {
+                                Ok(byte) => break byte,
+                                Err(nb::Error::Other(error)) => return Err(error),
+                                Err(nb::Error::WouldBlock) => self.handle_interrupt(),
+                            }
+                        };
+                    }
+
+                    Ok(())
+                }
+
+                /// Moves bytes between the USART and the buffers
+                ///
+                /// Call this from the interrupt handler of the USART. It only acts on the
+                /// flags that are set, so calling it at other times is harmless.
+                pub fn handle_interrupt(&mut self) {
+                    let usart = &self.serial.usart;
+                    let isr = usart.isr.read();
+
+                    let error = if isr.pe().bit_is_set() {
+                        Some(Error::Parity)
+                    } else if isr.fe().bit_is_set() {
+                        Some(Error::Framing)
+                    } else if isr.nf().bit_is_set() {
+                        Some(Error::Noise)
+                    } else if isr.ore().bit_is_set() {
+                        Some(Error::Overrun)
+                    } else {
+                        None
+                    };
+                    if error.is_some() {
+                        self.error = error;
+                        usart.icr.write(|w| {
+                            w.pecf().set_bit().fecf().set_bit().ncf().set_bit().orecf().set_bit()
+                        });
+                    }
+
+                    if isr.rxne().bit_is_set() {
+                        let byte = usart.rdr.read().rdr().bits() as u8;
+                        if self.rx.push(byte).is_err() {
+                            self.error = Some(Error::Overrun);
+                        }
+                    }
+
+                    if isr.txe().bit_is_set() && usart.cr1.read().txeie().bit_is_set() {
+                        match self.tx.pop() {
+                            Some(byte) => {
+                                usart.tdr.write(|w| unsafe { w.tdr().bits(u16::from(byte)) })
+                            }
+                            None => usart.cr1.modify(|_, w| w.txeie().clear_bit()),
+                        }
+                    }
+                }
+
+                /// Returns `true` once the TX buffer is empty and the last byte has been sent
+                pub fn is_flushed(&self) -> bool {
+                    self.tx.is_empty() && self.serial.usart.isr.read().tc().bit_is_set()
+                }
+
+                /// Stops the interrupts and returns the serial port, dropping buffered bytes
+                pub fn free(self) -> Serial<$USARTX, PINS> {
+                    self.serial
+                        .usart
+                        .cr1
+                        .modify(|_, w| w.rxneie().clear_bit().txeie().clear_bit());
+                    self.serial
+                }
+            }
+
+            impl<PINS, const TX: usize, const RX: usize> fmt::Write
+                for BufferedSerial<$USARTX, PINS, TX, RX>
+            {
+                fn write_str(&mut self, s: &str) -> fmt::Result {
+                    self.write_all(s.as_bytes());
+                    Ok(())
+                }
+            }
+
+            impl Rx<$USARTX> {
+                /// Binds the receiver to a DMA stream
+                pub fn with_dma<STREAM>(self, stream: STREAM) -> RxDma<$USARTX, STREAM>
+                where
+                    STREAM: RxStream<$USARTX>,
+                {
+                    // NOTE(unsafe) CR3 is shared with the transmitter, the critical section
+                    // makes the read-modify-write atomic
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmar().set_bit())
+                    });
+
+                    RxDma { rx: self, stream }
+                }
+            }
+
+            impl<STREAM> RxDma<$USARTX, STREAM>
+            where
+                STREAM: RxStream<$USARTX>,
+            {
+                /// Starts receiving into `buffer`, until it is full
+                #[allow(clippy::type_complexity)]
+                pub fn read(
+                    self,
+                    buffer: &'static mut [u8],
+                ) -> Result<
+                    Transfer<Self, &'static mut [u8]>,
+                    (dma::Error, &'static mut [u8], Self),
+                > {
+                    let stream = self.stream.handle();
+                    let started = stream.start(dma::Config {
+                        channel: STREAM::CHANNEL,
+                        direction: dma::Direction::PeripheralToMemory,
+                        width: dma::Width::Byte,
+                        // NOTE(unsafe) only the address of the register is taken
+                        peripheral: unsafe { ptr::addr_of!((*$USARTX::ptr()).rdr) as u32 },
+                        memory0: buffer.as_ptr() as u32,
+                        memory1: None,
+                        len: buffer.len(),
+                    });
+                    if let Err(error) = started {
+                        return Err((error, buffer, self));
+                    }
+
+                    Ok(Transfer::new(self, buffer, stream))
+                }
+
+                /// Starts receiving continuously, filling the two buffers in turn
+                ///
+                /// The buffers must have the same length.
+                #[allow(clippy::type_complexity)]
+                pub fn read_double_buffered(
+                    self,
+                    buffers: [&'static mut [u8]; 2],
+                ) -> Result<DoubleBuffer<Self>, (dma::Error, [&'static mut [u8]; 2], Self)> {
+                    assert_eq!(buffers[0].len(), buffers[1].len());
+
+                    let stream = self.stream.handle();
+                    let started = stream.start(dma::Config {
+                        channel: STREAM::CHANNEL,
+                        direction: dma::Direction::PeripheralToMemory,
+                        width: dma::Width::Byte,
+                        // NOTE(unsafe) only the address of the register is taken
+                        peripheral: unsafe { ptr::addr_of!((*$USARTX::ptr()).rdr) as u32 },
+                        memory0: buffers[0].as_ptr() as u32,
+                        memory1: Some(buffers[1].as_ptr() as u32),
+                        len: buffers[0].len(),
+                    });
+                    if let Err(error) = started {
+                        return Err((error, buffers, self));
+                    }
+
+                    Ok(DoubleBuffer::new(self, buffers, stream))
+                }
+
+                /// Releases the receiver and the stream
+                pub fn free(self) -> (Rx<$USARTX>, STREAM) {
+                    // NOTE(unsafe) CR3 is shared with the transmitter, the critical section
+                    // makes the read-modify-write atomic
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmar().clear_bit())
+                    });
+
+                    (self.rx, self.stream)
+                }
+            }
+
+            impl Tx<$USARTX> {
+                /// Blocks until the last byte has been shifted out completely, stop bits
+                /// included (TC)
+                ///
+                /// Unlike a full TX register (TXE), this is the moment the line is free, e.g. to
+                /// turn an RS-485 transceiver around.
+                pub fn wait_idle(&mut self) {
+                    // NOTE(unsafe) atomic read with no side effects
+                    while unsafe { (*$USARTX::ptr()).isr.read().tc().bit_is_clear() } {}
+                }
+
+                /// Binds the transmitter to a DMA stream
+                pub fn with_dma<STREAM>(self, stream: STREAM) -> TxDma<$USARTX, STREAM>
+                where
+                    STREAM: TxStream<$USARTX>,
+                {
+                    // NOTE(unsafe) CR3 is shared with the receiver, the critical section makes
+                    // the read-modify-write atomic
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmat().set_bit())
+                    });
+
+                    TxDma { tx: self, stream }
+                }
+            }
+
+            impl<STREAM> TxDma<$USARTX, STREAM>
+            where
+                STREAM: TxStream<$USARTX>,
+            {
+                /// Starts sending `buffer`
+                ///
+                /// The transfer completes once the last byte is handed to the USART, which
+                /// still has to shift it out.
+                #[allow(clippy::type_complexity)]
+                pub fn write(
+                    self,
+                    buffer: &'static mut [u8],
+                ) -> Result<
+                    Transfer<Self, &'static mut [u8]>,
+                    (dma::Error, &'static mut [u8], Self),
+                > {
+                    let stream = self.stream.handle();
+                    let started = stream.start(dma::Config {
+                        channel: STREAM::CHANNEL,
+                        direction: dma::Direction::MemoryToPeripheral,
+                        width: dma::Width::Byte,
+                        // NOTE(unsafe) only the address of the register is taken
+                        peripheral: unsafe { ptr::addr_of!((*$USARTX::ptr()).tdr) as u32 },
+                        memory0: buffer.as_ptr() as u32,
+                        memory1: None,
+                        len: buffer.len(),
+                    });
+                    if let Err(error) = started {
+                        return Err((error, buffer, self));
+                    }
+
+                    Ok(Transfer::new(self, buffer, stream))
+                }
+
+                /// Releases the transmitter and the stream
+                pub fn free(self) -> (Tx<$USARTX>, STREAM) {
+                    // NOTE(unsafe) CR3 is shared with the receiver, the critical section makes
+                    // the read-modify-write atomic
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmat().clear_bit())
+                    });
+
+                    (self.tx, self.stream)
+                }
+            }
+
+            impl serial::Read<u8> for Rx<$USARTX> {
+                type Error = Error;
+
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    // NOTE(unsafe) atomic read with no side effects
+                    let isr = unsafe { (*$USARTX::ptr()).isr.read() };
+                    // NOTE(unsafe) atomic write to a write-one-to-clear register
+                    let icr = unsafe { &(*$USARTX::ptr()).icr };
+
+                    Err(if isr.pe().bit_is_set() {
+                        icr.write(|w| w.pecf().set_bit());
+                        nb::Error::Other(Error::Parity)
+                    } else if isr.fe().bit_is_set() {
+                        icr.write(|w| w.fecf().set_bit());
+                        nb::Error::Other(Error::Framing)
+                    } else if isr.nf().bit_is_set() {
+                        icr.write(|w| w.ncf().set_bit());
+                        nb::Error::Other(Error::Noise)
+                    } else if isr.ore().bit_is_set() {
+                        icr.write(|w| w.orecf().set_bit());
+                        nb::Error::Other(Error::Overrun)
+                    } else if isr.rxne().bit_is_set() {
+                        // NOTE(unsafe) atomic read from a register owned by this half
+                        return Ok(unsafe { (*$USARTX::ptr()).rdr.read().rdr().bits() as u8 });
+                    } else {
+                        nb::Error::WouldBlock
+                    })
+                }
+            }
+
+            impl serial::Write<u8> for Tx<$USARTX> {
+                type Error = Void;
+
+                fn flush(&mut self) -> nb::Result<(), Void> {
+                    // NOTE(unsafe) atomic read with no side effects
+                    let isr = unsafe { (*$USARTX::ptr()).isr.read() };
+
+                    if isr.tc().bit_is_set() {
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+
+                fn write(&mut self, byte: u8) -> nb::Result<(), Void> {
+                    // NOTE(unsafe) atomic read with no side effects
+                    let isr = unsafe { (*$USARTX::ptr()).isr.read() };
+
+                    if isr.txe().bit_is_set() {
+                        // NOTE(unsafe) atomic write to a register owned by this half
+                        unsafe { (*$USARTX::ptr()).tdr.write(|w| w.tdr().bits(u16::from(byte))) }
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+            }
+
+            impl write::Default<u8> for Tx<$USARTX> {}
+        )+
+    }
+}
+
+hal! {
+    USART1: (usart1, APB2, usart1clk),
+    USART2: (usart2, APB1, usart2clk),
+    USART3: (usart3, APB1, usart3clk),
+    USART6: (usart6, APB2, usart6clk),
+}
+
+impl<USART> fmt::Write for Tx<USART>
+where
+    Tx<USART>: serial::Write<u8>,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            nb::block!(serial::Write::write(self, byte)).map_err(|_| fmt::Error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brr_from_hsi_kernel_clock() {
+        // 16 MHz / 115200 = 138.9
+        assert_eq!(brr(16_000_000, 115_200), 139);
+        // 16 MHz / 9600 = 1666.7
+        assert_eq!(brr(16_000_000, 9_600), 1667);
+    }
+
+    #[test]
+    fn brr_rounds_to_nearest_divisor() {
+        // PCLK1 at 216 MHz sysclk: 54 MHz / 460800 = 117.19
+        assert_eq!(brr(54_000_000, 460_800), 117);
+        // 54 MHz / 921600 = 58.59
+        assert_eq!(brr(54_000_000, 921_600), 59);
+        // Exactly half way rounds up: 108 MHz / 115200 = 937.5
+        assert_eq!(brr(108_000_000, 115_200), 938);
+    }
+
+    #[test]
+    fn brr_of_exact_divisors() {
+        assert_eq!(brr(108_000_000, 9_600), 11_250);
+        assert_eq!(brr(16_000_000, 1_000_000), 16);
+    }
+
+    #[test]
+    fn brr_is_the_nearest_divisor() {
+        // The bit time is off by at most half a kernel clock cycle
+        for &usartclk in [16_000_000, 54_000_000, 108_000_000].iter() {
+            for &baudrate in [9_600, 19_200, 57_600, 115_200, 230_400, 460_800, 921_600].iter() {
+                let divisor = brr(usartclk, baudrate);
+                let error = (i64::from(usartclk) - i64::from(divisor * baudrate)).abs();
+                assert!(
+                    error <= i64::from(baudrate / 2),
+                    "{} / {}",
+                    usartclk,
+                    baudrate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ring_buffer_is_first_in_first_out() {
+        let mut buffer = RingBuffer::<3>::new();
+
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.push(1), Ok(()));
+        assert_eq!(buffer.push(2), Ok(()));
+        assert_eq!(buffer.pop(), Some(1));
+        // Wraps around the end of the array
+        assert_eq!(buffer.push(3), Ok(()));
+        assert_eq!(buffer.push(4), Ok(()));
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.pop(), Some(3));
+        assert_eq!(buffer.pop(), Some(4));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn full_ring_buffer_gives_the_byte_back() {
+        let mut buffer = RingBuffer::<2>::new();
+
+        assert_eq!(buffer.push(1), Ok(()));
+        assert_eq!(buffer.push(2), Ok(()));
+        assert_eq!(buffer.push(3), Err(3));
+        assert_eq!(buffer.pop(), Some(1));
+        assert_eq!(buffer.push(3), Ok(()));
+    }
+
+    #[test]
+    fn empty_ring_buffer_holds_nothing() {
+        let mut buffer = RingBuffer::<0>::new();
+
+        assert_eq!(buffer.push(1), Err(1));
+        assert_eq!(buffer.pop(), None);
+    }
+}
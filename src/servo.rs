@@ -0,0 +1,115 @@
+//! Hobby servos driven by a PWM channel
+
+use crate::hal::PwmPin;
+use crate::time::Hertz;
+
+/// Default shortest pulse, in microseconds
+pub const MIN_PULSE_US: u32 = 1_000;
+
+/// Default longest pulse, in microseconds
+pub const MAX_PULSE_US: u32 = 2_000;
+
+/// Largest angle, in degrees
+pub const MAX_ANGLE: u32 = 180;
+
+/// Returns the duty cycle of a pulse of `us` within a period of `period_us`
+///
+/// Pulses longer than the period are kept high for the whole period.
+fn duty(max_duty: u16, us: u32, period_us: u32) -> u16 {
+    let duty = u64::from(max_duty) * u64::from(us) / u64::from(period_us);
+
+    duty.min(u64::from(max_duty)) as u16
+}
+
+/// Servo attached to a PWM channel
+///
+/// The pulse width is mapped onto the channel's duty cycle using the PWM frequency, which is
+/// 50 Hz for most servos.
+pub struct Servo<PWM> {
+    pwm: PWM,
+    period_us: u32,
+    min_us: u32,
+    max_us: u32,
+}
+
+impl<PWM> Servo<PWM>
+where
+    PWM: PwmPin<Duty = u16>,
+{
+    /// Wraps and enables a PWM channel running at `frequency`
+    ///
+    /// The period has to be long enough for the default 2000 µs pulse, so `frequency` can't
+    /// exceed 500 Hz.
+    pub fn new<F>(mut pwm: PWM, frequency: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        let frequency = frequency.into().0;
+        assert!(frequency > 0);
+        let period_us = 1_000_000 / frequency;
+        assert!(period_us >= MAX_PULSE_US);
+
+        pwm.enable();
+
+        Servo {
+            pwm,
+            period_us,
+            min_us: MIN_PULSE_US,
+            max_us: MAX_PULSE_US,
+        }
+    }
+
+    /// Overrides the pulse width range of the servo, in microseconds
+    ///
+    /// Many servos travel further than the standard 1000 - 2000 µs. Check the datasheet before
+    /// widening the range, driving a servo against its end stop can damage it.
+    pub fn pulse_range(mut self, min_us: u32, max_us: u32) -> Self {
+        assert!(min_us < max_us && max_us <= self.period_us);
+
+        self.min_us = min_us;
+        self.max_us = max_us;
+        self
+    }
+
+    /// Sets the pulse width in microseconds, clamped to the pulse width range
+    pub fn set_us(&mut self, us: u32) {
+        let us = us.max(self.min_us).min(self.max_us);
+        let duty = duty(self.pwm.get_max_duty(), us, self.period_us);
+
+        self.pwm.set_duty(duty);
+    }
+
+    /// Sets the angle in degrees (0 - 180), clamped to the range of the servo
+    pub fn set_angle(&mut self, degrees: u32) {
+        let degrees = degrees.min(MAX_ANGLE);
+        let us = self.min_us + (self.max_us - self.min_us) * degrees / MAX_ANGLE;
+
+        self.set_us(us);
+    }
+
+    /// Disables and releases the PWM channel
+    pub fn free(mut self) -> PWM {
+        self.pwm.disable();
+        self.pwm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duty_scales_pulse_to_period() {
+        // 50 Hz, 20 ms period
+        assert_eq!(duty(20_000, 1_000, 20_000), 1_000);
+        assert_eq!(duty(20_000, 1_500, 20_000), 1_500);
+        assert_eq!(duty(0xffff, 2_000, 20_000), 6_553);
+    }
+
+    #[test]
+    fn duty_is_clamped_to_max() {
+        assert_eq!(duty(1_000, 2_000, 2_000), 1_000);
+        assert_eq!(duty(1_000, 2_500, 2_000), 1_000);
+        assert_eq!(duty(0xffff, 1_000_000, 2_000), 0xffff);
+    }
+}
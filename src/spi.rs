@@ -0,0 +1,799 @@
+//! Serial Peripheral Interface (SPI) bus, master mode
+//!
+//! # Frame size
+//!
+//! The bus starts with 8-bit frames, [`Spi::into_16bit`] switches it to 16-bit ones. A 16-bit
+//! frame is shifted out starting with bit 15 by default, so its high byte goes over the wire
+//! first: `send(0x1234)` puts 0x12 on the bus before 0x34. Devices with 16-bit registers
+//! usually expect exactly that. [`Spi::transfer_bytes`] and [`Spi::write_bytes`] pack byte
+//! buffers into frames, with the [`ByteOrder`] of the buffer.
+//!
+//! # DMA
+//!
+//! [`Spi::with_dma`] binds the bus to a receive and a transmit stream. A transfer sends a
+//! buffer of frames and replaces them with the frames received meanwhile, in 8 or 16 bits as
+//! set up before binding.
+//!
+//! ```ignore
+//! let streams = dp.DMA1.split(&mut rcc.ahb1);
+//! let spi = spi.with_dma(streams.0, streams.5);
+//!
+//! static mut BUFFER: [u8; 2] = [0x75 | 0x80, 0];
+//! let buffer: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) };
+//! let transfer = spi.transfer(buffer).map_err(|(error, ..)| error).unwrap();
+//! let (buffer, spi) = transfer.wait().map_err(|(error, ..)| error).unwrap();
+//! ```
+
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::device::{DMA1, DMA2, SPI1, SPI2, SPI3, SPI4, SPI5, SPI6};
+use crate::dma::{self, DmaStream, DuplexTransfer, Stream};
+use crate::gpio::gpioa::{PA5, PA6, PA7};
+use crate::gpio::gpiob::{PB10, PB13, PB14, PB15, PB3, PB4, PB5};
+use crate::gpio::gpioc::{PC10, PC11, PC12, PC2, PC3};
+use crate::gpio::gpiod::{PD3, PD7};
+use crate::gpio::gpioe::{PE12, PE13, PE14, PE2, PE5, PE6};
+use crate::gpio::gpiof::{PF11, PF7, PF8, PF9};
+use crate::gpio::gpiog::{PG11, PG12, PG13, PG14, PG9};
+use crate::gpio::gpioh::{PH6, PH7};
+use crate::gpio::gpioi::{PI1, PI2, PI3};
+use crate::gpio::{Alternate, AF5, AF6};
+use crate::hal::blocking::spi::{transfer, write};
+use crate::hal::spi::FullDuplex;
+use crate::rcc::{ClockGate, Clocks, Enable, Reset, APB1, APB2};
+use crate::time::Hertz;
+use nb;
+
+pub use crate::hal::spi::{Mode, Phase, Polarity};
+
+/// SPI error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Overrun occurred
+    Overrun,
+    /// Mode fault occurred
+    ModeFault,
+    /// CRC error
+    Crc,
+}
+
+impl Error {
+    /// Returns the error reported by the OVR, MODF and CRCERR status flags, if any
+    fn from_flags(ovr: bool, modf: bool, crcerr: bool) -> Option<Error> {
+        if ovr {
+            Some(Error::Overrun)
+        } else if modf {
+            Some(Error::ModeFault)
+        } else if crcerr {
+            Some(Error::Crc)
+        } else {
+            None
+        }
+    }
+}
+
+/// Order in which the bits of a frame are shifted out and in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOrder {
+    /// Most significant bit first, the default
+    MsbFirst,
+    /// Least significant bit first
+    LsbFirst,
+}
+
+/// Order of the two bytes of a 16-bit frame in a byte buffer
+///
+/// The wire order assumes the default MSB first bit order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteOrder {
+    /// The high byte of a frame comes first in the buffer, and on the wire
+    BigEndian,
+    /// The low byte of a frame comes first in the buffer, but second on the wire
+    LittleEndian,
+}
+
+/// Returns the frame made of `bytes`, in the order of the buffer they came from
+fn frame(bytes: [u8; 2], order: ByteOrder) -> u16 {
+    match order {
+        ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+        ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+    }
+}
+
+/// Returns the bytes of `frame`, in the order of the buffer they go to
+fn frame_bytes(frame: u16, order: ByteOrder) -> [u8; 2] {
+    match order {
+        ByteOrder::BigEndian => frame.to_be_bytes(),
+        ByteOrder::LittleEndian => frame.to_le_bytes(),
+    }
+}
+
+/// Interrupt event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// A frame has been received (RXNE)
+    Rxne,
+    /// The TX FIFO has room for a frame (TXE)
+    Txe,
+    /// Overrun, mode fault or CRC error (ERRIE)
+    ///
+    /// The flags stay set until cleared, so the handler has to clear them or the interrupt fires
+    /// again right away. [`FullDuplex::read`] and [`FullDuplex::send`] clear the flag they report.
+    Error,
+}
+
+/// FIFO flags of SR, read together with the error flags
+struct Status {
+    rxne: bool,
+    txe: bool,
+}
+
+/// Returns the BR\[2:0\] value of the fastest clock division of `pclk` that doesn't exceed
+/// `freq`, or the slowest division if none does
+fn baud_rate_bits(pclk: u32, freq: u32) -> u8 {
+    // The divisor is 2^(br + 1)
+    (0..7).find(|br| pclk >> (br + 1) <= freq).unwrap_or(7)
+}
+
+/// Pins usable as the SCK line of an SPI bus
+pub trait PinSck<SPI> {}
+
+/// Pins usable as the MISO line of an SPI bus
+pub trait PinMiso<SPI> {}
+
+/// Pins usable as the MOSI line of an SPI bus
+pub trait PinMosi<SPI> {}
+
+/// SCK / MISO / MOSI pins of an SPI bus
+pub trait Pins<SPI> {}
+
+impl<SPI, SCK, MISO, MOSI> Pins<SPI> for (SCK, MISO, MOSI)
+where
+    SCK: PinSck<SPI>,
+    MISO: PinMiso<SPI>,
+    MOSI: PinMosi<SPI>,
+{
+}
+
+macro_rules! pins {
+    ($($SPI:ident: ($trait:ident, [$($PIN:ident<$AF:ident>),+]),)+) => {
+        $($(
+            impl $trait<$SPI> for $PIN<Alternate<$AF>> {}
+        )+)+
+    }
+}
+
+pins! {
+    SPI1: (PinSck, [PA5<AF5>, PB3<AF5>, PG11<AF5>]),
+    SPI1: (PinMiso, [PA6<AF5>, PB4<AF5>, PG9<AF5>]),
+    SPI1: (PinMosi, [PA7<AF5>, PB5<AF5>, PD7<AF5>]),
+
+    SPI2: (PinSck, [PB10<AF5>, PB13<AF5>, PD3<AF5>, PI1<AF5>]),
+    SPI2: (PinMiso, [PB14<AF5>, PC2<AF5>, PI2<AF5>]),
+    SPI2: (PinMosi, [PB15<AF5>, PC3<AF5>, PI3<AF5>]),
+
+    SPI3: (PinSck, [PB3<AF6>, PC10<AF6>]),
+    SPI3: (PinMiso, [PB4<AF6>, PC11<AF6>]),
+    SPI3: (PinMosi, [PB5<AF6>, PC12<AF6>]),
+
+    SPI4: (PinSck, [PE2<AF5>, PE12<AF5>]),
+    SPI4: (PinMiso, [PE5<AF5>, PE13<AF5>]),
+    SPI4: (PinMosi, [PE6<AF5>, PE14<AF5>]),
+
+    SPI5: (PinSck, [PF7<AF5>, PH6<AF5>]),
+    SPI5: (PinMiso, [PF8<AF5>, PH7<AF5>]),
+    SPI5: (PinMosi, [PF9<AF5>, PF11<AF5>]),
+
+    SPI6: (PinSck, [PG13<AF5>]),
+    SPI6: (PinMiso, [PG12<AF5>]),
+    SPI6: (PinMosi, [PG14<AF5>]),
+}
+
+/// DMA stream and channel of the receive request of an SPI
+pub trait RxStream<SPI>: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+/// DMA stream and channel of the transmit request of an SPI
+pub trait TxStream<SPI>: DmaStream {
+    #[doc(hidden)]
+    const CHANNEL: u8;
+}
+
+macro_rules! streams {
+    ($($SPI:ident: ($trait:ident, [$($DMA:ident: ($stream:expr, $channel:expr)),+]),)+) => {
+        $(
+            $(
+                impl $trait<$SPI> for Stream<$DMA, $stream> {
+                    const CHANNEL: u8 = $channel;
+                }
+            )+
+        )+
+    }
+}
+
+streams! {
+    SPI1: (RxStream, [DMA2: (0, 3), DMA2: (2, 3)]),
+    SPI1: (TxStream, [DMA2: (3, 3), DMA2: (5, 3)]),
+
+    SPI2: (RxStream, [DMA1: (3, 0)]),
+    SPI2: (TxStream, [DMA1: (4, 0)]),
+
+    SPI3: (RxStream, [DMA1: (0, 0), DMA1: (2, 0)]),
+    SPI3: (TxStream, [DMA1: (5, 0), DMA1: (7, 0)]),
+
+    SPI4: (RxStream, [DMA2: (0, 4)]),
+    SPI4: (TxStream, [DMA2: (1, 4)]),
+
+    SPI5: (RxStream, [DMA2: (3, 2)]),
+    SPI5: (TxStream, [DMA2: (4, 2)]),
+
+    SPI6: (RxStream, [DMA2: (6, 1)]),
+    SPI6: (TxStream, [DMA2: (5, 1)]),
+}
+
+/// SPI master abstraction, with 8-bit or 16-bit (`WORD`) frames
+pub struct Spi<SPI, PINS, WORD = u8>
+where
+    SPI: Enable,
+{
+    spi: SPI,
+    pins: PINS,
+    _clock: ClockGate<SPI>,
+    _word: PhantomData<WORD>,
+}
+
+/// SPI master bound to a receive and a transmit DMA stream
+pub struct SpiDma<SPI, PINS, WORD, RX, TX>
+where
+    SPI: Enable,
+{
+    spi: Spi<SPI, PINS, WORD>,
+    rx: RX,
+    tx: TX,
+}
+
+macro_rules! hal {
+    ($($SPIX:ident: ($spiX:ident, $apb:ident, $pclk:ident),)+) => {
+        $(
+            impl<PINS> Spi<$SPIX, PINS> {
+                /// Configures an SPI peripheral as bus master
+                ///
+                /// The bus runs at the fastest APB clock division that doesn't exceed `freq`, or
+                /// at the slowest one (1/256) if none is slow enough. NSS is managed by software,
+                /// drive the chip select with a GPIO. Bits go out MSB first, see
+                /// [`set_bit_order`](Self::set_bit_order).
+                ///
+                /// Clocks of several MHz need a faster pin speed than the reset one, applied
+                /// before the pins connect to the bus, e.g.
+                /// `pa5.into_alternate_af5_with(AltConfig { speed: Speed::High, ..AltConfig::default() })`.
+                pub fn $spiX<F>(
+                    mut spi: $SPIX,
+                    pins: PINS,
+                    mode: Mode,
+                    freq: F,
+                    clocks: Clocks,
+                    apb: &mut $apb,
+                ) -> Self
+                where
+                    PINS: Pins<$SPIX>,
+                    F: Into<Hertz>,
+                {
+                    // reset the peripheral to a clean slate state, then enable it
+                    spi.reset(apb);
+                    let clock = ClockGate::enable(&spi, apb);
+
+                    let br = baud_rate_bits(clocks.$pclk().0, freq.into().0);
+
+                    // 8-bit frames. RXNE has to be raised as soon as one byte is in the FIFO,
+                    // with the reset threshold of 16 bits a single byte is never reported.
+                    spi.cr2.write(|w| w.ds().eight_bit().frxth().set_bit());
+
+                    spi.cr1.write(|w| {
+                        w.cpha()
+                            .bit(mode.phase == Phase::CaptureOnSecondTransition)
+                            .cpol()
+                            .bit(mode.polarity == Polarity::IdleHigh)
+                            .mstr()
+                            .master()
+                            .br()
+                            .bits(br)
+                            .ssm()
+                            .set_bit()
+                            .ssi()
+                            .set_bit()
+                            .spe()
+                            .set_bit()
+                    });
+
+                    Spi {
+                        spi,
+                        pins,
+                        _clock: clock,
+                        _word: PhantomData,
+                    }
+                }
+
+                /// Switches the bus to 16-bit frames
+                ///
+                /// Waits until the frames already written are sent, like
+                /// [`set_bit_order`](Self::set_bit_order).
+                pub fn into_16bit(mut self) -> Spi<$SPIX, PINS, u16> {
+                    // RXNE is raised once a full 16-bit frame is in the FIFO
+                    self.reconfigure(|spi| {
+                        spi.cr2.modify(|_, w| w.ds().sixteen_bit().frxth().clear_bit())
+                    });
+                    self.into_word()
+                }
+
+                /// Sends the bytes of `buffer` and replaces each with the byte received meanwhile
+                ///
+                /// Every byte is read back before the next one is sent, so the RX FIFO never
+                /// holds more than one frame and can't overrun, however long the buffer.
+                pub fn transfer_in_place(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+                    for byte in buffer.iter_mut() {
+                        nb::block!(self.send(*byte))?;
+                        *byte = nb::block!(self.read())?;
+                    }
+
+                    Ok(())
+                }
+            }
+
+            impl<PINS> Spi<$SPIX, PINS, u16> {
+                /// Switches the bus back to 8-bit frames
+                ///
+                /// Waits until the frames already written are sent, like
+                /// [`set_bit_order`](Self::set_bit_order).
+                pub fn into_8bit(mut self) -> Spi<$SPIX, PINS, u8> {
+                    self.reconfigure(|spi| {
+                        spi.cr2.modify(|_, w| w.ds().eight_bit().frxth().set_bit())
+                    });
+                    self.into_word()
+                }
+
+                /// Sends `buffer` as 16-bit frames and replaces each frame with the one
+                /// received meanwhile
+                ///
+                /// `order` is the order of the two bytes of each frame in `buffer`, see the
+                /// [module documentation](self#frame-size). Panics if `buffer` has an odd
+                /// length.
+                pub fn transfer_bytes(
+                    &mut self,
+                    buffer: &mut [u8],
+                    order: ByteOrder,
+                ) -> Result<(), Error> {
+                    assert!(buffer.len() % 2 == 0, "buffer holds a partial frame");
+
+                    for bytes in buffer.chunks_exact_mut(2) {
+                        nb::block!(self.send(frame([bytes[0], bytes[1]], order)))?;
+                        let received = nb::block!(self.read())?;
+                        bytes.copy_from_slice(&frame_bytes(received, order));
+                    }
+
+                    Ok(())
+                }
+
+                /// Sends `bytes` as 16-bit frames, dropping the frames received meanwhile
+                ///
+                /// `order` is the order of the two bytes of each frame in `bytes`. Panics if
+                /// `bytes` has an odd length.
+                pub fn write_bytes(&mut self, bytes: &[u8], order: ByteOrder) -> Result<(), Error> {
+                    assert!(bytes.len() % 2 == 0, "buffer holds a partial frame");
+
+                    for bytes in bytes.chunks_exact(2) {
+                        nb::block!(self.send(frame([bytes[0], bytes[1]], order)))?;
+                        nb::block!(self.read())?;
+                    }
+
+                    Ok(())
+                }
+            }
+
+            impl<PINS, WORD> Spi<$SPIX, PINS, WORD> {
+                /// Sets the order in which the bits of a frame go over the bus
+                ///
+                /// LSBFIRST can only be changed while the peripheral is disabled, so this waits
+                /// until the frames already written are sent, turns the peripheral off for the
+                /// change and back on. It can't change the order in the middle of a transfer.
+                pub fn set_bit_order(&mut self, order: BitOrder) {
+                    self.reconfigure(|spi| {
+                        spi.cr1
+                            .modify(|_, w| w.lsbfirst().bit(order == BitOrder::LsbFirst))
+                    });
+                }
+
+                /// Starts listening for an interrupt event
+                pub fn listen(&mut self, event: Event) {
+                    self.spi.cr2.modify(|_, w| match event {
+                        Event::Rxne => w.rxneie().set_bit(),
+                        Event::Txe => w.txeie().set_bit(),
+                        Event::Error => w.errie().set_bit(),
+                    });
+                }
+
+                /// Stops listening for an interrupt event
+                pub fn unlisten(&mut self, event: Event) {
+                    self.spi.cr2.modify(|_, w| match event {
+                        Event::Rxne => w.rxneie().clear_bit(),
+                        Event::Txe => w.txeie().clear_bit(),
+                        Event::Error => w.errie().clear_bit(),
+                    });
+                }
+
+                /// Returns `true` if a received frame is waiting in the RX FIFO
+                ///
+                /// Cleared by reading the FIFO empty.
+                pub fn is_rxne(&self) -> bool {
+                    self.spi.sr.read().rxne().bit_is_set()
+                }
+
+                /// Returns `true` if the TX FIFO has room for a frame
+                ///
+                /// Cleared by filling the FIFO.
+                pub fn is_txe(&self) -> bool {
+                    self.spi.sr.read().txe().bit_is_set()
+                }
+
+                /// Returns `true` while a frame is being shifted out
+                pub fn is_busy(&self) -> bool {
+                    self.spi.sr.read().bsy().bit_is_set()
+                }
+
+                /// Returns `true` if a frame was received while the RX FIFO was full
+                ///
+                /// Cleared by a read of DR followed by a read of SR, the data in the FIFO is
+                /// lost.
+                pub fn is_overrun(&self) -> bool {
+                    self.spi.sr.read().ovr().bit_is_set()
+                }
+
+                /// Returns `true` if NSS was pulled low by another master (mode fault)
+                ///
+                /// The fault turns the peripheral off and back into slave mode. A read of SR
+                /// followed by a write of CR1 clears it, which [`FullDuplex::read`] and
+                /// [`FullDuplex::send`] do while restoring master mode.
+                pub fn is_mode_fault(&self) -> bool {
+                    self.spi.sr.read().modf().bit_is_set()
+                }
+
+                /// Returns `true` if the received CRC didn't match
+                ///
+                /// Cleared by writing 0 to CRCERR.
+                pub fn is_crc_error(&self) -> bool {
+                    self.spi.sr.read().crcerr().bit_is_set()
+                }
+
+                /// Binds the bus to DMA streams for its receive and transmit requests
+                pub fn with_dma<RX, TX>(self, rx: RX, tx: TX) -> SpiDma<$SPIX, PINS, WORD, RX, TX>
+                where
+                    RX: RxStream<$SPIX>,
+                    TX: TxStream<$SPIX>,
+                {
+                    // Receive requests first, so no frame is missed. The transmit requests are
+                    // only enabled once a transfer has started its streams.
+                    self.spi.cr2.modify(|_, w| w.rxdmaen().set_bit());
+
+                    SpiDma { spi: self, rx, tx }
+                }
+
+                /// Releases the SPI peripheral and associated pins, turning off its clock
+                pub fn free(self) -> ($SPIX, PINS) {
+                    self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                    (self.spi, self.pins)
+                }
+
+                /// Applies `f` to the registers while the peripheral is disabled
+                ///
+                /// Waits until the frames already written are sent first.
+                fn reconfigure<F>(&mut self, f: F)
+                where
+                    F: FnOnce(&$SPIX),
+                {
+                    while self.spi.sr.read().ftlvl().bits() != 0 || self.is_busy() {}
+
+                    self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                    f(&self.spi);
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                }
+
+                fn into_word<NEWWORD>(self) -> Spi<$SPIX, PINS, NEWWORD> {
+                    Spi {
+                        spi: self.spi,
+                        pins: self.pins,
+                        _clock: self._clock,
+                        _word: PhantomData,
+                    }
+                }
+
+                /// Reads SR, clearing and returning the error it reports, if any
+                fn status(&mut self) -> Result<Status, Error> {
+                    let sr = self.spi.sr.read();
+
+                    match Error::from_flags(
+                        sr.ovr().bit_is_set(),
+                        sr.modf().bit_is_set(),
+                        sr.crcerr().bit_is_set(),
+                    ) {
+                        Some(error) => {
+                            self.clear_error(error);
+                            Err(error)
+                        }
+                        None => Ok(Status {
+                            rxne: sr.rxne().bit_is_set(),
+                            txe: sr.txe().bit_is_set(),
+                        }),
+                    }
+                }
+
+                /// Clears the flag behind `error`, so the following transfers can go on
+                ///
+                /// Expects SR to have been read right before, as part of the clear sequences.
+                fn clear_error(&mut self, error: Error) {
+                    match error {
+                        // A read of DR followed by a read of SR. The received data is lost.
+                        Error::Overrun => {
+                            let _ = self.spi.dr.read();
+                            let _ = self.spi.sr.read();
+                        }
+                        // A read of SR followed by a write of CR1. The fault also cleared MSTR
+                        // and SPE, so the write restores them.
+                        Error::ModeFault => {
+                            self.spi.cr1.modify(|_, w| w.mstr().master().spe().set_bit());
+                        }
+                        // CRCERR is cleared by writing 0, the other bits of SR are read-only
+                        Error::Crc => self.spi.sr.write(|w| w.crcerr().clear_bit()),
+                    }
+                }
+            }
+
+            impl<PINS> FullDuplex<u8> for Spi<$SPIX, PINS> {
+                type Error = Error;
+
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    let status = self.status()?;
+
+                    Err(if status.rxne {
+                        // A byte access pops a single frame, a half-word one would pop two
+                        // NOTE(unsafe) read from a register owned by this abstraction
+                        return Ok(unsafe { ptr::read_volatile(ptr::addr_of!((*$SPIX::ptr()).dr) as *const u8) });
+                    } else {
+                        nb::Error::WouldBlock
+                    })
+                }
+
+                fn send(&mut self, byte: u8) -> nb::Result<(), Error> {
+                    let status = self.status()?;
+
+                    Err(if status.txe {
+                        // A half-word access would pack two frames into the FIFO
+                        // NOTE(unsafe) write to a register owned by this abstraction
+                        unsafe { ptr::write_volatile(ptr::addr_of!((*$SPIX::ptr()).dr) as *mut u8, byte) }
+                        return Ok(());
+                    } else {
+                        nb::Error::WouldBlock
+                    })
+                }
+            }
+
+            impl<PINS> FullDuplex<u16> for Spi<$SPIX, PINS, u16> {
+                type Error = Error;
+
+                fn read(&mut self) -> nb::Result<u16, Error> {
+                    let status = self.status()?;
+
+                    Err(if status.rxne {
+                        // A half-word access pops one 16-bit frame
+                        // NOTE(unsafe) read from a register owned by this abstraction
+                        return Ok(unsafe { ptr::read_volatile(ptr::addr_of!((*$SPIX::ptr()).dr) as *const u16) });
+                    } else {
+                        nb::Error::WouldBlock
+                    })
+                }
+
+                fn send(&mut self, frame: u16) -> nb::Result<(), Error> {
+                    let status = self.status()?;
+
+                    Err(if status.txe {
+                        // NOTE(unsafe) write to a register owned by this abstraction
+                        unsafe { ptr::write_volatile(ptr::addr_of!((*$SPIX::ptr()).dr) as *mut u16, frame) }
+                        return Ok(());
+                    } else {
+                        nb::Error::WouldBlock
+                    })
+                }
+            }
+
+            impl<PINS, RX, TX> SpiDma<$SPIX, PINS, u8, RX, TX>
+            where
+                RX: RxStream<$SPIX>,
+                TX: TxStream<$SPIX>,
+            {
+                /// Starts sending `buffer`, replacing each byte with the one received meanwhile
+                #[allow(clippy::type_complexity)]
+                pub fn transfer(
+                    self,
+                    buffer: &'static mut [u8],
+                ) -> Result<
+                    DuplexTransfer<Self, &'static mut [u8]>,
+                    (dma::Error, &'static mut [u8], Self),
+                > {
+                    self.start(buffer, dma::Width::Byte)
+                }
+            }
+
+            impl<PINS, RX, TX> SpiDma<$SPIX, PINS, u16, RX, TX>
+            where
+                RX: RxStream<$SPIX>,
+                TX: TxStream<$SPIX>,
+            {
+                /// Starts sending `buffer`, replacing each frame with the one received meanwhile
+                #[allow(clippy::type_complexity)]
+                pub fn transfer(
+                    self,
+                    buffer: &'static mut [u16],
+                ) -> Result<
+                    DuplexTransfer<Self, &'static mut [u16]>,
+                    (dma::Error, &'static mut [u16], Self),
+                > {
+                    self.start(buffer, dma::Width::HalfWord)
+                }
+            }
+
+            impl<PINS, WORD, RX, TX> SpiDma<$SPIX, PINS, WORD, RX, TX>
+            where
+                RX: RxStream<$SPIX>,
+                TX: TxStream<$SPIX>,
+            {
+                /// Releases the bus and the streams
+                pub fn free(self) -> (Spi<$SPIX, PINS, WORD>, RX, TX) {
+                    self.spi
+                        .spi
+                        .cr2
+                        .modify(|_, w| w.rxdmaen().clear_bit().txdmaen().clear_bit());
+
+                    (self.spi, self.rx, self.tx)
+                }
+
+                #[allow(clippy::type_complexity)]
+                fn start(
+                    self,
+                    buffer: &'static mut [WORD],
+                    width: dma::Width,
+                ) -> Result<
+                    DuplexTransfer<Self, &'static mut [WORD]>,
+                    (dma::Error, &'static mut [WORD], Self),
+                > {
+                    // TXE is set while the bus is idle, so the transmit requests of the previous
+                    // transfer have to be off until the streams are set up again
+                    self.spi.spi.cr2.modify(|_, w| w.txdmaen().clear_bit());
+
+                    // NOTE(unsafe) only the address of the register is taken
+                    let dr = unsafe { ptr::addr_of!((*$SPIX::ptr()).dr) as u32 };
+                    let rx = self.rx.handle();
+                    let tx = self.tx.handle();
+                    let started = rx
+                        .start(dma::Config {
+                            channel: RX::CHANNEL,
+                            direction: dma::Direction::PeripheralToMemory,
+                            width,
+                            peripheral: dr,
+                            memory0: buffer.as_ptr() as u32,
+                            memory1: None,
+                            len: buffer.len(),
+                        })
+                        .and_then(|()| {
+                            tx.start(dma::Config {
+                                channel: TX::CHANNEL,
+                                direction: dma::Direction::MemoryToPeripheral,
+                                width,
+                                peripheral: dr,
+                                memory0: buffer.as_ptr() as u32,
+                                memory1: None,
+                                len: buffer.len(),
+                            })
+                        });
+                    if let Err(error) = started {
+                        rx.stop();
+                        return Err((error, buffer, self));
+                    }
+
+                    self.spi.spi.cr2.modify(|_, w| w.txdmaen().set_bit());
+
+                    Ok(DuplexTransfer::new(self, buffer, rx, tx))
+                }
+            }
+
+            // All of them read back every received frame, so the RX FIFO can't overrun
+            impl<PINS> transfer::Default<u8> for Spi<$SPIX, PINS> {}
+
+            impl<PINS> write::Default<u8> for Spi<$SPIX, PINS> {}
+
+            impl<PINS> transfer::Default<u16> for Spi<$SPIX, PINS, u16> {}
+
+            impl<PINS> write::Default<u16> for Spi<$SPIX, PINS, u16> {}
+        )+
+    }
+}
+
+hal! {
+    SPI1: (spi1, APB2, pclk2),
+    SPI2: (spi2, APB1, pclk1),
+    SPI3: (spi3, APB1, pclk1),
+    SPI4: (spi4, APB2, pclk2),
+    SPI5: (spi5, APB2, pclk2),
+    SPI6: (spi6, APB2, pclk2),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_error_without_flags() {
+        assert_eq!(Error::from_flags(false, false, false), None);
+    }
+
+    #[test]
+    fn each_flag_maps_to_its_error() {
+        assert_eq!(Error::from_flags(true, false, false), Some(Error::Overrun));
+        assert_eq!(
+            Error::from_flags(false, true, false),
+            Some(Error::ModeFault)
+        );
+        assert_eq!(Error::from_flags(false, false, true), Some(Error::Crc));
+    }
+
+    #[test]
+    fn overrun_is_reported_first() {
+        // Each call clears one flag, the others are reported by the following calls
+        assert_eq!(Error::from_flags(true, true, true), Some(Error::Overrun));
+        assert_eq!(Error::from_flags(false, true, true), Some(Error::ModeFault));
+    }
+
+    #[test]
+    fn baud_rate_does_not_exceed_request() {
+        // 108 MHz APB2 at 216 MHz sysclk
+        assert_eq!(baud_rate_bits(108_000_000, 54_000_000), 0);
+        assert_eq!(baud_rate_bits(108_000_000, 50_000_000), 1);
+        assert_eq!(baud_rate_bits(108_000_000, 1_000_000), 6);
+    }
+
+    #[test]
+    fn baud_rate_falls_back_to_slowest_division() {
+        assert_eq!(baud_rate_bits(108_000_000, 100_000), 7);
+        assert_eq!(baud_rate_bits(16_000_000, 0), 7);
+    }
+
+    /// Bytes of a frame in the order they go over the wire, MSB first
+    fn wire(frame: u16) -> [u8; 2] {
+        [(frame >> 8) as u8, frame as u8]
+    }
+
+    #[test]
+    fn big_endian_buffer_is_in_wire_order() {
+        let frame = frame([0x12, 0x34], ByteOrder::BigEndian);
+        assert_eq!(frame, 0x1234);
+        assert_eq!(wire(frame), [0x12, 0x34]);
+    }
+
+    #[test]
+    fn little_endian_buffer_is_swapped_on_the_wire() {
+        let frame = frame([0x12, 0x34], ByteOrder::LittleEndian);
+        assert_eq!(frame, 0x3412);
+        assert_eq!(wire(frame), [0x34, 0x12]);
+    }
+
+    #[test]
+    fn received_frames_keep_the_buffer_order() {
+        // 0xabcd came in with 0xab first on the wire
+        assert_eq!(frame_bytes(0xabcd, ByteOrder::BigEndian), [0xab, 0xcd]);
+        assert_eq!(frame_bytes(0xabcd, ByteOrder::LittleEndian), [0xcd, 0xab]);
+
+        for &order in &[ByteOrder::BigEndian, ByteOrder::LittleEndian] {
+            assert_eq!(frame(frame_bytes(0xabcd, order), order), 0xabcd);
+        }
+    }
+}
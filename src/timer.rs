@@ -1,10 +1,16 @@
 //! Timers
 
+use core::time::Duration;
+
 use crate::device::{TIM2, TIM3, TIM4, TIM5};
+use crate::gpio::gpioa::{PA0, PA15, PA5};
+use crate::gpio::gpiod::PD2;
+use crate::gpio::gpioe::PE0;
+use crate::gpio::{Alternate, AF1, AF2};
 use crate::hal::timer::{CountDown, Periodic};
-use crate::rcc::{Clocks, APB1};
+use crate::rcc::{Clocks, Reset, APB1};
 use crate::time::Hertz;
-use cast::{u16, u32};
+use cast::{u16, u32, u64};
 use nb;
 use void::Void;
 
@@ -21,8 +27,17 @@ pub enum Event {
     TimeOut,
 }
 
+/// Counting direction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Counting up from 0 to the auto-reload value
+    Up,
+    /// Counting down from the auto-reload value to 0
+    Down,
+}
+
 macro_rules! hal {
-    ($($TIM:ident: ($tim:ident, $timXen:ident, $timXrst:ident, $apb:ident),)+) => {
+    ($($TIM:ident: ($tim:ident, $timXen:ident, $apb:ident, $width:ident),)+) => {
         $(
             impl Periodic for Timer<$TIM> {}
 
@@ -39,7 +54,7 @@ macro_rules! hal {
 
                     self.timeout = timeout.into();
                     let frequency = self.timeout.0;
-                    let ticks = self.clocks.pclk1().0 / frequency;
+                    let ticks = self.clocks.timclk1().0 / frequency;
                     let psc = u16((ticks - 1) / (1 << 16)).unwrap();
 
                     self.tim.psc.write(|w| unsafe { w.psc().bits(psc) });
@@ -71,14 +86,13 @@ macro_rules! hal {
 
             impl Timer<$TIM> {
                 /// Configures a TIM peripheral as a periodic count down timer
-                pub fn $tim<T>(tim: $TIM, timeout: T, clocks: Clocks, apb: &mut $apb) -> Self
+                pub fn $tim<T>(mut tim: $TIM, timeout: T, clocks: Clocks, apb: &mut $apb) -> Self
                 where
                     T: Into<Hertz>,
                 {
-                    // enable and reset peripheral to a clean slate state
+                    // reset and enable peripheral to a clean slate state
+                    tim.reset(apb);
                     apb.enr().modify(|_, w| w.$timXen().set_bit());
-                    apb.rstr().modify(|_, w| w.$timXrst().set_bit());
-                    apb.rstr().modify(|_, w| w.$timXrst().clear_bit());
 
                     let mut timer = Timer {
                         clocks,
@@ -110,6 +124,64 @@ macro_rules! hal {
                     }
                 }
 
+                /// Returns the current counter value
+                pub fn counter(&self) -> u32 {
+                    // Mask the UIF copy that 16-bit timers can map into bit 31
+                    self.tim.cnt.read().bits() & Self::max_auto_reload()
+                }
+
+                /// Sets the counter value
+                #[allow(unused_unsafe)]
+                pub fn set_counter(&mut self, value: $width) {
+                    self.tim.cnt.write(|w| unsafe { w.bits(u32(value)) });
+                }
+
+                /// Sets the auto-reload value, i.e. the counter value at which the timer wraps
+                ///
+                /// Without auto-reload preload the value takes effect immediately, otherwise at
+                /// the next update event.
+                #[allow(unused_unsafe)]
+                pub fn set_auto_reload(&mut self, value: $width) {
+                    self.tim.arr.write(|w| unsafe { w.bits(u32(value)) });
+                }
+
+                /// Returns the largest auto-reload value this timer supports
+                ///
+                /// TIM2 and TIM5 are 32-bit timers, the others are 16-bit ones.
+                pub fn max_auto_reload() -> u32 {
+                    u32($width::MAX)
+                }
+
+                /// Returns the direction the counter is counting in
+                pub fn direction(&self) -> Direction {
+                    if self.tim.cr1.read().dir().bit_is_set() {
+                        Direction::Down
+                    } else {
+                        Direction::Up
+                    }
+                }
+
+                /// Enables / disables the auto-reload preload
+                ///
+                /// With preload enabled, a new auto-reload value is buffered and only takes
+                /// effect at the next update event, so the period can be changed while the
+                /// counter runs without producing a glitch or a missed period.
+                pub fn auto_reload_preload(&mut self, on: bool) {
+                    self.tim.cr1.modify(|_, w| w.arpe().bit(on));
+                }
+
+                /// Generates an update event by software
+                ///
+                /// This reinitializes the counter and transfers all preloaded values (prescaler
+                /// and auto-reload) to the active registers at once. The update flag is not
+                /// raised, so this neither triggers an interrupt nor completes a `wait`.
+                pub fn trigger_update(&mut self) {
+                    // Only let counter overflows set the update flag while we force the update
+                    self.tim.cr1.modify(|_, w| w.urs().set_bit());
+                    self.tim.egr.write(|w| w.ug().set_bit());
+                    self.tim.cr1.modify(|_, w| w.urs().clear_bit());
+                }
+
                 /// Releases the TIM peripheral
                 pub fn free(self) -> $TIM {
                     // pause counter
@@ -123,8 +195,243 @@ macro_rules! hal {
 
 // TODO: Add support for missing timers
 hal! {
-    TIM2: (tim2, tim2en, tim2rst, APB1),
-    TIM3: (tim3, tim3en, tim3rst, APB1),
-    TIM4: (tim4, tim4en, tim4rst, APB1),
-    TIM5: (tim5, tim5en, tim5rst, APB1),
+    TIM2: (tim2, tim2en, APB1, u32),
+    TIM3: (tim3, tim3en, APB1, u16),
+    TIM4: (tim4, tim4en, APB1, u16),
+    TIM5: (tim5, tim5en, APB1, u32),
+}
+
+/// 32-bit timer counting up freely at about 1 MHz, the time base of [`Stopwatch`]
+pub struct FreeRunningTimer<TIM> {
+    tim: TIM,
+    frequency: Hertz,
+}
+
+/// Stopwatch measuring time on a [`FreeRunningTimer`]
+///
+/// Spans are computed with wrapping arithmetic, so a single wrap of the counter is handled
+/// correctly. Spans longer than one full counter period (2^32 ticks, about 71 minutes at 1 MHz)
+/// can't be told apart from shorter ones and will be reported too short.
+pub struct Stopwatch<'a, TIM> {
+    timer: &'a FreeRunningTimer<TIM>,
+    start: u32,
+    lap: u32,
+}
+
+impl<'a, TIM> Clone for Stopwatch<'a, TIM> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, TIM> Copy for Stopwatch<'a, TIM> {}
+
+/// Converts `ticks` of a counter running at `frequency` to a `Duration`
+fn ticks_to_duration(ticks: u32, frequency: Hertz) -> Duration {
+    Duration::from_nanos(u64(ticks) * 1_000_000_000 / u64(frequency.0))
+}
+
+macro_rules! free_running {
+    ($($TIM:ident: ($tim:ident, $timXen:ident, $apb:ident),)+) => {
+        $(
+            impl FreeRunningTimer<$TIM> {
+                /// Configures a 32-bit TIM peripheral as a free running counter
+                #[allow(unused_unsafe)]
+                pub fn $tim(mut tim: $TIM, clocks: Clocks, apb: &mut $apb) -> Self {
+                    // reset and enable peripheral to a clean slate state
+                    tim.reset(apb);
+                    apb.enr().modify(|_, w| w.$timXen().set_bit());
+
+                    let clock = clocks.timclk1().0;
+                    let psc = (clock / 1_000_000).max(1) - 1;
+
+                    tim.psc.write(|w| unsafe { w.psc().bits(u16(psc).unwrap()) });
+                    tim.arr.write(|w| unsafe { w.bits(u32::MAX) });
+
+                    // Load the prescaler, then start counting from 0
+                    tim.egr.write(|w| w.ug().set_bit());
+                    tim.sr.modify(|_, w| w.uif().clear_bit());
+                    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    FreeRunningTimer {
+                        tim,
+                        frequency: Hertz(clock / (psc + 1)),
+                    }
+                }
+
+                /// Returns the counter frequency
+                pub fn frequency(&self) -> Hertz {
+                    self.frequency
+                }
+
+                /// Returns the current counter value
+                pub fn now(&self) -> u32 {
+                    self.tim.cnt.read().bits()
+                }
+
+                /// Returns a stopwatch started at the current counter value
+                pub fn stopwatch(&self) -> Stopwatch<'_, $TIM> {
+                    let now = self.now();
+                    Stopwatch {
+                        timer: self,
+                        start: now,
+                        lap: now,
+                    }
+                }
+
+                /// Releases the TIM peripheral
+                pub fn free(self) -> $TIM {
+                    // pause counter
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim
+                }
+            }
+
+            impl<'a> Stopwatch<'a, $TIM> {
+                /// Restarts the stopwatch, resetting the lap as well
+                pub fn start(&mut self) {
+                    self.start = self.timer.now();
+                    self.lap = self.start;
+                }
+
+                /// Returns the time since the previous lap (or the start) and begins a new lap
+                pub fn lap(&mut self) -> Duration {
+                    let now = self.timer.now();
+                    let ticks = now.wrapping_sub(self.lap);
+                    self.lap = now;
+
+                    ticks_to_duration(ticks, self.timer.frequency)
+                }
+
+                /// Returns the time since the stopwatch was started
+                pub fn elapsed(&self) -> Duration {
+                    let ticks = self.timer.now().wrapping_sub(self.start);
+
+                    ticks_to_duration(ticks, self.timer.frequency)
+                }
+            }
+        )+
+    }
+}
+
+free_running! {
+    TIM2: (tim2, tim2en, APB1),
+    TIM5: (tim5, tim5en, APB1),
+}
+
+/// Pins usable as the external trigger (ETR) input of a timer
+pub trait EtrPin<TIM> {}
+
+impl EtrPin<TIM2> for PA0<Alternate<AF1>> {}
+impl EtrPin<TIM2> for PA5<Alternate<AF1>> {}
+impl EtrPin<TIM2> for PA15<Alternate<AF1>> {}
+impl EtrPin<TIM3> for PD2<Alternate<AF2>> {}
+impl EtrPin<TIM4> for PE0<Alternate<AF2>> {}
+
+/// Hardware counter of rising edges on a timer's ETR pin
+///
+/// The timer runs in external clock mode 2, so every rising edge on the pin increments the
+/// counter without any CPU involvement. Counter overflows are accumulated in software into a
+/// `u64`: either call [`on_overflow`] from the timer interrupt (see `listen`), or read
+/// [`count`] at least once per counter period (2^16 edges for TIM3/TIM4, 2^32 for TIM2).
+///
+/// The ETR input is resampled by the timer kernel clock, which limits the input frequency to a
+/// quarter of it, e.g. 27 MHz with a 108 MHz timer clock. Faster signals drop edges.
+///
+/// [`on_overflow`]: PulseCounter::on_overflow
+/// [`count`]: PulseCounter::count
+pub struct PulseCounter<TIM, PIN> {
+    tim: TIM,
+    pin: PIN,
+    overflows: u64,
+}
+
+macro_rules! pulse_counter {
+    ($($TIM:ident: ($tim:ident, $timXen:ident, $apb:ident, $width:ident),)+) => {
+        $(
+            impl<PIN> PulseCounter<$TIM, PIN>
+            where
+                PIN: EtrPin<$TIM>,
+            {
+                /// Configures a TIM peripheral to count the rising edges on its ETR pin
+                #[allow(unused_unsafe)]
+                pub fn $tim(mut tim: $TIM, pin: PIN, apb: &mut $apb) -> Self {
+                    // reset and enable peripheral to a clean slate state
+                    tim.reset(apb);
+                    apb.enr().modify(|_, w| w.$timXen().set_bit());
+
+                    tim.arr.write(|w| unsafe { w.bits(u32($width::MAX)) });
+                    // External clock mode 2: rising edges, no filter, no prescaler
+                    tim.smcr.write(|w| w.ece().set_bit());
+                    // Only let counter overflows set the update flag
+                    tim.cr1.modify(|_, w| w.urs().set_bit());
+                    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    PulseCounter {
+                        tim,
+                        pin,
+                        overflows: 0,
+                    }
+                }
+
+                /// Returns the number of rising edges counted since the start or the last reset
+                pub fn count(&mut self) -> u64 {
+                    loop {
+                        let count = self.tim.cnt.read().bits() & u32($width::MAX);
+
+                        // An overflow may have happened since the read, account for it and
+                        // read again
+                        if !self.on_overflow() {
+                            return (self.overflows << $width::MAX.count_ones()) + u64(count);
+                        }
+                    }
+                }
+
+                /// Restarts counting from 0
+                pub fn reset(&mut self) {
+                    self.tim.cnt.reset();
+                    self.on_overflow();
+                    self.overflows = 0;
+                }
+
+                /// Enables the update interrupt, raised on every counter overflow
+                pub fn listen(&mut self) {
+                    self.tim.dier.write(|w| w.uie().set_bit());
+                }
+
+                /// Disables the update interrupt
+                pub fn unlisten(&mut self) {
+                    self.tim.dier.write(|w| w.uie().clear_bit());
+                }
+
+                /// Accumulates a pending counter overflow, clearing the update flag
+                ///
+                /// Meant to be called from the timer interrupt. Returns `true` if there was an
+                /// overflow to accumulate.
+                pub fn on_overflow(&mut self) -> bool {
+                    if self.tim.sr.read().uif().bit_is_set() {
+                        self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                        self.overflows += 1;
+                        true
+                    } else {
+                        false
+                    }
+                }
+
+                /// Stops counting and releases the TIM peripheral and the pin
+                pub fn free(self) -> ($TIM, PIN) {
+                    // pause counter
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim.smcr.reset();
+                    (self.tim, self.pin)
+                }
+            }
+        )+
+    }
+}
+
+pulse_counter! {
+    TIM2: (tim2, tim2en, APB1, u32),
+    TIM3: (tim3, tim3en, APB1, u16),
+    TIM4: (tim4, tim4en, APB1, u16),
 }
@@ -0,0 +1,191 @@
+//! Independent Watchdog (IWDG)
+
+use crate::device::IWDG;
+use crate::hal::watchdog::{Watchdog, WatchdogEnable};
+use crate::time::MilliSeconds;
+
+/// Nominal LSI frequency, the clock of the IWDG
+const LSI: u32 = 32_000; // Hz
+
+/// Largest reload value, the reload register is 12 bits wide
+const MAX_RELOAD: u32 = 0xfff;
+
+/// Largest prescaler setting, dividing LSI by 256
+const MAX_PRESCALER: u8 = 0b110;
+
+/// Independent Watchdog
+///
+/// Once started, the watchdog can't be stopped other than by a reset, and it keeps running
+/// while the core is halted by a debugger unless `DBGMCU_APB1_FZ.DBG_IWDG_STOP` is set.
+pub struct IndependentWatchdog {
+    iwdg: IWDG,
+}
+
+impl IndependentWatchdog {
+    /// Wraps the IWDG peripheral, without starting it
+    pub fn new(iwdg: IWDG) -> Self {
+        IndependentWatchdog { iwdg }
+    }
+
+    /// Returns the longest supported timeout, about 32.7 s
+    pub fn max_period() -> MilliSeconds {
+        MilliSeconds((MAX_RELOAD + 1) * (4 << MAX_PRESCALER) * 1_000 / LSI)
+    }
+}
+
+impl WatchdogEnable for IndependentWatchdog {
+    type Time = MilliSeconds;
+
+    /// Starts the watchdog with a timeout of `period`
+    ///
+    /// The timeout is based on the nominal LSI frequency. LSI varies between roughly 17 and
+    /// 47 kHz across devices and temperature, so leave a generous margin. Periods longer than
+    /// [`max_period`](IndependentWatchdog::max_period) are clamped to it.
+    fn start<T>(&mut self, period: T)
+    where
+        T: Into<MilliSeconds>,
+    {
+        // Ticks of LSI / 4, the smallest prescaler
+        let ticks = u64::from(period.into().0) * u64::from(LSI) / 4 / 1_000;
+
+        // Pick the finest prescaler that still fits the reload value
+        let mut pr = 0;
+        while pr < MAX_PRESCALER && ticks >> pr > u64::from(MAX_RELOAD) + 1 {
+            pr += 1;
+        }
+        let reload = ((ticks >> pr).max(1) - 1).min(u64::from(MAX_RELOAD)) as u16;
+
+        // Starting the watchdog also starts LSI
+        self.iwdg.kr.write(|w| w.key().start());
+        // Unlock PR and RLR
+        self.iwdg.kr.write(|w| w.key().enable());
+        self.iwdg.pr.write(|w| w.pr().bits(pr));
+        self.iwdg.rlr.write(|w| w.rl().bits(reload));
+
+        // The registers are updated in the LSI domain, a reload before that would still use
+        // the old values
+        while {
+            let sr = self.iwdg.sr.read();
+            sr.pvu().bit_is_set() || sr.rvu().bit_is_set()
+        } {}
+
+        self.feed();
+    }
+}
+
+impl Watchdog for IndependentWatchdog {
+    fn feed(&mut self) {
+        self.iwdg.kr.write(|w| w.key().reset());
+    }
+}
+
+/// Check-ins of `N` tasks since the watchdog was last fed
+struct Heartbeats<const N: usize> {
+    checked_in: [bool; N],
+}
+
+impl<const N: usize> Heartbeats<N> {
+    const fn new() -> Self {
+        Heartbeats {
+            checked_in: [false; N],
+        }
+    }
+
+    /// Records the check-in of `task`, returns `true` and starts over once all tasks did
+    fn check_in(&mut self, task: usize) -> bool {
+        self.checked_in[task] = true;
+
+        let all = self.checked_in.iter().all(|&checked_in| checked_in);
+        if all {
+            self.checked_in = [false; N];
+        }
+        all
+    }
+}
+
+/// Independent Watchdog fed only once each of `N` tasks checked in
+///
+/// A superloop feeding the watchdog from a single place keeps feeding it while one of its
+/// tasks hangs, e.g. spinning on a flag of a peripheral. Here every task calls
+/// [`checkpoint`](SupervisedWatchdog::checkpoint) with its own id instead, and the watchdog
+/// is fed when the last of them checks in. A task that stops checking in holds back the
+/// feed, and the watchdog resets the device.
+///
+/// # Timing budget
+///
+/// The watchdog is fed at most once per round of check-ins, so the timeout has to exceed the
+/// longest time any single task may take between two of its check-ins, e.g. the slowest pass
+/// of a task that only runs every few loop iterations. Check-ins in between don't extend it.
+/// On top of that comes the LSI tolerance of [`start`](SupervisedWatchdog::start), which can
+/// make the timeout as short as two thirds of the requested one.
+pub struct SupervisedWatchdog<const N: usize> {
+    watchdog: IndependentWatchdog,
+    heartbeats: Heartbeats<N>,
+}
+
+impl<const N: usize> SupervisedWatchdog<N> {
+    /// Supervises tasks `0..N` with `watchdog`, without starting it
+    pub fn new(watchdog: IndependentWatchdog) -> Self {
+        SupervisedWatchdog {
+            watchdog,
+            heartbeats: Heartbeats::new(),
+        }
+    }
+
+    /// Starts the watchdog with a timeout of `period`, see [`IndependentWatchdog::start`]
+    ///
+    /// The tasks have to check in within `period` from now on.
+    pub fn start<T>(&mut self, period: T)
+    where
+        T: Into<MilliSeconds>,
+    {
+        self.heartbeats = Heartbeats::new();
+        self.watchdog.start(period);
+    }
+
+    /// Records that `task` is alive, and feeds the watchdog if it's the last task to check in
+    ///
+    /// Panics if `task` isn't below `N`.
+    pub fn checkpoint(&mut self, task: usize) {
+        if self.heartbeats.check_in(task) {
+            self.watchdog.feed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feeds_once_all_tasks_checked_in() {
+        let mut heartbeats = Heartbeats::<3>::new();
+        assert!(!heartbeats.check_in(2));
+        assert!(!heartbeats.check_in(0));
+        // Checking in again doesn't make up for a missing task
+        assert!(!heartbeats.check_in(0));
+        assert!(heartbeats.check_in(1));
+    }
+
+    #[test]
+    fn starts_over_after_feeding() {
+        let mut heartbeats = Heartbeats::<2>::new();
+        assert!(!heartbeats.check_in(0));
+        assert!(heartbeats.check_in(1));
+        assert!(!heartbeats.check_in(1));
+        assert!(heartbeats.check_in(0));
+    }
+
+    #[test]
+    fn single_task_feeds_every_time() {
+        let mut heartbeats = Heartbeats::<1>::new();
+        assert!(heartbeats.check_in(0));
+        assert!(heartbeats.check_in(0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_unknown_task() {
+        Heartbeats::<2>::new().check_in(2);
+    }
+}